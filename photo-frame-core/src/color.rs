@@ -0,0 +1,72 @@
+//
+// The seven colours of the panel's ink, and how each is named, stored and
+// seen.
+//
+use defmt::Format;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Color {
+    Black = 0x000000,
+    White = 0xffffff,
+    Green = 0x00ff00,
+    Blue = 0xff0000,
+    Red = 0x0000ff,
+    Yellow = 0x00ffff,
+    Orange = 0x0080ff,
+}
+
+impl Color {
+    /// Every colour the panel can show, in register order
+    pub const ALL: [Color; 7] = [
+        Color::Black,
+        Color::White,
+        Color::Green,
+        Color::Blue,
+        Color::Red,
+        Color::Yellow,
+        Color::Orange,
+    ];
+
+    /// Parses a lower case colour name such as "orange"
+    pub fn from_name(name: &str) -> Option<Color> {
+        match name {
+            "black" => Some(Color::Black),
+            "white" => Some(Color::White),
+            "green" => Some(Color::Green),
+            "blue" => Some(Color::Blue),
+            "red" => Some(Color::Red),
+            "yellow" => Some(Color::Yellow),
+            "orange" => Some(Color::Orange),
+            _ => None,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Color> {
+        Color::ALL.into_iter().find(|color| color.to_byte() == byte)
+    }
+
+    /// Approximate perceived lightness of the colour on the panel, 0-255
+    pub fn luminance(self) -> u8 {
+        match self {
+            Color::Black => 0,
+            Color::Blue => 45,
+            Color::Red => 80,
+            Color::Green => 120,
+            Color::Orange => 160,
+            Color::Yellow => 220,
+            Color::White => 255,
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Color::Black => 0x0,
+            Color::White => 0x1,
+            Color::Green => 0x2,
+            Color::Blue => 0x3,
+            Color::Red => 0x4,
+            Color::Yellow => 0x5,
+            Color::Orange => 0x6,
+        }
+    }
+}
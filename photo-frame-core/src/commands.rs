@@ -0,0 +1,209 @@
+//
+// Commands typed at the USB serial console, parsed into requests. Kept apart
+// from `console` so it doesn't depend on the hardware.
+//
+// Words are separated by spaces; quote one to keep spaces in it, e.g.
+// `wifi set "My Network" secret`.
+//
+use alloc::vec::Vec;
+use defmt::Format;
+
+use crate::color::Color;
+
+/// Usage and description of each command, for `help` and usage errors
+pub const COMMANDS: [(&str, &str); 9] = [
+    ("help", "list commands"),
+    (
+        "wifi set <ssid> [password]",
+        "join a network, open if no password",
+    ),
+    ("url set <url>", "fetch images from a new server"),
+    ("refresh", "fetch and show a new image now"),
+    (
+        "clear [colour]",
+        "fill the panel with one colour, white by default",
+    ),
+    ("heap", "show memory use"),
+    ("scan", "list networks in range"),
+    ("reboot", "restart the frame"),
+    ("factory-reset", "forget stored settings and restart"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request<'a> {
+    Help,
+    SetWifi { ssid: &'a str, password: &'a str },
+    SetUrl(&'a str),
+    Refresh,
+    Clear(Color),
+    Heap,
+    Scan,
+    Reboot,
+    FactoryReset,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ParseError {
+    Empty,
+    Unknown,
+    /// A known command with the wrong arguments; holds its usage
+    Usage(&'static str),
+    UnterminatedQuote,
+}
+
+// Splits a line into words, keeping quoted ones whole
+fn split(line: &str) -> Result<Vec<&str>, ParseError> {
+    let mut words = Vec::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let (word, after) = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').ok_or(ParseError::UnterminatedQuote)?;
+                (&quoted[..end], &quoted[end + 1..])
+            }
+            None => {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            }
+        };
+        words.push(word);
+        rest = after.trim_start();
+    }
+    Ok(words)
+}
+
+// The error for a command used wrongly, with its usage if it's one we know
+fn misused(command: &str) -> ParseError {
+    COMMANDS
+        .iter()
+        .map(|&(usage, _)| usage)
+        .find(|usage| usage.split(' ').next() == Some(command))
+        .map_or(ParseError::Unknown, ParseError::Usage)
+}
+
+/// Parses one line typed at the console
+pub fn parse(line: &str) -> Result<Request<'_>, ParseError> {
+    let words = split(line)?;
+    let request = match *words.as_slice() {
+        [] => return Err(ParseError::Empty),
+        ["help"] => Request::Help,
+        ["wifi", "set", ssid] => Request::SetWifi { ssid, password: "" },
+        ["wifi", "set", ssid, password] => Request::SetWifi { ssid, password },
+        ["url", "set", url] => Request::SetUrl(url),
+        ["refresh"] => Request::Refresh,
+        ["clear"] => Request::Clear(Color::White),
+        ["clear", name] => match Color::from_name(name) {
+            Some(color) => Request::Clear(color),
+            None => return Err(misused("clear")),
+        },
+        ["heap"] => Request::Heap,
+        ["scan"] => Request::Scan,
+        ["reboot"] => Request::Reboot,
+        ["factory-reset"] => Request::FactoryReset,
+        [command, ..] => return Err(misused(command)),
+    };
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_words_keep_their_spaces() {
+        assert_eq!(
+            parse(r#"wifi set "My Network" "pass word""#),
+            Ok(Request::SetWifi {
+                ssid: "My Network",
+                password: "pass word",
+            })
+        );
+        assert_eq!(
+            parse(r#"  wifi   set "Cafe"  "#),
+            Ok(Request::SetWifi {
+                ssid: "Cafe",
+                password: "",
+            })
+        );
+        assert_eq!(
+            parse(r#"wifi set "" secret"#),
+            Ok(Request::SetWifi {
+                ssid: "",
+                password: "secret",
+            })
+        );
+    }
+
+    #[test]
+    fn unterminated_quote() {
+        assert_eq!(
+            parse(r#"wifi set "My Network secret"#),
+            Err(ParseError::UnterminatedQuote)
+        );
+    }
+
+    #[test]
+    fn usage_errors() {
+        assert_eq!(
+            parse("wifi set"),
+            Err(ParseError::Usage("wifi set <ssid> [password]"))
+        );
+        assert_eq!(
+            parse("wifi set a b c"),
+            Err(ParseError::Usage("wifi set <ssid> [password]"))
+        );
+        assert_eq!(parse("url"), Err(ParseError::Usage("url set <url>")));
+        assert_eq!(parse("refresh now"), Err(ParseError::Usage("refresh")));
+        assert_eq!(
+            parse("clear mauve"),
+            Err(ParseError::Usage("clear [colour]"))
+        );
+    }
+
+    #[test]
+    fn unknown_commands() {
+        assert_eq!(parse("dance"), Err(ParseError::Unknown));
+        assert_eq!(parse("HELP"), Err(ParseError::Unknown));
+        assert_eq!(parse(r#""help me""#), Err(ParseError::Unknown));
+    }
+
+    #[test]
+    fn clear_takes_a_colour() {
+        assert_eq!(parse("clear"), Ok(Request::Clear(Color::White)));
+        for color in Color::ALL {
+            let name = match color {
+                Color::Black => "black",
+                Color::White => "white",
+                Color::Green => "green",
+                Color::Blue => "blue",
+                Color::Red => "red",
+                Color::Yellow => "yellow",
+                Color::Orange => "orange",
+            };
+            assert_eq!(
+                parse(&alloc::format!("clear {}", name)),
+                Ok(Request::Clear(color))
+            );
+        }
+    }
+
+    #[test]
+    fn empty_lines() {
+        assert_eq!(parse(""), Err(ParseError::Empty));
+        assert_eq!(parse("   \t "), Err(ParseError::Empty));
+    }
+
+    #[test]
+    fn simple_commands() {
+        assert_eq!(parse("help"), Ok(Request::Help));
+        assert_eq!(
+            parse("url set http://frame.local/next"),
+            Ok(Request::SetUrl("http://frame.local/next"))
+        );
+        assert_eq!(parse(" refresh "), Ok(Request::Refresh));
+        assert_eq!(parse("heap"), Ok(Request::Heap));
+        assert_eq!(parse("scan"), Ok(Request::Scan));
+        assert_eq!(parse("reboot"), Ok(Request::Reboot));
+        assert_eq!(parse("factory-reset"), Ok(Request::FactoryReset));
+    }
+}
@@ -0,0 +1,519 @@
+//
+// DNS messages as used by multicast DNS (RFC 6762) and DNS-SD (RFC 6763):
+// parsing whatever arrives, including compressed names, and encoding the few
+// queries and responses `mdns` sends. Kept apart from the sockets so it can be
+// tested on the host.
+//
+use alloc::{format, string::String, vec, vec::Vec};
+
+// How long records we send may be cached
+const TTL: u32 = 120;
+// Guards against compression pointer loops
+const MAX_POINTERS: usize = 16;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+    A([u8; 4]),
+    Ptr(String),
+    Txt(Vec<String>),
+    Srv { port: u16, target: String },
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    pub data: RData,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub id: u16,
+    pub is_response: bool,
+    pub questions: Vec<Question>,
+    /// Answers and additional records together
+    pub records: Vec<Record>,
+    // Where the question section ends, for echoing it back
+    questions_end: usize,
+}
+
+/// An image server found on the network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    pub address: [u8; 4],
+    pub port: u16,
+    /// From the `path` TXT entry, "/" if there is none
+    pub path: String,
+}
+
+impl Service {
+    pub fn url(&self) -> String {
+        let [a, b, c, d] = self.address;
+        format!("http://{}.{}.{}.{}:{}{}", a, b, c, d, self.port, self.path)
+    }
+}
+
+/// DNS names compare without regard to case or a trailing dot
+pub fn same_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    let bytes = packet.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+// Reads a possibly compressed name, returning it and the offset just past it
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *packet.get(offset)? as usize;
+        match len & 0xc0 {
+            0x00 if len == 0 => return Some((name, end.unwrap_or(offset + 1))),
+            0x00 => {
+                let label = packet.get(offset + 1..offset + 1 + len)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(core::str::from_utf8(label).ok()?);
+                offset += 1 + len;
+            }
+            0xc0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(offset + 2);
+                offset = ((len & 0x3f) << 8) | *packet.get(offset + 1)? as usize;
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn read_txt(mut data: &[u8]) -> Vec<String> {
+    let mut entries = Vec::new();
+    while let Some((&len, rest)) = data.split_first() {
+        let Some(entry) = rest.get(..len as usize) else {
+            break;
+        };
+        if let Ok(entry) = core::str::from_utf8(entry) {
+            entries.push(String::from(entry));
+        }
+        data = &rest[len as usize..];
+    }
+    entries
+}
+
+/// Parses a DNS message, or returns `None` if it is malformed
+pub fn parse(packet: &[u8]) -> Option<Message> {
+    let id = read_u16(packet, 0)?;
+    let flags = read_u16(packet, 2)?;
+    let question_count = read_u16(packet, 4)?;
+    let record_count = (6..12)
+        .step_by(2)
+        .map(|offset| read_u16(packet, offset).map(usize::from))
+        .sum::<Option<usize>>()?;
+
+    let mut offset = 12;
+    let mut questions = Vec::new();
+    for _ in 0..question_count {
+        let (name, next) = read_name(packet, offset)?;
+        let qtype = read_u16(packet, next)?;
+        // The class isn't used, but a question cut short before it is malformed,
+        // and would be echoed back past the end of the packet
+        read_u16(packet, next + 2)?;
+        questions.push(Question { name, qtype });
+        offset = next + 4;
+    }
+    let questions_end = offset;
+
+    let mut records = Vec::new();
+    for _ in 0..record_count {
+        let (name, next) = read_name(packet, offset)?;
+        let rtype = read_u16(packet, next)?;
+        let len = read_u16(packet, next + 8)? as usize;
+        let start = next + 10;
+        let rdata = packet.get(start..start + len)?;
+        let data = match rtype {
+            TYPE_A => RData::A(rdata.try_into().ok()?),
+            TYPE_PTR => RData::Ptr(read_name(packet, start)?.0),
+            TYPE_TXT => RData::Txt(read_txt(rdata)),
+            TYPE_SRV => RData::Srv {
+                port: read_u16(rdata, 4)?,
+                target: read_name(packet, start + 6)?.0,
+            },
+            _ => RData::Other,
+        };
+        records.push(Record { name, data });
+        offset = start + len;
+    }
+
+    Some(Message {
+        id,
+        is_response: flags & FLAG_RESPONSE != 0,
+        questions,
+        records,
+        questions_end,
+    })
+}
+
+fn push_u16(packet: &mut Vec<u8>, value: u16) {
+    packet.extend_from_slice(&value.to_be_bytes());
+}
+
+// Names are written out in full; messages here are small enough not to need compression
+fn push_name(packet: &mut Vec<u8>, name: &str) {
+    for label in name.trim_end_matches('.').split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+}
+
+fn push_header(packet: &mut Vec<u8>, id: u16, flags: u16, questions: usize, records: usize) {
+    push_u16(packet, id);
+    push_u16(packet, flags);
+    push_u16(packet, questions as u16);
+    push_u16(packet, records as u16);
+    push_u16(packet, 0);
+    push_u16(packet, 0);
+}
+
+fn push_record(packet: &mut Vec<u8>, record: &Record) {
+    push_name(packet, &record.name);
+    let (rtype, rdata) = match &record.data {
+        RData::A(address) => (TYPE_A, Vec::from(*address)),
+        RData::Ptr(name) => {
+            let mut rdata = Vec::new();
+            push_name(&mut rdata, name);
+            (TYPE_PTR, rdata)
+        }
+        RData::Txt(entries) => {
+            let mut rdata = Vec::new();
+            for entry in entries {
+                rdata.push(entry.len() as u8);
+                rdata.extend_from_slice(entry.as_bytes());
+            }
+            // An empty TXT record still holds one empty string
+            if rdata.is_empty() {
+                rdata.push(0);
+            }
+            (TYPE_TXT, rdata)
+        }
+        RData::Srv { port, target } => {
+            // Priority and weight
+            let mut rdata = vec![0, 0, 0, 0];
+            push_u16(&mut rdata, *port);
+            push_name(&mut rdata, target);
+            (TYPE_SRV, rdata)
+        }
+        RData::Other => return,
+    };
+    push_u16(packet, rtype);
+    push_u16(packet, CLASS_IN);
+    packet.extend_from_slice(&TTL.to_be_bytes());
+    push_u16(packet, rdata.len() as u16);
+    packet.extend_from_slice(&rdata);
+}
+
+pub fn encode_query(questions: &[Question]) -> Vec<u8> {
+    let mut packet = Vec::new();
+    push_header(&mut packet, 0, 0, questions.len(), 0);
+    for question in questions {
+        push_name(&mut packet, &question.name);
+        push_u16(&mut packet, question.qtype);
+        push_u16(&mut packet, CLASS_IN);
+    }
+    packet
+}
+
+/// A response carrying `records`. Replies to a legacy unicast `query` keep
+/// its ID and repeat its questions, as RFC 6762 section 6.7 asks.
+pub fn encode_response(query: Option<(&[u8], &Message)>, records: &[Record]) -> Vec<u8> {
+    let mut packet = Vec::new();
+    let flags = FLAG_RESPONSE | FLAG_AUTHORITATIVE;
+    match query {
+        Some((raw, message)) => {
+            push_header(
+                &mut packet,
+                message.id,
+                flags,
+                message.questions.len(),
+                records.len(),
+            );
+            packet.extend_from_slice(&raw[12..message.questions_end]);
+        }
+        None => push_header(&mut packet, 0, flags, 0, records.len()),
+    }
+    for record in records {
+        push_record(&mut packet, record);
+    }
+    packet
+}
+
+fn find<'r, T>(records: &'r [Record], f: impl Fn(&'r Record) -> Option<T>) -> Option<T> {
+    records.iter().find_map(f)
+}
+
+/// Works out where the first instance of `service` is from the records seen
+/// so far, or what still needs asking for
+pub fn locate(records: &[Record], service: &str) -> Result<Service, Vec<Question>> {
+    let question = |name: &str, qtype| Question {
+        name: String::from(name),
+        qtype,
+    };
+    let Some(instance) = find(records, |r| match &r.data {
+        RData::Ptr(instance) if same_name(&r.name, service) => Some(instance),
+        _ => None,
+    }) else {
+        return Err(vec![question(service, TYPE_PTR)]);
+    };
+    let txt = find(records, |r| match &r.data {
+        RData::Txt(entries) if same_name(&r.name, instance) => Some(entries),
+        _ => None,
+    });
+    let Some((port, target)) = find(records, |r| match &r.data {
+        RData::Srv { port, target } if same_name(&r.name, instance) => Some((*port, target)),
+        _ => None,
+    }) else {
+        return Err(vec![
+            question(instance, TYPE_SRV),
+            question(instance, TYPE_TXT),
+        ]);
+    };
+    let Some(address) = find(records, |r| match &r.data {
+        RData::A(address) if same_name(&r.name, target) => Some(*address),
+        _ => None,
+    }) else {
+        let mut questions = vec![question(target, TYPE_A)];
+        if txt.is_none() {
+            questions.push(question(instance, TYPE_TXT));
+        }
+        return Err(questions);
+    };
+
+    let path = txt
+        .and_then(|entries| entries.iter().find_map(|e| e.strip_prefix("path=")))
+        .filter(|path| !path.is_empty())
+        .unwrap_or("/");
+    let path = if path.starts_with('/') {
+        String::from(path)
+    } else {
+        format!("/{}", path)
+    };
+    Ok(Service {
+        address,
+        port,
+        path,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What `dns-sd -B _photoframe._tcp` asks: a PTR query for the service,
+    // asking for a unicast reply
+    #[rustfmt::skip]
+    const QUERY: &[u8] = &[
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // 12: _photoframe._tcp.local PTR, QU class
+        0x0b, b'_', b'p', b'h', b'o', b't', b'o', b'f', b'r', b'a', b'm', b'e',
+        0x04, b'_', b't', b'c', b'p',
+        0x05, b'l', b'o', b'c', b'a', b'l', 0x00,
+        0x00, 0x0c, 0x80, 0x01,
+    ];
+
+    // A server's answer, with the instance, host and address as additional
+    // records and every repeated name compressed
+    #[rustfmt::skip]
+    const RESPONSE: &[u8] = &[
+        0x00, 0x00, 0x84, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03,
+        // 12: _photoframe._tcp.local PTR Studio._photoframe._tcp.local
+        0x0b, b'_', b'p', b'h', b'o', b't', b'o', b'f', b'r', b'a', b'm', b'e',
+        0x04, b'_', b't', b'c', b'p',
+        0x05, b'l', b'o', b'c', b'a', b'l', 0x00,
+        0x00, 0x0c, 0x00, 0x01, 0x00, 0x00, 0x11, 0x94, 0x00, 0x09,
+        0x06, b'S', b't', b'u', b'd', b'i', b'o', 0xc0, 0x0c,
+        // 55: Studio... SRV 0 0 8080 studio.local
+        0xc0, 0x2e, 0x00, 0x21, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0x0f,
+        0x00, 0x00, 0x00, 0x00, 0x1f, 0x90,
+        0x06, b's', b't', b'u', b'd', b'i', b'o', 0xc0, 0x1d,
+        // 82: Studio... TXT "path=/next" "txtvers=1"
+        0xc0, 0x2e, 0x00, 0x10, 0x80, 0x01, 0x00, 0x00, 0x11, 0x94, 0x00, 0x15,
+        0x0a, b'p', b'a', b't', b'h', b'=', b'/', b'n', b'e', b'x', b't',
+        0x09, b't', b'x', b't', b'v', b'e', b'r', b's', b'=', b'1',
+        // 115: studio.local A 192.168.1.20
+        0xc0, 0x49, 0x00, 0x01, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0x04,
+        192, 168, 1, 20,
+    ];
+
+    const SERVICE: &str = "_photoframe._tcp.local";
+    const INSTANCE: &str = "Studio._photoframe._tcp.local";
+
+    // A query with one question whose name starts at offset 12
+    fn query_with_name(name: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(name);
+        packet.extend_from_slice(&[0x00, 0x0c, 0x00, 0x01]);
+        packet
+    }
+
+    #[test]
+    fn parses_query() {
+        let query = parse(QUERY).unwrap();
+        assert!(!query.is_response);
+        assert_eq!(
+            query.questions,
+            [Question {
+                name: String::from(SERVICE),
+                qtype: TYPE_PTR,
+            }]
+        );
+        assert!(query.records.is_empty());
+    }
+
+    #[test]
+    fn parses_compressed_response() {
+        let response = parse(RESPONSE).unwrap();
+        assert!(response.is_response);
+        assert_eq!(
+            response.records,
+            [
+                Record {
+                    name: String::from(SERVICE),
+                    data: RData::Ptr(String::from(INSTANCE)),
+                },
+                Record {
+                    name: String::from(INSTANCE),
+                    data: RData::Srv {
+                        port: 8080,
+                        target: String::from("studio.local"),
+                    },
+                },
+                Record {
+                    name: String::from(INSTANCE),
+                    data: RData::Txt(vec![String::from("path=/next"), String::from("txtvers=1"),]),
+                },
+                Record {
+                    name: String::from("studio.local"),
+                    data: RData::A([192, 168, 1, 20]),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn locates_service_in_response() {
+        let response = parse(RESPONSE).unwrap();
+        let service = locate(&response.records, SERVICE).unwrap();
+        assert_eq!(service.url(), "http://192.168.1.20:8080/next");
+    }
+
+    #[test]
+    fn asks_for_what_is_missing() {
+        let response = parse(RESPONSE).unwrap();
+        // Only the PTR answer, as from a server that leaves out additional records
+        let questions = locate(&response.records[..1], SERVICE).unwrap_err();
+        assert_eq!(
+            questions,
+            [
+                Question {
+                    name: String::from(INSTANCE),
+                    qtype: TYPE_SRV,
+                },
+                Question {
+                    name: String::from(INSTANCE),
+                    qtype: TYPE_TXT,
+                },
+            ]
+        );
+        assert_eq!(
+            locate(&[], SERVICE).unwrap_err(),
+            [Question {
+                name: String::from(SERVICE),
+                qtype: TYPE_PTR,
+            }]
+        );
+    }
+
+    #[test]
+    fn encoded_query_matches_real_one() {
+        let query = encode_query(&[Question {
+            name: String::from(SERVICE),
+            qtype: TYPE_PTR,
+        }]);
+        // The same but for the QU bit, which legacy unicast queries don't set
+        assert_eq!(query[..query.len() - 2], QUERY[..QUERY.len() - 2]);
+        assert_eq!(query[query.len() - 2..], [0x00, 0x01]);
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let query = parse(QUERY).unwrap();
+        let records = parse(RESPONSE).unwrap().records;
+        let encoded = encode_response(Some((QUERY, &query)), &records);
+        let response = parse(&encoded).unwrap();
+        assert!(response.is_response);
+        // Legacy unicast replies repeat the question
+        assert_eq!(response.questions, query.questions);
+        assert_eq!(response.records, records);
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        for len in 0..QUERY.len() {
+            assert_eq!(parse(&QUERY[..len]), None, "query cut to {}", len);
+        }
+        for len in 0..RESPONSE.len() {
+            assert_eq!(parse(&RESPONSE[..len]), None, "response cut to {}", len);
+        }
+    }
+
+    #[test]
+    fn rejects_pointer_loops() {
+        // A name pointing at itself
+        assert_eq!(parse(&query_with_name(&[0xc0, 0x0c])), None);
+        // Two labels pointing at each other
+        assert_eq!(
+            parse(&query_with_name(&[0x01, b'a', 0xc0, 0x0e, 0xc0, 0x0c])),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_pointers() {
+        assert_eq!(parse(&query_with_name(&[0xc0, 0xff])), None);
+        assert_eq!(parse(&query_with_name(&[0x01, b'a', 0xff, 0xff])), None);
+        // Inside a record's data, here the PTR answer's
+        let mut response = RESPONSE.to_vec();
+        response[46] = 0xc0;
+        response[47] = 0xc8;
+        assert_eq!(parse(&response), None);
+    }
+
+    #[test]
+    fn rejects_reserved_label_types() {
+        assert_eq!(parse(&query_with_name(&[0x40, 0x00])), None);
+        assert_eq!(parse(&query_with_name(&[0x80, 0x00])), None);
+    }
+}
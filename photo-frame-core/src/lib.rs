@@ -0,0 +1,34 @@
+//
+// The parts of the firmware that don't touch the hardware, so they can be
+// built and tested on the host: `cargo +stable test -p photo-frame-core
+// --target x86_64-unknown-linux-gnu`.
+//
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub mod color;
+pub mod commands;
+pub mod dns;
+pub mod mqtt;
+pub mod pulse;
+pub mod records;
+pub mod schedule;
+pub mod sequence;
+pub mod tz;
+
+// defmt needs somewhere to send its output, even in tests that log nothing
+#[cfg(test)]
+mod test_logger {
+    #[defmt::global_logger]
+    struct Logger;
+
+    unsafe impl defmt::Logger for Logger {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_: &[u8]) {}
+    }
+
+    defmt::timestamp!("{=u8}", 0);
+}
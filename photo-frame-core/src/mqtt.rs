@@ -0,0 +1,485 @@
+//
+// Minimal MQTT 3.1.1 client: QoS 0 publish and subscribe, acknowledging any
+// QoS 1 messages, last will and keep-alive pings. Enough for a frame
+// reporting state and taking commands.
+//
+use alloc::{string::String, vec::Vec};
+use defmt::Format;
+use embassy_net::tcp::TcpSocket;
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::Write;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+
+// Largest packet we accept from the broker
+const MAX_PACKET_LEN: usize = 2048;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Error {
+    Network,
+    Timeout,
+    Protocol,
+    PacketTooLarge,
+    /// The broker refused the connection with this return code
+    Refused(u8),
+}
+
+impl From<embassy_net::tcp::Error> for Error {
+    fn from(_: embassy_net::tcp::Error) -> Self {
+        Error::Network
+    }
+}
+
+pub struct Will<'a> {
+    pub topic: &'a str,
+    pub message: &'a [u8],
+    pub retain: bool,
+}
+
+pub struct ConnectOptions<'a> {
+    pub client_id: &'a str,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+    pub keep_alive: Duration,
+    pub will: Option<Will<'a>>,
+}
+
+/// A packet from the broker
+#[derive(Debug, PartialEq, Eq)]
+pub enum Packet<'a> {
+    ConnAck {
+        code: u8,
+    },
+    Publish {
+        topic: &'a str,
+        payload: &'a [u8],
+        /// Set for QoS 1 and 2 messages, which need acknowledging
+        packet_id: Option<u16>,
+    },
+    SubAck,
+    PingResp,
+    Other,
+}
+
+/// A message received on a subscribed topic
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+fn push_length(packet: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+fn push_bytes(packet: &mut Vec<u8>, bytes: &[u8]) {
+    packet.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    packet.extend_from_slice(bytes);
+}
+
+// Fixed header followed by the rest of the packet
+fn packet(kind: u8, rest: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(rest.len() + 5);
+    packet.push(kind);
+    push_length(&mut packet, rest.len());
+    packet.extend_from_slice(rest);
+    packet
+}
+
+pub fn encode_connect(options: &ConnectOptions) -> Vec<u8> {
+    let mut flags = 0x02; // clean session
+    if let Some(will) = &options.will {
+        flags |= 0x04;
+        if will.retain {
+            flags |= 0x20;
+        }
+    }
+    if options.username.is_some() {
+        flags |= 0x80;
+    }
+    if options.password.is_some() {
+        flags |= 0x40;
+    }
+
+    let mut rest = Vec::new();
+    push_bytes(&mut rest, b"MQTT");
+    rest.push(4); // protocol level 3.1.1
+    rest.push(flags);
+    let keep_alive = options.keep_alive.as_secs().min(u16::MAX as u64) as u16;
+    rest.extend_from_slice(&keep_alive.to_be_bytes());
+    push_bytes(&mut rest, options.client_id.as_bytes());
+    if let Some(will) = &options.will {
+        push_bytes(&mut rest, will.topic.as_bytes());
+        push_bytes(&mut rest, will.message);
+    }
+    if let Some(username) = options.username {
+        push_bytes(&mut rest, username.as_bytes());
+    }
+    if let Some(password) = options.password {
+        push_bytes(&mut rest, password.as_bytes());
+    }
+    packet(CONNECT, &rest)
+}
+
+pub fn encode_publish(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut rest = Vec::with_capacity(topic.len() + payload.len() + 2);
+    push_bytes(&mut rest, topic.as_bytes());
+    rest.extend_from_slice(payload);
+    packet(PUBLISH | u8::from(retain), &rest)
+}
+
+pub fn encode_puback(packet_id: u16) -> Vec<u8> {
+    packet(PUBACK, &packet_id.to_be_bytes())
+}
+
+pub fn encode_subscribe(packet_id: u16, topic: &str) -> Vec<u8> {
+    let mut rest = Vec::with_capacity(topic.len() + 5);
+    rest.extend_from_slice(&packet_id.to_be_bytes());
+    push_bytes(&mut rest, topic.as_bytes());
+    rest.push(0); // QoS 0
+    packet(SUBSCRIBE, &rest)
+}
+
+fn read_u16(bytes: &[u8]) -> Result<u16, Error> {
+    match bytes {
+        [high, low, ..] => Ok(u16::from_be_bytes([*high, *low])),
+        _ => Err(Error::Protocol),
+    }
+}
+
+/// Parses one packet from the start of `buffer`, returning it and its length,
+/// or `None` if more bytes are needed
+pub fn parse(buffer: &[u8]) -> Result<Option<(Packet<'_>, usize)>, Error> {
+    let Some(&first) = buffer.first() else {
+        return Ok(None);
+    };
+
+    // Remaining length: up to four bytes, seven bits each
+    let mut len = 0usize;
+    let mut header_len = 1;
+    loop {
+        let Some(&byte) = buffer.get(header_len) else {
+            return Ok(None);
+        };
+        len |= ((byte & 0x7f) as usize) << (7 * (header_len - 1));
+        header_len += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if header_len > 4 {
+            return Err(Error::Protocol);
+        }
+    }
+    if len > MAX_PACKET_LEN {
+        return Err(Error::PacketTooLarge);
+    }
+    let Some(body) = buffer.get(header_len..header_len + len) else {
+        return Ok(None);
+    };
+
+    let packet = match first & 0xf0 {
+        CONNACK => Packet::ConnAck {
+            code: *body.get(1).ok_or(Error::Protocol)?,
+        },
+        PUBLISH => {
+            let topic_len = read_u16(body)? as usize;
+            let topic = body.get(2..2 + topic_len).ok_or(Error::Protocol)?;
+            let topic = core::str::from_utf8(topic).map_err(|_| Error::Protocol)?;
+            let mut rest = &body[2 + topic_len..];
+            let packet_id = if first & 0x06 != 0 {
+                let id = read_u16(rest)?;
+                rest = &rest[2..];
+                Some(id)
+            } else {
+                None
+            };
+            Packet::Publish {
+                topic,
+                payload: rest,
+                packet_id,
+            }
+        }
+        SUBACK => Packet::SubAck,
+        PINGRESP => Packet::PingResp,
+        _ => Packet::Other,
+    };
+    Ok(Some((packet, header_len + len)))
+}
+
+/// A connection to a broker over an already connected TCP socket
+pub struct Client<'s> {
+    socket: TcpSocket<'s>,
+    rx: Vec<u8>,
+    /// Acknowledgements still to send, so `next_message` needn't wait on them
+    acks: Vec<u8>,
+    next_packet_id: u16,
+}
+
+impl<'s> Client<'s> {
+    /// Sends CONNECT and waits for the broker to accept it
+    pub async fn connect(
+        socket: TcpSocket<'s>,
+        options: &ConnectOptions<'_>,
+    ) -> Result<Self, Error> {
+        let mut client = Self {
+            socket,
+            rx: Vec::new(),
+            acks: Vec::new(),
+            next_packet_id: 1,
+        };
+        client.send(&encode_connect(options)).await?;
+        with_timeout(CONNECT_TIMEOUT, client.wait_for_connack())
+            .await
+            .map_err(|_| Error::Timeout)??;
+        Ok(client)
+    }
+
+    async fn wait_for_connack(&mut self) -> Result<(), Error> {
+        loop {
+            self.fill().await?;
+            if let Some((packet, len)) = parse(&self.rx)? {
+                let result = match packet {
+                    Packet::ConnAck { code: 0 } => Ok(()),
+                    Packet::ConnAck { code } => Err(Error::Refused(code)),
+                    _ => Err(Error::Protocol),
+                };
+                self.rx.drain(..len);
+                return result;
+            }
+        }
+    }
+
+    async fn send(&mut self, packet: &[u8]) -> Result<(), Error> {
+        self.send_acks().await?;
+        self.socket.write_all(packet).await?;
+        self.socket.flush().await?;
+        Ok(())
+    }
+
+    // Dropping only what the socket took, so a cancelled call neither loses
+    // nor repeats any bytes
+    async fn send_acks(&mut self) -> Result<(), Error> {
+        while !self.acks.is_empty() {
+            let written = self.socket.write(&self.acks).await?;
+            self.acks.drain(..written);
+        }
+        Ok(())
+    }
+
+    // Reads whatever the broker has sent into the receive buffer
+    async fn fill(&mut self) -> Result<(), Error> {
+        let mut chunk = [0u8; 256];
+        let read = self.socket.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(Error::Network);
+        }
+        // No await between the read and keeping the bytes, so a cancelled
+        // `next_message` loses nothing
+        self.rx.extend_from_slice(&chunk[..read]);
+        Ok(())
+    }
+
+    pub async fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        retain: bool,
+    ) -> Result<(), Error> {
+        self.send(&encode_publish(topic, payload, retain)).await
+    }
+
+    pub async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let packet_id = self.next_packet_id;
+        self.next_packet_id = self.next_packet_id.wrapping_add(1).max(1);
+        self.send(&encode_subscribe(packet_id, topic)).await
+    }
+
+    pub async fn ping(&mut self) -> Result<(), Error> {
+        self.send(&[PINGREQ, 0]).await
+    }
+
+    /// Waits for the next message on a subscribed topic, dealing with any
+    /// acknowledgements and ping responses on the way. Safe to cancel.
+    ///
+    /// A message that needs acknowledging is returned as soon as it's taken
+    /// out of the receive buffer, with the PUBACK queued to go out at the
+    /// start of the next call or send.
+    pub async fn next_message(&mut self) -> Result<Message, Error> {
+        self.send_acks().await?;
+        loop {
+            while let Some((packet, len)) = parse(&self.rx)? {
+                let message = match packet {
+                    Packet::Publish {
+                        topic,
+                        payload,
+                        packet_id,
+                    } => Some((
+                        Message {
+                            topic: String::from(topic),
+                            payload: Vec::from(payload),
+                        },
+                        packet_id,
+                    )),
+                    _ => None,
+                };
+                // No await from here on, so the message can't be lost
+                self.rx.drain(..len);
+                if let Some((message, packet_id)) = message {
+                    if let Some(id) = packet_id {
+                        self.acks.extend_from_slice(&encode_puback(id));
+                    }
+                    return Ok(message);
+                }
+            }
+            self.fill().await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publish_round_trip() {
+        let packet = encode_publish("frame/state", b"{\"on\":true}", false);
+        let (parsed, len) = parse(&packet).unwrap().unwrap();
+        assert_eq!(len, packet.len());
+        assert_eq!(
+            parsed,
+            Packet::Publish {
+                topic: "frame/state",
+                payload: b"{\"on\":true}",
+                packet_id: None,
+            }
+        );
+    }
+
+    #[test]
+    fn long_publish_round_trip() {
+        // 303 bytes after the fixed header needs two bytes of remaining length
+        let payload = [0x5a; 300];
+        let packet = encode_publish("t", &payload, true);
+        assert_eq!(packet[0], PUBLISH | 1);
+        assert_eq!(&packet[1..3], &[0xaf, 0x02]);
+        let (parsed, len) = parse(&packet).unwrap().unwrap();
+        assert_eq!(len, packet.len());
+        assert_eq!(
+            parsed,
+            Packet::Publish {
+                topic: "t",
+                payload: &payload,
+                packet_id: None,
+            }
+        );
+    }
+
+    #[test]
+    fn qos1_publish_has_packet_id() {
+        let packet = [PUBLISH | 0x02, 7, 0, 1, b'c', 0x12, 0x34, b'o', b'n'];
+        let (parsed, len) = parse(&packet).unwrap().unwrap();
+        assert_eq!(len, packet.len());
+        assert_eq!(
+            parsed,
+            Packet::Publish {
+                topic: "c",
+                payload: b"on",
+                packet_id: Some(0x1234),
+            }
+        );
+        assert_eq!(encode_puback(0x1234), [PUBACK, 2, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn waits_for_whole_packet() {
+        let packet = encode_publish("frame/command", b"refresh", false);
+        for end in 0..packet.len() {
+            assert_eq!(parse(&packet[..end]), Ok(None));
+        }
+        // A second packet behind the first is left for next time
+        let mut two = packet.clone();
+        two.extend_from_slice(&[PINGRESP, 0]);
+        let (_, len) = parse(&two).unwrap().unwrap();
+        assert_eq!(parse(&two[len..]), Ok(Some((Packet::PingResp, 2))));
+    }
+
+    #[test]
+    fn broker_replies() {
+        assert_eq!(
+            parse(&[CONNACK, 2, 0, 0]),
+            Ok(Some((Packet::ConnAck { code: 0 }, 4)))
+        );
+        assert_eq!(
+            parse(&[CONNACK, 2, 0, 5]),
+            Ok(Some((Packet::ConnAck { code: 5 }, 4)))
+        );
+        assert_eq!(parse(&[SUBACK, 3, 0, 1, 0]), Ok(Some((Packet::SubAck, 5))));
+        assert_eq!(parse(&[0xb0, 2, 0, 1]), Ok(Some((Packet::Other, 4))));
+    }
+
+    #[test]
+    fn rejects_bad_lengths() {
+        assert_eq!(
+            parse(&[PUBLISH, 0xff, 0xff, 0xff, 0xff, 0x01]),
+            Err(Error::Protocol)
+        );
+        assert_eq!(
+            parse(&[PUBLISH, 0x80, 0x80, 0x01]),
+            Err(Error::PacketTooLarge)
+        );
+        // Topic longer than the packet
+        assert_eq!(parse(&[PUBLISH, 3, 0, 9, b'a']), Err(Error::Protocol));
+    }
+
+    #[test]
+    fn connect_bytes() {
+        let options = ConnectOptions {
+            client_id: "f1",
+            username: Some("u"),
+            password: Some("p"),
+            keep_alive: Duration::from_secs(60),
+            will: Some(Will {
+                topic: "w",
+                message: b"off",
+                retain: true,
+            }),
+        };
+        #[rustfmt::skip]
+        let expected = [
+            CONNECT, 28,
+            0, 4, b'M', b'Q', b'T', b'T', 4,
+            // Clean session, will, will retain, password and username
+            0xe6, 0, 60,
+            0, 2, b'f', b'1',
+            0, 1, b'w', 0, 3, b'o', b'f', b'f',
+            0, 1, b'u',
+            0, 1, b'p',
+        ];
+        assert_eq!(encode_connect(&options), expected);
+    }
+
+    #[test]
+    fn subscribe_bytes() {
+        assert_eq!(
+            encode_subscribe(0x0102, "a/b"),
+            [SUBSCRIBE, 8, 0x01, 0x02, 0, 3, b'a', b'/', b'b', 0]
+        );
+    }
+}
@@ -0,0 +1,365 @@
+//
+// Turning colours into RMT pulse codes for addressable LEDs: channel order,
+// gamma, brightness and bit timing. Kept apart from `led` so it doesn't need
+// the hardware.
+//
+use core::slice::IterMut;
+use defmt::Format;
+use smart_leds_trait::RGB8;
+
+/// All types of errors that can happen during the conversion and transmission
+/// of LED commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum LedAdapterError {
+    /// Raised in the event that the provided data container is not large enough
+    BufferSizeExceeded,
+    /// Raised if something goes wrong in the transmission,
+    TransmissionError,
+    /// Raised if the RMT channel was lost to an earlier failed transmission
+    ChannelUnavailable,
+    /// Raised if the RMT channel couldn't be set up
+    ChannelConfig,
+}
+
+// An RMT pulse code: high for `high` ticks, then low for `low`. A code of 0
+// ends the sequence.
+fn pulse_code(high: u16, low: u16) -> u32 {
+    (1 << 15) | (high as u32 & 0x7fff) | (low as u32 & 0x7fff) << 16
+}
+
+/// Order the colour channels are sent in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorOrder {
+    Rgb,
+    Grb,
+    /// RGB with the part shared by all three sent on a white channel
+    Rgbw,
+    Grbw,
+}
+
+impl ColorOrder {
+    /// Looks up an order by name, e.g. "grb"
+    pub fn by_name(name: &str) -> Option<ColorOrder> {
+        match name {
+            "rgb" => Some(ColorOrder::Rgb),
+            "grb" => Some(ColorOrder::Grb),
+            "rgbw" => Some(ColorOrder::Rgbw),
+            "grbw" => Some(ColorOrder::Grbw),
+            _ => None,
+        }
+    }
+
+    /// Colour channels sent per LED
+    pub fn channels(self) -> usize {
+        match self {
+            ColorOrder::Rgb | ColorOrder::Grb => 3,
+            ColorOrder::Rgbw | ColorOrder::Grbw => 4,
+        }
+    }
+
+    // Channel values in the order they're sent, and how many there are
+    fn arrange(self, value: RGB8) -> ([u8; 4], usize) {
+        let RGB8 { r, g, b } = value;
+        let w = r.min(g).min(b);
+        match self {
+            ColorOrder::Rgb => ([r, g, b, 0], 3),
+            ColorOrder::Grb => ([g, r, b, 0], 3),
+            ColorOrder::Rgbw => ([r - w, g - w, b - w, w], 4),
+            ColorOrder::Grbw => ([g - w, r - w, b - w, w], 4),
+        }
+    }
+}
+
+/// High and low times of the pulses for each bit, which differ between
+/// families of LED
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    pub name: &'static str,
+    /// High then low time of a 0 bit, in nanoseconds
+    pub zero: (u32, u32),
+    /// High then low time of a 1 bit, in nanoseconds
+    pub one: (u32, u32),
+}
+
+pub const WS2812B: Timing = Timing {
+    name: "ws2812b",
+    zero: (400, 850),
+    one: (800, 450),
+};
+
+/// Also right for the SK68XX on ESP32-S3 dev boards
+pub const SK6812: Timing = Timing {
+    name: "sk6812",
+    zero: (320, 880),
+    one: (640, 560),
+};
+
+/// In its 800 kHz mode
+pub const WS2811: Timing = Timing {
+    name: "ws2811",
+    zero: (250, 1000),
+    one: (600, 650),
+};
+
+static TIMINGS: &[&Timing] = &[&WS2812B, &SK6812, &WS2811];
+
+impl Timing {
+    /// Looks up one of the built-in timings by name
+    pub fn by_name(name: &str) -> Option<Timing> {
+        TIMINGS.iter().copied().copied().find(|t| t.name == name)
+    }
+
+    /// Pulse codes for a 0 and a 1 bit, with the RMT counting at `clock_mhz`
+    pub fn pulses(&self, clock_mhz: u32) -> (u32, u32) {
+        let code = |(high, low): (u32, u32)| {
+            pulse_code(
+                ((high * clock_mhz) / 1000) as u16,
+                ((low * clock_mhz) / 1000) as u16,
+            )
+        };
+        (code(self.zero), code(self.one))
+    }
+}
+
+/// Gamma 2.8, so that evenly spaced values look evenly spaced in brightness
+#[rustfmt::skip]
+pub static GAMMA_2_8: [u8; 256] = [
+      0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,
+      0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   1,   1,   1,   1,
+      1,   1,   1,   1,   1,   1,   1,   1,   1,   2,   2,   2,   2,   2,   2,   2,
+      2,   3,   3,   3,   3,   3,   3,   3,   4,   4,   4,   4,   4,   5,   5,   5,
+      5,   6,   6,   6,   6,   7,   7,   7,   7,   8,   8,   8,   9,   9,   9,  10,
+     10,  10,  11,  11,  11,  12,  12,  13,  13,  13,  14,  14,  15,  15,  16,  16,
+     17,  17,  18,  18,  19,  19,  20,  20,  21,  21,  22,  22,  23,  24,  24,  25,
+     25,  26,  27,  27,  28,  29,  29,  30,  31,  32,  32,  33,  34,  35,  35,  36,
+     37,  38,  39,  39,  40,  41,  42,  43,  44,  45,  46,  47,  48,  49,  50,  50,
+     51,  52,  54,  55,  56,  57,  58,  59,  60,  61,  62,  63,  64,  66,  67,  68,
+     69,  70,  72,  73,  74,  75,  77,  78,  79,  81,  82,  83,  85,  86,  87,  89,
+     90,  92,  93,  95,  96,  98,  99, 101, 102, 104, 105, 107, 109, 110, 112, 114,
+    115, 117, 119, 120, 122, 124, 126, 127, 129, 131, 133, 135, 137, 138, 140, 142,
+    144, 146, 148, 150, 152, 154, 156, 158, 160, 162, 164, 167, 169, 171, 173, 175,
+    177, 180, 182, 184, 186, 189, 191, 193, 196, 198, 200, 203, 205, 208, 210, 213,
+    215, 218, 220, 223, 225, 228, 231, 233, 236, 239, 241, 244, 247, 249, 252, 255,
+];
+
+/// How colours are turned into what's sent to the LEDs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LedConfig {
+    pub order: ColorOrder,
+    pub timing: Timing,
+    /// Applied to each channel before `brightness`; none leaves values as they are
+    pub gamma: Option<&'static [u8; 256]>,
+    /// Scales every channel, out of 255
+    pub brightness: u8,
+    /// LEDs on the line
+    pub count: usize,
+}
+
+impl Default for LedConfig {
+    fn default() -> Self {
+        LedConfig {
+            order: ColorOrder::Grb,
+            timing: SK6812,
+            gamma: None,
+            brightness: 255,
+            count: 1,
+        }
+    }
+}
+
+impl LedConfig {
+    /// Pulse codes needed to set every LED, end marker included
+    pub fn pulse_count(&self) -> usize {
+        self.count * self.order.channels() * 8 + 1
+    }
+
+    fn correct(&self, value: u8) -> u8 {
+        let value = self.gamma.map_or(value, |table| table[value as usize]);
+        (value as u16 * self.brightness as u16 / 255) as u8
+    }
+}
+
+fn convert_rgb_to_pulse(
+    value: RGB8,
+    mut_iter: &mut IterMut<u32>,
+    config: &LedConfig,
+    pulses: (u32, u32),
+) -> Result<(), LedAdapterError> {
+    let (channels, count) = config.order.arrange(value);
+    for channel_value in &channels[..count] {
+        convert_rgb_channel_to_pulses(config.correct(*channel_value), mut_iter, pulses)?;
+    }
+
+    Ok(())
+}
+
+fn convert_rgb_channel_to_pulses(
+    channel_value: u8,
+    mut_iter: &mut IterMut<u32>,
+    pulses: (u32, u32),
+) -> Result<(), LedAdapterError> {
+    for position in [128, 64, 32, 16, 8, 4, 2, 1] {
+        *mut_iter.next().ok_or(LedAdapterError::BufferSizeExceeded)? =
+            match channel_value & position {
+                0 => pulses.0,
+                _ => pulses.1,
+            }
+    }
+
+    Ok(())
+}
+
+/// Converts all the colours to pulse codes at the start of the buffer,
+/// followed by an end marker, and returns how much of the buffer they take
+///
+/// `pulses` are the codes for a 0 and a 1 bit, from [`Timing::pulses`].
+pub fn encode<T, I>(
+    rmt_buffer: &mut [u32],
+    iterator: T,
+    config: &LedConfig,
+    pulses: (u32, u32),
+) -> Result<usize, LedAdapterError>
+where
+    T: IntoIterator<Item = I>,
+    I: Into<RGB8>,
+{
+    let size = rmt_buffer.len();
+    // We always start from the beginning of the buffer
+    let mut seq_iter = rmt_buffer.iter_mut();
+
+    // Add all converted iterator items to the buffer.
+    // This will result in an `BufferSizeExceeded` error in case
+    // the iterator provides more elements than the buffer can take.
+    for item in iterator {
+        convert_rgb_to_pulse(item.into(), &mut seq_iter, config, pulses)?;
+    }
+
+    // Finally, add an end element.
+    *seq_iter.next().ok_or(LedAdapterError::BufferSizeExceeded)? = 0;
+
+    Ok(size - seq_iter.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    const ORANGE: RGB8 = RGB8 {
+        r: 200,
+        g: 100,
+        b: 50,
+    };
+
+    // High and low ticks of a pulse code
+    fn ticks(code: u32) -> (u32, u32) {
+        (code & 0x7fff, (code >> 16) & 0x7fff)
+    }
+
+    #[test]
+    fn orders_arrange_channels() {
+        assert_eq!(ColorOrder::Rgb.arrange(ORANGE), ([200, 100, 50, 0], 3));
+        assert_eq!(ColorOrder::Grb.arrange(ORANGE), ([100, 200, 50, 0], 3));
+        assert_eq!(ColorOrder::Rgbw.arrange(ORANGE), ([150, 50, 0, 50], 4));
+        assert_eq!(ColorOrder::Grbw.arrange(ORANGE), ([50, 150, 0, 50], 4));
+    }
+
+    #[test]
+    fn white_takes_all_of_grey() {
+        let grey = RGB8 {
+            r: 80,
+            g: 80,
+            b: 80,
+        };
+        assert_eq!(ColorOrder::Rgbw.arrange(grey), ([0, 0, 0, 80], 4));
+        assert_eq!(ColorOrder::Grbw.arrange(grey), ([0, 0, 0, 80], 4));
+    }
+
+    #[test]
+    fn orders_by_name() {
+        assert_eq!(ColorOrder::by_name("rgb"), Some(ColorOrder::Rgb));
+        assert_eq!(ColorOrder::by_name("grb"), Some(ColorOrder::Grb));
+        assert_eq!(ColorOrder::by_name("rgbw"), Some(ColorOrder::Rgbw));
+        assert_eq!(ColorOrder::by_name("grbw"), Some(ColorOrder::Grbw));
+        assert_eq!(ColorOrder::by_name("bgr"), None);
+    }
+
+    #[test]
+    fn gamma_and_brightness() {
+        assert_eq!(GAMMA_2_8[0], 0);
+        assert_eq!(GAMMA_2_8[255], 255);
+        assert!(GAMMA_2_8.windows(2).all(|pair| pair[0] <= pair[1]));
+
+        let mut config = LedConfig::default();
+        assert_eq!(config.correct(128), 128);
+        config.gamma = Some(&GAMMA_2_8);
+        assert_eq!(config.correct(128), 37);
+        config.brightness = 32;
+        assert_eq!(config.correct(128), 4);
+        assert_eq!(config.correct(255), 32);
+        config.gamma = None;
+        assert_eq!(config.correct(128), 16);
+    }
+
+    #[test]
+    fn timings_at_80_mhz() {
+        let expected = [
+            (WS2812B, (32, 68), (64, 36)),
+            (SK6812, (25, 70), (51, 44)),
+            (WS2811, (20, 80), (48, 52)),
+        ];
+        for (timing, zero, one) in expected {
+            let (zero_code, one_code) = timing.pulses(80);
+            assert_eq!(ticks(zero_code), zero, "{}", timing.name);
+            assert_eq!(ticks(one_code), one, "{}", timing.name);
+            // Each starts high and ends low
+            assert_eq!(zero_code & 0x8000_8000, 0x8000);
+            assert_eq!(one_code & 0x8000_8000, 0x8000);
+            assert_eq!(Timing::by_name(timing.name), Some(timing));
+        }
+        assert_eq!(Timing::by_name("apa102"), None);
+    }
+
+    #[test]
+    fn encodes_bits_most_significant_first() {
+        let config = LedConfig::default();
+        let pulses = (1, 2);
+        let mut buffer = [u32::MAX; 30];
+        let len = encode(&mut buffer, [ORANGE], &config, pulses).unwrap();
+        assert_eq!(len, config.pulse_count());
+        assert_eq!(len, 25);
+        // Green first for GRB
+        let bits = |value: u8| (0..8).rev().map(move |i| [1, 2][(value >> i & 1) as usize]);
+        let expected: Vec<u32> = bits(100).chain(bits(200)).chain(bits(50)).collect();
+        assert_eq!(&buffer[..24], &expected[..]);
+        assert_eq!(buffer[24], 0);
+        assert_eq!(buffer[25], u32::MAX);
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let config = LedConfig::default();
+        // No room for the end marker
+        let mut buffer = [0; 24];
+        assert_eq!(
+            encode(&mut buffer, [ORANGE], &config, (1, 2)),
+            Err(LedAdapterError::BufferSizeExceeded)
+        );
+        // No room for the second LED
+        let mut buffer = [0; 25];
+        assert_eq!(
+            encode(&mut buffer, [ORANGE, ORANGE], &config, (1, 2)),
+            Err(LedAdapterError::BufferSizeExceeded)
+        );
+    }
+
+    #[test]
+    fn pulse_count_includes_white_and_end_marker() {
+        let config = LedConfig {
+            order: ColorOrder::Grbw,
+            count: 5,
+            ..LedConfig::default()
+        };
+        assert_eq!(config.pulse_count(), 161);
+    }
+}
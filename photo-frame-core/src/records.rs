@@ -0,0 +1,344 @@
+//
+// Small records kept in NOR flash so they outlast power cuts.
+//
+// Each kind of record has a ring of sectors to itself, split into equal
+// slots. A write goes into the next blank slot with a higher sequence number
+// and a CRC, and a read takes the newest slot that checks out, so a write cut
+// short leaves the previous record in place. A sector is only erased when the
+// ring comes back round to it, which spreads the wear.
+//
+use alloc::vec;
+use defmt::Format;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+const MAGIC: u32 = 0x4652_4543;
+// Magic, sequence number, length and CRC, four bytes each
+const HEADER_SIZE: usize = 16;
+pub const SECTOR_SIZE: u32 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Error {
+    Flash,
+    /// The record doesn't fit in a slot
+    TooLarge,
+}
+
+/// Where one kind of record lives
+#[derive(Debug, Clone, Copy)]
+pub struct Ring {
+    /// Sector the ring starts at, counting from the start of the flash given
+    pub first_sector: u32,
+    /// At least two, so the newest record is never in the sector being erased
+    pub sectors: u32,
+    /// Bytes per slot, header included; divides the sector size
+    pub slot_size: u32,
+}
+
+/// CRC-32 (IEEE) of the parts, one after another
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn word(bytes: &[u8], index: usize) -> u32 {
+    u32::from_le_bytes(bytes[index * 4..index * 4 + 4].try_into().unwrap())
+}
+
+impl Ring {
+    fn slots_per_sector(&self) -> u32 {
+        SECTOR_SIZE / self.slot_size
+    }
+
+    fn slots(&self) -> u32 {
+        self.sectors * self.slots_per_sector()
+    }
+
+    fn address(&self, slot: u32) -> u32 {
+        let per_sector = self.slots_per_sector();
+        (self.first_sector + slot / per_sector) * SECTOR_SIZE + slot % per_sector * self.slot_size
+    }
+
+    /// Largest record that fits
+    pub const fn capacity(&self) -> usize {
+        self.slot_size as usize - HEADER_SIZE
+    }
+
+    // The newest slot whose record checks out, with its sequence number and length
+    fn newest<F: ReadNorFlash>(
+        &self,
+        flash: &mut F,
+        slot_buffer: &mut [u8],
+    ) -> Result<Option<(u32, u32, usize)>, Error> {
+        let mut newest: Option<(u32, u32, usize)> = None;
+        for slot in 0..self.slots() {
+            flash
+                .read(self.address(slot), slot_buffer)
+                .map_err(|_| Error::Flash)?;
+            let (sequence, len, crc) = (
+                word(slot_buffer, 1),
+                word(slot_buffer, 2),
+                word(slot_buffer, 3),
+            );
+            if word(slot_buffer, 0) != MAGIC || len as usize > self.capacity() {
+                continue;
+            }
+            let record = &slot_buffer[HEADER_SIZE..HEADER_SIZE + len as usize];
+            if crc32(&[&slot_buffer[4..12], record]) != crc {
+                continue;
+            }
+            if newest.is_none_or(|(_, newest, _)| sequence > newest) {
+                newest = Some((slot, sequence, len as usize));
+            }
+        }
+        Ok(newest)
+    }
+
+    /// Reads the newest record into `buffer`, returning its length, or None if
+    /// there isn't one
+    pub fn read<F: ReadNorFlash>(
+        &self,
+        flash: &mut F,
+        buffer: &mut [u8],
+    ) -> Result<Option<usize>, Error> {
+        let mut slot_buffer = vec![0u8; self.slot_size as usize];
+        let Some((slot, _, len)) = self.newest(flash, &mut slot_buffer)? else {
+            return Ok(None);
+        };
+        flash
+            .read(self.address(slot), &mut slot_buffer)
+            .map_err(|_| Error::Flash)?;
+        let len = len.min(buffer.len());
+        buffer[..len].copy_from_slice(&slot_buffer[HEADER_SIZE..HEADER_SIZE + len]);
+        Ok(Some(len))
+    }
+
+    /// Erases the ring, forgetting every record in it
+    pub fn clear<F: NorFlash>(&self, flash: &mut F) -> Result<(), Error> {
+        let start = self.first_sector * SECTOR_SIZE;
+        flash
+            .erase(start, start + self.sectors * SECTOR_SIZE)
+            .map_err(|_| Error::Flash)
+    }
+
+    /// Stores `record` as the newest
+    pub fn write<F: NorFlash>(&self, flash: &mut F, record: &[u8]) -> Result<(), Error> {
+        if record.len() > self.capacity() {
+            return Err(Error::TooLarge);
+        }
+        let mut slot_buffer = vec![0xFFu8; self.slot_size as usize];
+        let (mut slot, sequence) = match self.newest(flash, &mut slot_buffer)? {
+            Some((slot, sequence, _)) => ((slot + 1) % self.slots(), sequence.wrapping_add(1)),
+            None => (0, 0),
+        };
+
+        let per_sector = self.slots_per_sector();
+        if slot % per_sector != 0 {
+            flash
+                .read(self.address(slot), &mut slot_buffer)
+                .map_err(|_| Error::Flash)?;
+            // Left over from a write that was cut short, so start on a fresh sector
+            if slot_buffer.iter().any(|&byte| byte != 0xFF) {
+                slot = (slot / per_sector + 1) % self.sectors * per_sector;
+            }
+        }
+        if slot % per_sector == 0 {
+            let start = (self.first_sector + slot / per_sector) * SECTOR_SIZE;
+            flash
+                .erase(start, start + SECTOR_SIZE)
+                .map_err(|_| Error::Flash)?;
+        }
+
+        let len = record.len() as u32;
+        let header = [MAGIC, sequence, len, 0];
+        for (bytes, value) in slot_buffer.chunks_exact_mut(4).zip(header) {
+            bytes.copy_from_slice(&value.to_le_bytes());
+        }
+        slot_buffer[HEADER_SIZE..HEADER_SIZE + record.len()].copy_from_slice(record);
+        let crc = crc32(&[&slot_buffer[4..12], record]);
+        slot_buffer[12..16].copy_from_slice(&crc.to_le_bytes());
+        // Whole words, with anything past the record left erased
+        let used = (HEADER_SIZE + record.len()).next_multiple_of(F::WRITE_SIZE);
+        slot_buffer[HEADER_SIZE + record.len()..].fill(0xFF);
+        flash
+            .write(self.address(slot), &slot_buffer[..used])
+            .map_err(|_| Error::Flash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use embedded_storage::nor_flash::{ErrorType, NorFlashError, NorFlashErrorKind};
+
+    const RING: Ring = Ring {
+        first_sector: 1,
+        sectors: 2,
+        slot_size: 1024,
+    };
+
+    #[derive(Debug)]
+    struct MockError;
+
+    impl NorFlashError for MockError {
+        fn kind(&self) -> NorFlashErrorKind {
+            NorFlashErrorKind::Other
+        }
+    }
+
+    // Flash in RAM that, like the real thing, can only clear bits until erased
+    struct MockFlash {
+        bytes: Vec<u8>,
+        erases: usize,
+    }
+
+    impl MockFlash {
+        fn new() -> Self {
+            MockFlash {
+                bytes: vec![0xFF; 4 * SECTOR_SIZE as usize],
+                erases: 0,
+            }
+        }
+    }
+
+    impl ErrorType for MockFlash {
+        type Error = MockError;
+    }
+
+    impl ReadNorFlash for MockFlash {
+        const READ_SIZE: usize = 4;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), MockError> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.bytes[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.bytes.len()
+        }
+    }
+
+    impl NorFlash for MockFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = SECTOR_SIZE as usize;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), MockError> {
+            assert_eq!(from % SECTOR_SIZE, 0);
+            assert_eq!(to % SECTOR_SIZE, 0);
+            self.bytes[from as usize..to as usize].fill(0xFF);
+            self.erases += 1;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), MockError> {
+            assert_eq!(offset % 4, 0);
+            assert_eq!(bytes.len() % 4, 0);
+            for (stored, &byte) in self.bytes[offset as usize..].iter_mut().zip(bytes) {
+                *stored &= byte;
+            }
+            Ok(())
+        }
+    }
+
+    fn read(flash: &mut MockFlash) -> Option<Vec<u8>> {
+        let mut buffer = [0u8; 1024];
+        let len = RING.read(flash, &mut buffer).unwrap()?;
+        Some(buffer[..len].to_vec())
+    }
+
+    #[test]
+    fn crc_matches_reference() {
+        assert_eq!(crc32(&[b"123456789"]), 0xCBF4_3926);
+        assert_eq!(crc32(&[b"1234", b"56789"]), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn blank_flash_has_no_record() {
+        assert_eq!(read(&mut MockFlash::new()), None);
+    }
+
+    #[test]
+    fn reads_back_newest_record() {
+        let mut flash = MockFlash::new();
+        RING.write(&mut flash, b"first").unwrap();
+        RING.write(&mut flash, b"second").unwrap();
+        assert_eq!(read(&mut flash).as_deref(), Some(&b"second"[..]));
+    }
+
+    #[test]
+    fn stays_inside_its_sectors() {
+        let mut flash = MockFlash::new();
+        for i in 0..20u8 {
+            RING.write(&mut flash, &[i; 100]).unwrap();
+        }
+        let sector = SECTOR_SIZE as usize;
+        assert!(flash.bytes[..sector].iter().all(|&byte| byte == 0xFF));
+        assert!(flash.bytes[3 * sector..].iter().all(|&byte| byte == 0xFF));
+        assert_eq!(read(&mut flash), Some(vec![19; 100]));
+    }
+
+    #[test]
+    fn erases_only_when_wrapping_round() {
+        let mut flash = MockFlash::new();
+        // Four slots a sector, so eight writes go round the ring once
+        for i in 0..8u8 {
+            RING.write(&mut flash, &[i]).unwrap();
+        }
+        assert_eq!(flash.erases, 2);
+        RING.write(&mut flash, &[8]).unwrap();
+        assert_eq!(flash.erases, 3);
+        assert_eq!(read(&mut flash), Some(vec![8]));
+    }
+
+    #[test]
+    fn cut_short_write_keeps_previous_record() {
+        let mut flash = MockFlash::new();
+        RING.write(&mut flash, b"good").unwrap();
+        // Half a header in the next slot, as if the power went mid-write
+        let next = RING.address(1) as usize;
+        flash.bytes[next..next + 8].copy_from_slice(&[0x43, 0x45, 0x52, 0x46, 1, 0, 0, 0]);
+        assert_eq!(read(&mut flash).as_deref(), Some(&b"good"[..]));
+
+        RING.write(&mut flash, b"better").unwrap();
+        assert_eq!(read(&mut flash).as_deref(), Some(&b"better"[..]));
+    }
+
+    #[test]
+    fn corrupt_record_is_ignored() {
+        let mut flash = MockFlash::new();
+        RING.write(&mut flash, b"old").unwrap();
+        RING.write(&mut flash, b"new").unwrap();
+        let newest = RING.address(1) as usize + HEADER_SIZE;
+        flash.bytes[newest] &= 0x0F;
+        assert_eq!(read(&mut flash).as_deref(), Some(&b"old"[..]));
+    }
+
+    #[test]
+    fn clear_forgets_records() {
+        let mut flash = MockFlash::new();
+        RING.write(&mut flash, b"gone").unwrap();
+        RING.clear(&mut flash).unwrap();
+        assert_eq!(read(&mut flash), None);
+        RING.write(&mut flash, b"back").unwrap();
+        assert_eq!(read(&mut flash).as_deref(), Some(&b"back"[..]));
+    }
+
+    #[test]
+    fn refuses_oversized_record() {
+        let mut flash = MockFlash::new();
+        let record = vec![0; RING.capacity() + 1];
+        assert_eq!(RING.write(&mut flash, &record), Err(Error::TooLarge));
+        assert_eq!(read(&mut flash), None);
+    }
+}
@@ -0,0 +1,520 @@
+//
+// Refresh schedule: fixed times or cron expressions, quiet hours during
+// which the frame stays asleep, and the time until the next wake.
+//
+// Entries are separated by ';' and are either "HH:MM" or a five field cron
+// expression ("minute hour day-of-month month day-of-week"), e.g.
+// "07:00;0 12-20/2 * * 1-5". Daily windows such as quiet hours are written
+// "22:30-06:45".
+//
+use alloc::vec::Vec;
+use defmt::Format;
+use embassy_time::Duration;
+
+use crate::tz::{civil_from_days, weekday_from_days, TimeZone};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+// How far ahead to look for a matching cron time before giving up
+const MAX_DAYS_AHEAD: i64 = 366 * 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct InvalidSchedule;
+
+/// A parsed cron expression, one bit per allowed value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Cron {
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    weekdays: u8,
+    // Cron matches either day field when both are restricted
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl Cron {
+    /// Parses "minute hour day-of-month month day-of-week"
+    pub fn parse(expression: &str) -> Result<Self, InvalidSchedule> {
+        let mut fields = expression.split_whitespace();
+        let mut next = || fields.next().ok_or(InvalidSchedule);
+        let (minutes, hours, days, months, weekdays) =
+            (next()?, next()?, next()?, next()?, next()?);
+        if fields.next().is_some() {
+            return Err(InvalidSchedule);
+        }
+
+        // Day of week 7 is Sunday, as is 0
+        let weekday_bits = parse_field(weekdays, 0, 7)?;
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)? as u32,
+            days: parse_field(days, 1, 31)? as u32,
+            months: parse_field(months, 1, 12)? as u16,
+            weekdays: ((weekday_bits | weekday_bits >> 7) & 0x7f) as u8,
+            days_restricted: days != "*",
+            weekdays_restricted: weekdays != "*",
+        })
+    }
+
+    /// A cron expression that fires once a day at a fixed local time, from "HH:MM"
+    pub fn daily_at(time: &str) -> Result<Self, InvalidSchedule> {
+        let seconds = parse_time_of_day(time)?;
+        Ok(Self {
+            minutes: 1 << (seconds / 60 % 60),
+            hours: 1 << (seconds / 3600),
+            days: u32::MAX,
+            months: u16::MAX,
+            weekdays: 0x7f,
+            days_restricted: false,
+            weekdays_restricted: false,
+        })
+    }
+
+    fn matches_date(&self, day: u8, month: u8, weekday: u8) -> bool {
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+        let day_matches = self.days & (1 << day) != 0;
+        let weekday_matches = self.weekdays & (1 << weekday) != 0;
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day_matches || weekday_matches,
+            (true, false) => day_matches,
+            (false, true) => weekday_matches,
+            (false, false) => true,
+        }
+    }
+
+    /// The first matching time strictly after `now` that `accept` allows, as Unix seconds
+    fn next_after(
+        &self,
+        now: i64,
+        time_zone: &TimeZone,
+        accept: impl Fn(u32) -> bool,
+    ) -> Option<i64> {
+        let local_now = time_zone.to_local(now);
+        let today = (now + local_now.utc_offset as i64).div_euclid(SECONDS_PER_DAY);
+
+        for days in today..today + MAX_DAYS_AHEAD {
+            let (_, month, day) = civil_from_days(days);
+            if !self.matches_date(day, month, weekday_from_days(days)) {
+                continue;
+            }
+            for hour in (0..24).filter(|h| self.hours & (1 << h) != 0) {
+                for minute in (0..60).filter(|m| self.minutes & (1 << m) != 0) {
+                    let seconds_of_day = hour * 3600 + minute * 60;
+                    let local = days * SECONDS_PER_DAY + seconds_of_day as i64;
+                    let unix = time_zone.unix_from_local(local);
+                    if unix > now && accept(seconds_of_day) {
+                        return Some(unix);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+// One field of a cron expression: "*", "5", "1-5", "*/15", "0-30/10" or a comma separated list
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, InvalidSchedule> {
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, parse_number(step)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (parse_number(start)?, parse_number(end)?),
+                // "5/10" means from 5 to the end in steps of 10
+                None if step > 1 => (parse_number(range)?, max),
+                None => {
+                    let value = parse_number(range)?;
+                    (value, value)
+                }
+            },
+        };
+        if step == 0 || start < min || end > max || start > end {
+            return Err(InvalidSchedule);
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+fn parse_number(s: &str) -> Result<u32, InvalidSchedule> {
+    if s.is_empty() || s.len() > 2 {
+        return Err(InvalidSchedule);
+    }
+    s.parse().map_err(|_| InvalidSchedule)
+}
+
+// "HH:MM" as seconds since midnight
+fn parse_time_of_day(time: &str) -> Result<u32, InvalidSchedule> {
+    let (hours, minutes) = time.trim().split_once(':').ok_or(InvalidSchedule)?;
+    let (hours, minutes) = (parse_number(hours)?, parse_number(minutes)?);
+    if hours > 23 || minutes > 59 {
+        return Err(InvalidSchedule);
+    }
+    Ok(hours * 3600 + minutes * 60)
+}
+
+/// A daily window in local time, e.g. quiet hours during which the frame
+/// stays asleep
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct DailyWindow {
+    /// Seconds since local midnight
+    start: u32,
+    end: u32,
+}
+
+impl DailyWindow {
+    /// Parses "HH:MM-HH:MM"; the window may wrap past midnight
+    pub fn parse(window: &str) -> Result<Self, InvalidSchedule> {
+        let (start, end) = window.split_once('-').ok_or(InvalidSchedule)?;
+        Ok(Self {
+            start: parse_time_of_day(start)?,
+            end: parse_time_of_day(end)?,
+        })
+    }
+
+    pub fn contains(&self, seconds_of_day: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&seconds_of_day)
+        } else {
+            seconds_of_day >= self.start || seconds_of_day < self.end
+        }
+    }
+
+    // Seconds from `seconds_of_day` until the window next opens, or 0 if it is open
+    fn seconds_until_start(&self, seconds_of_day: u32) -> u32 {
+        if self.contains(seconds_of_day) {
+            0
+        } else if self.start > seconds_of_day {
+            self.start - seconds_of_day
+        } else {
+            self.start + SECONDS_PER_DAY as u32 - seconds_of_day
+        }
+    }
+
+    // Seconds from `seconds_of_day` until the window next closes
+    fn seconds_until_end(&self, seconds_of_day: u32) -> u32 {
+        if self.end > seconds_of_day {
+            self.end - seconds_of_day
+        } else {
+            self.end + SECONDS_PER_DAY as u32 - seconds_of_day
+        }
+    }
+}
+
+/// When the frame should wake up and refresh
+pub struct Schedule {
+    entries: Vec<Cron>,
+    /// Time between refreshes when there are no entries, or the clock is not set
+    interval: Duration,
+    quiet_hours: Option<DailyWindow>,
+}
+
+impl Schedule {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            entries: Vec::new(),
+            interval,
+            quiet_hours: None,
+        }
+    }
+
+    /// Adds ';' separated entries, each "HH:MM" or a cron expression
+    pub fn with_entries(mut self, entries: &str) -> Result<Self, InvalidSchedule> {
+        for entry in entries.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let cron = if entry.contains(':') {
+                Cron::daily_at(entry)?
+            } else {
+                Cron::parse(entry)?
+            };
+            self.entries.push(cron);
+        }
+        Ok(self)
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    pub fn with_quiet_hours(mut self, quiet_hours: DailyWindow) -> Self {
+        self.quiet_hours = Some(quiet_hours);
+        self
+    }
+
+    fn is_quiet(&self, seconds_of_day: u32) -> bool {
+        self.quiet_hours
+            .is_some_and(|quiet| quiet.contains(seconds_of_day))
+    }
+
+    /// Unix time of the next refresh after `now`, skipping quiet hours
+    pub fn next_refresh(&self, now: i64, time_zone: &TimeZone) -> i64 {
+        if self.entries.is_empty() {
+            let next = now + self.interval.as_secs() as i64;
+            let local = time_zone.to_local(next);
+            return match self.quiet_hours {
+                Some(quiet) if quiet.contains(local.seconds_of_day()) => {
+                    let wait = quiet.seconds_until_end(local.seconds_of_day());
+                    time_zone.unix_from_local(next + local.utc_offset as i64 + wait as i64)
+                }
+                _ => next,
+            };
+        }
+
+        self.entries
+            .iter()
+            .filter_map(|cron| cron.next_after(now, time_zone, |s| !self.is_quiet(s)))
+            .min()
+            // Nothing matches in the next few years; fall back to the interval
+            .unwrap_or(now + self.interval.as_secs() as i64)
+    }
+
+    /// Whether quiet hours start at any point between `now` and `until`
+    pub fn is_quiet_between(&self, now: i64, until: i64, time_zone: &TimeZone) -> bool {
+        let Some(quiet) = self.quiet_hours else {
+            return false;
+        };
+        let local = time_zone.to_local(now);
+        (quiet.seconds_until_start(local.seconds_of_day()) as i64) < until - now
+    }
+
+    /// How long to sleep before the next refresh. Without a wall clock this
+    /// is just the refresh interval.
+    pub fn next_wake(&self, now: Option<u64>, time_zone: &TimeZone) -> Duration {
+        match now {
+            Some(now) => {
+                let now = now as i64;
+                let next = self.next_refresh(now, time_zone);
+                Duration::from_secs((next - now).max(1) as u64)
+            }
+            None => self.interval,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tz::days_from_civil;
+
+    const HOUR: u32 = 3600;
+
+    fn utc(year: i32, month: u8, day: u8, hour: i64, minute: i64) -> i64 {
+        days_from_civil(year, month, day) * SECONDS_PER_DAY + hour * 3600 + minute * 60
+    }
+
+    fn bits(values: &[u32]) -> u64 {
+        values.iter().fold(0, |bits, value| bits | 1 << value)
+    }
+
+    #[test]
+    fn cron_lists_ranges_and_steps() {
+        assert_eq!(parse_field("0,30", 0, 59), Ok(bits(&[0, 30])));
+        assert_eq!(parse_field("9-12", 0, 23), Ok(bits(&[9, 10, 11, 12])));
+        assert_eq!(parse_field("*/15", 0, 59), Ok(bits(&[0, 15, 30, 45])));
+        assert_eq!(parse_field("5/20", 0, 59), Ok(bits(&[5, 25, 45])));
+        assert_eq!(parse_field("0-30/10", 0, 59), Ok(bits(&[0, 10, 20, 30])));
+        assert_eq!(
+            parse_field("1,10-12,*/20", 0, 59),
+            Ok(bits(&[0, 1, 10, 11, 12, 20, 40]))
+        );
+        assert_eq!(
+            parse_field("*", 1, 12),
+            Ok(bits(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]))
+        );
+    }
+
+    #[test]
+    fn cron_rejects_bad_fields() {
+        for field in ["60", "5-1", "*/0", "", "1,", "a", "-5", "100"] {
+            assert_eq!(parse_field(field, 0, 59), Err(InvalidSchedule), "{}", field);
+        }
+        assert_eq!(Cron::parse("* * * *"), Err(InvalidSchedule));
+        assert_eq!(Cron::parse("* * * * * *"), Err(InvalidSchedule));
+        assert_eq!(Cron::parse("* * 0 * *"), Err(InvalidSchedule));
+        assert_eq!(Cron::parse("* * * 13 *"), Err(InvalidSchedule));
+    }
+
+    #[test]
+    fn cron_sunday_is_zero_or_seven() {
+        assert_eq!(Cron::parse("0 0 * * 7"), Cron::parse("0 0 * * 0"));
+        assert_eq!(Cron::parse("0 0 * * 5-7").unwrap().weekdays, 0b110_0001);
+    }
+
+    #[test]
+    fn cron_day_of_month_or_day_of_week() {
+        // 2025-06-13 is a Friday; June 14th a Saturday, June 20th a Friday
+        let either = Cron::parse("0 12 13 * 5").unwrap();
+        assert!(either.matches_date(13, 6, 5));
+        assert!(either.matches_date(13, 7, 0));
+        assert!(either.matches_date(20, 6, 5));
+        assert!(!either.matches_date(14, 6, 6));
+
+        let day_only = Cron::parse("0 12 13 * *").unwrap();
+        assert!(day_only.matches_date(13, 7, 0));
+        assert!(!day_only.matches_date(20, 6, 5));
+
+        let weekday_only = Cron::parse("0 12 * * 5").unwrap();
+        assert!(weekday_only.matches_date(20, 6, 5));
+        assert!(!weekday_only.matches_date(13, 7, 0));
+
+        let june_only = Cron::parse("0 12 * 6 *").unwrap();
+        assert!(june_only.matches_date(1, 6, 0));
+        assert!(!june_only.matches_date(1, 7, 2));
+    }
+
+    #[test]
+    fn cron_next_time() {
+        let schedule = Schedule::new(Duration::from_secs(3600))
+            .with_entries("0 12-20/2 * * 1-5")
+            .unwrap();
+        // Friday evening to Monday noon
+        let friday = utc(2025, 3, 28, 20, 30);
+        assert_eq!(
+            schedule.next_refresh(friday, &TimeZone::UTC),
+            utc(2025, 3, 31, 12, 0)
+        );
+        // Strictly after now
+        let monday = utc(2025, 3, 31, 14, 0);
+        assert_eq!(
+            schedule.next_refresh(monday, &TimeZone::UTC),
+            utc(2025, 3, 31, 16, 0)
+        );
+    }
+
+    #[test]
+    fn daily_times_and_cron_mix() {
+        let schedule = Schedule::new(Duration::from_secs(3600))
+            .with_entries("07:00; 0 18 * * *")
+            .unwrap();
+        let morning = utc(2025, 5, 1, 8, 0);
+        assert_eq!(
+            schedule.next_refresh(morning, &TimeZone::UTC),
+            utc(2025, 5, 1, 18, 0)
+        );
+        let evening = utc(2025, 5, 1, 19, 0);
+        assert_eq!(
+            schedule.next_refresh(evening, &TimeZone::UTC),
+            utc(2025, 5, 2, 7, 0)
+        );
+        assert!(Schedule::new(Duration::from_secs(60))
+            .with_entries("25:00")
+            .is_err());
+    }
+
+    #[test]
+    fn window_spanning_midnight() {
+        let quiet = DailyWindow::parse("22:30-06:45").unwrap();
+        assert!(quiet.contains(22 * HOUR + 30 * 60));
+        assert!(quiet.contains(23 * HOUR));
+        assert!(quiet.contains(0));
+        assert!(quiet.contains(6 * HOUR + 44 * 60));
+        assert!(!quiet.contains(6 * HOUR + 45 * 60));
+        assert!(!quiet.contains(12 * HOUR));
+
+        assert_eq!(quiet.seconds_until_end(23 * HOUR), 7 * HOUR + 45 * 60);
+        assert_eq!(quiet.seconds_until_end(HOUR), 5 * HOUR + 45 * 60);
+        assert_eq!(quiet.seconds_until_start(12 * HOUR), 10 * HOUR + 30 * 60);
+        assert_eq!(quiet.seconds_until_start(23 * HOUR), 0);
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let awake = DailyWindow::parse("08:00-20:00").unwrap();
+        assert!(awake.contains(8 * HOUR));
+        assert!(!awake.contains(20 * HOUR));
+        assert!(!awake.contains(HOUR));
+        assert_eq!(awake.seconds_until_start(21 * HOUR), 11 * HOUR);
+        assert!(DailyWindow::parse("08:00").is_err());
+        assert!(DailyWindow::parse("08:00-24:00").is_err());
+    }
+
+    #[test]
+    fn interval_skips_quiet_hours_past_midnight() {
+        let schedule = Schedule::new(Duration::from_secs(3600))
+            .with_quiet_hours(DailyWindow::parse("22:00-07:00").unwrap());
+        let evening = utc(2025, 5, 1, 21, 30);
+        assert_eq!(
+            schedule.next_refresh(evening, &TimeZone::UTC),
+            utc(2025, 5, 2, 7, 0)
+        );
+        let afternoon = utc(2025, 5, 1, 15, 0);
+        assert_eq!(
+            schedule.next_refresh(afternoon, &TimeZone::UTC),
+            utc(2025, 5, 1, 16, 0)
+        );
+    }
+
+    #[test]
+    fn cron_skips_quiet_hours_past_midnight() {
+        let schedule = Schedule::new(Duration::from_secs(3600))
+            .with_entries("0 * * * *")
+            .unwrap()
+            .with_quiet_hours(DailyWindow::parse("22:00-07:00").unwrap());
+        let evening = utc(2025, 5, 1, 21, 30);
+        assert_eq!(
+            schedule.next_refresh(evening, &TimeZone::UTC),
+            utc(2025, 5, 2, 7, 0)
+        );
+        assert!(schedule.is_quiet_between(evening, evening + 3600, &TimeZone::UTC));
+        assert!(!schedule.is_quiet_between(evening, evening + 1800, &TimeZone::UTC));
+    }
+
+    #[test]
+    fn next_wake_across_dst() {
+        let london = TimeZone::parse("GMT0BST,M3.5.0/1,M10.5.0").unwrap();
+        let schedule = Schedule::new(Duration::from_secs(3600))
+            .with_entries("07:00")
+            .unwrap();
+        let hours = |h: u64| Duration::from_secs(h * 3600);
+
+        // Saturday noon GMT to 07:00 BST, an hour shorter than usual
+        let spring = utc(2025, 3, 29, 12, 0) as u64;
+        assert_eq!(schedule.next_wake(Some(spring), &london), hours(18));
+        // Saturday 13:00 BST to 07:00 GMT, an hour longer
+        let autumn = utc(2025, 10, 25, 12, 0) as u64;
+        assert_eq!(schedule.next_wake(Some(autumn), &london), hours(19));
+        // Without the time, just the interval
+        assert_eq!(schedule.next_wake(None, &london), hours(1));
+    }
+
+    #[test]
+    fn next_wake_at_skipped_and_repeated_times() {
+        let london = TimeZone::parse("GMT0BST,M3.5.0/1,M10.5.0").unwrap();
+        let schedule = Schedule::new(Duration::from_secs(3600))
+            .with_entries("01:30")
+            .unwrap();
+        // 01:30 doesn't happen when the clocks go forward; wake at 02:30 BST instead
+        let spring = utc(2025, 3, 30, 0, 0);
+        assert_eq!(
+            schedule.next_refresh(spring, &london),
+            utc(2025, 3, 30, 1, 30)
+        );
+        // It happens twice when they go back; wake the first time only
+        let autumn = utc(2025, 10, 25, 23, 0);
+        assert_eq!(
+            schedule.next_refresh(autumn, &london),
+            utc(2025, 10, 26, 0, 30)
+        );
+        let after_first = utc(2025, 10, 26, 0, 31);
+        assert_eq!(
+            schedule.next_refresh(after_first, &london),
+            utc(2025, 10, 27, 1, 30)
+        );
+    }
+
+    #[test]
+    fn interval_across_dst_is_elapsed_time() {
+        let london = TimeZone::parse("GMT0BST,M3.5.0/1,M10.5.0").unwrap();
+        let schedule = Schedule::new(Duration::from_secs(3600));
+        let before = utc(2025, 3, 30, 0, 30);
+        assert_eq!(schedule.next_refresh(before, &london), before + 3600);
+    }
+}
@@ -0,0 +1,295 @@
+//
+// Panel command sequences expressed as data rather than hand-written
+// `send_command`/`send_data` chains. `run` executes them over anything with
+// the panel's control lines, so alternate waveforms or panel revisions only
+// need a new table, and a table can be checked against a recorded trace
+// without the hardware.
+//
+// The init sequence is picked by name from the stored settings at boot, or at
+// build time with `EPD_INIT_SEQUENCE` if none is stored.
+//
+use defmt::Format;
+
+/// A single step of a panel command sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Step {
+    /// Pulse the hardware reset line
+    Reset,
+    /// Send a command byte followed by its data bytes
+    Command(u8, &'static [u8]),
+    /// Wait for the panel to release BUSY
+    WaitBusy,
+    /// Sleep for the given number of milliseconds
+    Delay(u64),
+}
+
+/// A named list of steps that brings the panel into a known state
+#[derive(Debug, Format)]
+pub struct InitSequence {
+    pub name: &'static str,
+    pub steps: &'static [Step],
+}
+
+impl InitSequence {
+    /// Looks up one of the built-in sequences by name
+    pub fn by_name(name: &str) -> Option<&'static InitSequence> {
+        INIT_SEQUENCES.iter().copied().find(|seq| seq.name == name)
+    }
+}
+
+/// Register setup for the 7.3" ACeP panel, as shipped in the Waveshare driver
+pub static INIT_DEFAULT: InitSequence = InitSequence {
+    name: "default",
+    steps: &[
+        Step::Reset,
+        Step::WaitBusy,
+        Step::Delay(30),
+        Step::Command(0xAA, &[0x49, 0x55, 0x20, 0x08, 0x09, 0x18]), // CMDH
+        Step::Command(0x01, &[0x3F, 0x00, 0x32, 0x2A, 0x0E, 0x2A]),
+        Step::Command(0x00, &[0x5F, 0x69]),
+        Step::Command(0x03, &[0x00, 0x54, 0x00, 0x44]),
+        Step::Command(0x05, &[0x40, 0x1F, 0x1F, 0x2C]),
+        Step::Command(0x06, &[0x6F, 0x1F, 0x1F, 0x22]),
+        Step::Command(0x08, &[0x6F, 0x1F, 0x1F, 0x22]),
+        Step::Command(0x13, &[0x00, 0x04]), // IPC
+        Step::Command(0x30, &[0x3C]),
+        Step::Command(0x41, &[0x00]), // TSE
+        Step::Command(0x50, &[0x3F]),
+        Step::Command(0x60, &[0x02, 0x00]),
+        Step::Command(0x61, &[0x03, 0x20, 0x01, 0xE0]), // 800 x 480
+        Step::Command(0x82, &[0x1E]),
+        Step::Command(0x84, &[0x00]),
+        Step::Command(0x86, &[0x00]), // AGID
+        Step::Command(0xE3, &[0x2F]),
+        Step::Command(0xE0, &[0x00]), // CCSET
+        Step::Command(0xE6, &[0x00]), // TSSET
+    ],
+};
+
+/// The same panel as sold by Good Display (GDEY073D46), with its own booster
+/// and frame rate settings
+pub static INIT_GDEY073D46: InitSequence = InitSequence {
+    name: "gdey073d46",
+    steps: &[
+        Step::Reset,
+        Step::WaitBusy,
+        Step::Delay(30),
+        Step::Command(0xAA, &[0x49, 0x55, 0x20, 0x08, 0x09, 0x18]), // CMDH
+        Step::Command(0x01, &[0x3F, 0x00, 0x32, 0x2A, 0x0E, 0x2A]),
+        Step::Command(0x00, &[0x5F, 0x69]),
+        Step::Command(0x03, &[0x00, 0x54, 0x00, 0x44]),
+        Step::Command(0x05, &[0x40, 0x1F, 0x1F, 0x2C]),
+        Step::Command(0x06, &[0x6F, 0x1F, 0x16, 0x25]),
+        Step::Command(0x08, &[0x6F, 0x1F, 0x1F, 0x22]),
+        Step::Command(0x13, &[0x00, 0x04]), // IPC
+        Step::Command(0x30, &[0x02]),
+        Step::Command(0x41, &[0x00]), // TSE
+        Step::Command(0x50, &[0x3F]),
+        Step::Command(0x60, &[0x02, 0x00]),
+        Step::Command(0x61, &[0x03, 0x20, 0x01, 0xE0]), // 800 x 480
+        Step::Command(0x82, &[0x1E]),
+        Step::Command(0x84, &[0x01]),
+        Step::Command(0x86, &[0x00]), // AGID
+        Step::Command(0xE3, &[0x2F]),
+        Step::Command(0xE0, &[0x00]), // CCSET
+        Step::Command(0xE6, &[0x00]), // TSSET
+    ],
+};
+
+/// Register setup for the later 7.3" Spectra 6 panel (Waveshare 7.3" E), which
+/// takes the same commands and resolution
+pub static INIT_7IN3E: InitSequence = InitSequence {
+    name: "7in3e",
+    steps: &[
+        Step::Reset,
+        Step::WaitBusy,
+        Step::Delay(30),
+        Step::Command(0xAA, &[0x49, 0x55, 0x20, 0x08, 0x09, 0x18]), // CMDH
+        Step::Command(0x01, &[0x3F]),
+        Step::Command(0x00, &[0x5F, 0x69]),
+        Step::Command(0x03, &[0x00, 0x54, 0x00, 0x44]),
+        Step::Command(0x05, &[0x40, 0x1F, 0x1F, 0x2C]),
+        Step::Command(0x06, &[0x6F, 0x1F, 0x17, 0x49]),
+        Step::Command(0x08, &[0x6F, 0x1F, 0x1F, 0x22]),
+        Step::Command(0x30, &[0x03]),
+        Step::Command(0x50, &[0x3F]),
+        Step::Command(0x60, &[0x02, 0x00]),
+        Step::Command(0x61, &[0x03, 0x20, 0x01, 0xE0]), // 800 x 480
+        Step::Command(0x84, &[0x01]),
+        Step::Command(0xE3, &[0x2F]),
+    ],
+};
+
+/// All init sequences that can be picked by name
+pub static INIT_SEQUENCES: &[&InitSequence] = &[&INIT_DEFAULT, &INIT_GDEY073D46, &INIT_7IN3E];
+
+/// Puts the panel into deep sleep; only a hardware reset wakes it again
+pub static DEEP_SLEEP: &[Step] = &[
+    Step::Command(0x07, &[0xA5]), // DEEP_SLEEP
+    Step::Delay(2000),
+];
+
+/// The panel's control lines, as a sequence drives them
+// Only ever driven from the single-threaded executor, so no Send bounds are needed
+#[allow(async_fn_in_trait)]
+pub trait Bus {
+    type Error;
+
+    /// Pulses the hardware reset line
+    async fn reset(&mut self);
+    /// Sets DC: low before a command byte, high before data
+    fn set_dc(&mut self, data: bool);
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error>;
+    /// Waits for the panel to release BUSY
+    async fn wait_busy(&mut self);
+    async fn delay(&mut self, ms: u64);
+}
+
+/// Executes a table of panel steps in order
+pub async fn run<B: Bus>(bus: &mut B, steps: &[Step]) -> Result<(), B::Error> {
+    for step in steps {
+        match *step {
+            Step::Reset => bus.reset().await,
+            Step::Command(command, data) => {
+                bus.set_dc(false);
+                bus.write(&[command]).await?;
+                // A byte per transfer, as the panel has always been driven
+                bus.set_dc(true);
+                for &byte in data {
+                    bus.write(&[byte]).await?;
+                }
+            }
+            Step::WaitBusy => bus.wait_busy().await,
+            Step::Delay(ms) => bus.delay(ms).await,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use core::convert::Infallible;
+    use embassy_futures::block_on;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Event {
+        Reset,
+        WaitBusy,
+        Delay(u64),
+        Command(u8),
+        Data(u8),
+    }
+    use Event::*;
+
+    // Stands in for the SPI bus, DC and BUSY, noting what crosses them
+    #[derive(Default)]
+    struct Recorder {
+        dc: bool,
+        trace: Vec<Event>,
+    }
+
+    impl Bus for Recorder {
+        type Error = Infallible;
+
+        async fn reset(&mut self) {
+            self.trace.push(Reset);
+        }
+
+        fn set_dc(&mut self, data: bool) {
+            self.dc = data;
+        }
+
+        async fn write(&mut self, bytes: &[u8]) -> Result<(), Infallible> {
+            for &byte in bytes {
+                self.trace
+                    .push(if self.dc { Data(byte) } else { Command(byte) });
+            }
+            Ok(())
+        }
+
+        async fn wait_busy(&mut self) {
+            self.trace.push(WaitBusy);
+        }
+
+        async fn delay(&mut self, ms: u64) {
+            self.trace.push(Delay(ms));
+        }
+    }
+
+    fn trace(steps: &[Step]) -> Vec<Event> {
+        let mut recorder = Recorder::default();
+        block_on(run(&mut recorder, steps)).unwrap();
+        recorder.trace
+    }
+
+    // What the hand-written `init` sent before it became a table
+    #[rustfmt::skip]
+    const RECORDED_INIT: &[Event] = &[
+        Reset,
+        WaitBusy,
+        Delay(30),
+        Command(0xAA), Data(0x49), Data(0x55), Data(0x20), Data(0x08), Data(0x09), Data(0x18),
+        Command(0x01), Data(0x3F), Data(0x00), Data(0x32), Data(0x2A), Data(0x0E), Data(0x2A),
+        Command(0x00), Data(0x5F), Data(0x69),
+        Command(0x03), Data(0x00), Data(0x54), Data(0x00), Data(0x44),
+        Command(0x05), Data(0x40), Data(0x1F), Data(0x1F), Data(0x2C),
+        Command(0x06), Data(0x6F), Data(0x1F), Data(0x1F), Data(0x22),
+        Command(0x08), Data(0x6F), Data(0x1F), Data(0x1F), Data(0x22),
+        Command(0x13), Data(0x00), Data(0x04),
+        Command(0x30), Data(0x3C),
+        Command(0x41), Data(0x00),
+        Command(0x50), Data(0x3F),
+        Command(0x60), Data(0x02), Data(0x00),
+        Command(0x61), Data(0x03), Data(0x20), Data(0x01), Data(0xE0),
+        Command(0x82), Data(0x1E),
+        Command(0x84), Data(0x00),
+        Command(0x86), Data(0x00),
+        Command(0xE3), Data(0x2F),
+        Command(0xE0), Data(0x00),
+        Command(0xE6), Data(0x00),
+    ];
+
+    #[test]
+    fn default_init_matches_recorded_trace() {
+        assert_eq!(trace(INIT_DEFAULT.steps), RECORDED_INIT);
+    }
+
+    #[test]
+    fn deep_sleep_sends_check_code() {
+        assert_eq!(trace(DEEP_SLEEP), [Command(0x07), Data(0xA5), Delay(2000)]);
+    }
+
+    #[test]
+    fn sequences_found_by_name() {
+        assert_eq!(InitSequence::by_name("default").unwrap().name, "default");
+        assert_eq!(InitSequence::by_name("7in3e").unwrap().name, "7in3e");
+        assert!(InitSequence::by_name("missing").is_none());
+    }
+
+    #[test]
+    fn every_sequence_resets_first_and_sets_resolution() {
+        for sequence in INIT_SEQUENCES {
+            let trace = trace(sequence.steps);
+            assert_eq!(
+                trace[..3],
+                [Reset, WaitBusy, Delay(30)],
+                "{}",
+                sequence.name
+            );
+            let resolution = [
+                Command(0x61),
+                Data(0x03),
+                Data(0x20),
+                Data(0x01),
+                Data(0xE0),
+            ];
+            assert!(
+                trace.windows(5).any(|w| w == resolution),
+                "{}",
+                sequence.name
+            );
+        }
+    }
+}
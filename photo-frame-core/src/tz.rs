@@ -0,0 +1,596 @@
+//
+// Calendar maths and POSIX TZ strings, e.g. "GMT0BST,M3.5.0/1,M10.5.0" or
+// "AEST-10AEDT,M10.1.0,M4.1.0/3". Everything here is plain arithmetic on
+// Unix seconds so it can be checked on the host.
+//
+use core::fmt;
+use defmt::Format;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+const SECONDS_PER_HOUR: i32 = 60 * 60;
+// Transitions happen at 02:00 local time unless the rule says otherwise
+const DEFAULT_TRANSITION_TIME: i32 = 2 * SECONDS_PER_HOUR;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct InvalidTimeZone;
+
+/// Days since 1970-01-01 for a proleptic Gregorian date
+pub fn days_from_civil(year: i32, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year } as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Gregorian (year, month, day) for a number of days since 1970-01-01
+pub fn civil_from_days(days: i64) -> (i32, u8, u8) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = (year_of_era + era * 400 + if month <= 2 { 1 } else { 0 }) as i32;
+    (year, month, day)
+}
+
+/// Day of the week for days since 1970-01-01, with Sunday as 0
+pub fn weekday_from_days(days: i64) -> u8 {
+    // 1970-01-01 was a Thursday
+    (days + 4).rem_euclid(7) as u8
+}
+
+pub fn is_leap_year(year: i32) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+pub fn days_in_month(year: i32, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// A broken-down local time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct DateTime {
+    pub year: i32,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    /// Day of the week, with Sunday as 0
+    pub weekday: u8,
+    /// Seconds east of UTC that were applied to get this local time
+    pub utc_offset: i32,
+}
+
+impl DateTime {
+    /// Breaks down Unix seconds shifted by `utc_offset` seconds
+    pub fn from_unix(unix: i64, utc_offset: i32) -> Self {
+        let local = unix + utc_offset as i64;
+        let days = local.div_euclid(SECONDS_PER_DAY);
+        let seconds = local.rem_euclid(SECONDS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+        Self {
+            year,
+            month,
+            day,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+            weekday: weekday_from_days(days),
+            utc_offset,
+        }
+    }
+
+    /// Unix seconds for this local time
+    pub fn to_unix(self) -> i64 {
+        days_from_civil(self.year, self.month, self.day) * SECONDS_PER_DAY
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64
+            - self.utc_offset as i64
+    }
+
+    /// Seconds since local midnight
+    pub fn seconds_of_day(&self) -> u32 {
+        self.hour as u32 * 3600 + self.minute as u32 * 60 + self.second as u32
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// The day a DST transition happens on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+enum Rule {
+    /// `Jn`: day 1-365, never counting February 29th
+    Julian(u16),
+    /// `n`: zero based day of the year, counting February 29th
+    DayOfYear(u16),
+    /// `Mm.w.d`: weekday `d` (0 = Sunday) of week `w` (5 = last) of month `m`
+    MonthWeekDay { month: u8, week: u8, weekday: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+struct Transition {
+    rule: Rule,
+    /// Local time of day the transition happens at, in seconds
+    time: i32,
+}
+
+impl Transition {
+    // Local seconds since the epoch at which the transition happens in `year`
+    fn local_seconds(&self, year: i32) -> i64 {
+        let days = match self.rule {
+            Rule::Julian(n) => {
+                let skip_leap_day = is_leap_year(year) && n >= 60;
+                days_from_civil(year, 1, 1) + n as i64 - 1 + skip_leap_day as i64
+            }
+            Rule::DayOfYear(n) => days_from_civil(year, 1, 1) + n as i64,
+            Rule::MonthWeekDay {
+                month,
+                week,
+                weekday,
+            } => {
+                let first = days_from_civil(year, month, 1);
+                let first_weekday = weekday_from_days(first);
+                let mut day = 1 + (weekday + 7 - first_weekday) % 7 + (week - 1) * 7;
+                while day > days_in_month(year, month) {
+                    day -= 7;
+                }
+                first + day as i64 - 1
+            }
+        };
+        days * SECONDS_PER_DAY + self.time as i64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+struct DaylightSaving {
+    /// Seconds east of UTC while DST is in effect
+    offset: i32,
+    start: Transition,
+    end: Transition,
+}
+
+/// A timezone with optional daylight saving rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct TimeZone {
+    /// Seconds east of UTC outside of DST
+    offset: i32,
+    dst: Option<DaylightSaving>,
+}
+
+impl TimeZone {
+    pub const UTC: TimeZone = TimeZone {
+        offset: 0,
+        dst: None,
+    };
+
+    /// Parses a POSIX TZ string such as "CET-1CEST,M3.5.0,M10.5.0/3"
+    pub fn parse(tz: &str) -> Result<Self, InvalidTimeZone> {
+        let mut parser = Parser(tz.as_bytes());
+        parser.name()?;
+        // POSIX offsets are the time to add to local time to get UTC
+        let offset = -parser.offset()?;
+        if parser.is_empty() {
+            return Ok(Self { offset, dst: None });
+        }
+
+        parser.name()?;
+        let dst_offset = if parser.peek().is_some_and(|c| c != b',') {
+            -parser.offset()?
+        } else {
+            offset + SECONDS_PER_HOUR
+        };
+        let (start, end) = if parser.is_empty() {
+            // No rules given: fall back to the current US rules, as glibc does
+            (
+                Transition {
+                    rule: Rule::MonthWeekDay {
+                        month: 3,
+                        week: 2,
+                        weekday: 0,
+                    },
+                    time: DEFAULT_TRANSITION_TIME,
+                },
+                Transition {
+                    rule: Rule::MonthWeekDay {
+                        month: 11,
+                        week: 1,
+                        weekday: 0,
+                    },
+                    time: DEFAULT_TRANSITION_TIME,
+                },
+            )
+        } else {
+            parser.expect(b',')?;
+            let start = parser.transition()?;
+            parser.expect(b',')?;
+            let end = parser.transition()?;
+            (start, end)
+        };
+        if !parser.is_empty() {
+            return Err(InvalidTimeZone);
+        }
+
+        Ok(Self {
+            offset,
+            dst: Some(DaylightSaving {
+                offset: dst_offset,
+                start,
+                end,
+            }),
+        })
+    }
+
+    /// Whether daylight saving is in effect at the given Unix time
+    pub fn is_dst(&self, unix: i64) -> bool {
+        let Some(dst) = self.dst else {
+            return false;
+        };
+        let year = DateTime::from_unix(unix, self.offset).year;
+        // Transition times are given in the local time in effect before they happen
+        let start = dst.start.local_seconds(year) - self.offset as i64;
+        let end = dst.end.local_seconds(year) - dst.offset as i64;
+        if start < end {
+            (start..end).contains(&unix)
+        } else {
+            // Southern hemisphere: DST spans the new year
+            unix < end || unix >= start
+        }
+    }
+
+    /// Seconds east of UTC at the given Unix time
+    pub fn offset_at(&self, unix: i64) -> i32 {
+        match self.dst {
+            Some(dst) if self.is_dst(unix) => dst.offset,
+            _ => self.offset,
+        }
+    }
+
+    pub fn to_local(self, unix: i64) -> DateTime {
+        DateTime::from_unix(unix, self.offset_at(unix))
+    }
+
+    /// Unix time for local wall-clock seconds since the epoch. Times skipped or
+    /// repeated by a DST transition resolve using the offset in effect just before it.
+    pub fn unix_from_local(self, local: i64) -> i64 {
+        let standard = local - self.offset as i64;
+        let Some(dst) = self.dst else {
+            return standard;
+        };
+        let daylight = local - dst.offset as i64;
+        match (
+            self.offset_at(standard) == self.offset,
+            self.offset_at(daylight) == dst.offset,
+        ) {
+            (true, false) => standard,
+            (false, true) => daylight,
+            // Repeated as the clocks go back: the first time round
+            (true, true) => standard.min(daylight),
+            // Skipped as the clocks go forward: read with the smaller offset from before
+            (false, false) => standard.max(daylight),
+        }
+    }
+}
+
+struct Parser<'a>(&'a [u8]);
+
+impl Parser<'_> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.0.first().copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let (&c, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(c)
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), InvalidTimeZone> {
+        match self.next() {
+            Some(next) if next == c => Ok(()),
+            _ => Err(InvalidTimeZone),
+        }
+    }
+
+    // A zone abbreviation, either alphabetic or quoted in angle brackets like <+0530>
+    fn name(&mut self) -> Result<(), InvalidTimeZone> {
+        let len = if self.peek() == Some(b'<') {
+            self.next();
+            let len = self
+                .0
+                .iter()
+                .position(|&c| c == b'>')
+                .ok_or(InvalidTimeZone)?;
+            self.0 = &self.0[len + 1..];
+            len
+        } else {
+            let len = self
+                .0
+                .iter()
+                .take_while(|c| c.is_ascii_alphabetic())
+                .count();
+            self.0 = &self.0[len..];
+            len
+        };
+        if len < 3 {
+            return Err(InvalidTimeZone);
+        }
+        Ok(())
+    }
+
+    fn number(&mut self, max_digits: usize) -> Result<i32, InvalidTimeZone> {
+        let digits = self
+            .0
+            .iter()
+            .take(max_digits)
+            .take_while(|c| c.is_ascii_digit())
+            .count();
+        if digits == 0 {
+            return Err(InvalidTimeZone);
+        }
+        let value = self.0[..digits]
+            .iter()
+            .fold(0, |acc, &c| acc * 10 + (c - b'0') as i32);
+        self.0 = &self.0[digits..];
+        Ok(value)
+    }
+
+    // [+-]hh[:mm[:ss]], in seconds
+    fn offset(&mut self) -> Result<i32, InvalidTimeZone> {
+        let sign = match self.peek() {
+            Some(b'-') => {
+                self.next();
+                -1
+            }
+            Some(b'+') => {
+                self.next();
+                1
+            }
+            _ => 1,
+        };
+        let mut seconds = self.number(3)? * SECONDS_PER_HOUR;
+        if self.peek() == Some(b':') {
+            self.next();
+            seconds += self.number(2)? * 60;
+            if self.peek() == Some(b':') {
+                self.next();
+                seconds += self.number(2)?;
+            }
+        }
+        Ok(sign * seconds)
+    }
+
+    fn transition(&mut self) -> Result<Transition, InvalidTimeZone> {
+        let rule = match self.peek() {
+            Some(b'J') => {
+                self.next();
+                match self.number(3)? {
+                    n @ 1..=365 => Rule::Julian(n as u16),
+                    _ => return Err(InvalidTimeZone),
+                }
+            }
+            Some(b'M') => {
+                self.next();
+                let month = self.number(2)?;
+                self.expect(b'.')?;
+                let week = self.number(1)?;
+                self.expect(b'.')?;
+                let weekday = self.number(1)?;
+                if !(1..=12).contains(&month) || !(1..=5).contains(&week) || weekday > 6 {
+                    return Err(InvalidTimeZone);
+                }
+                Rule::MonthWeekDay {
+                    month: month as u8,
+                    week: week as u8,
+                    weekday: weekday as u8,
+                }
+            }
+            _ => match self.number(3)? {
+                n @ 0..=365 => Rule::DayOfYear(n as u16),
+                _ => return Err(InvalidTimeZone),
+            },
+        };
+        let time = if self.peek() == Some(b'/') {
+            self.next();
+            self.offset()?
+        } else {
+            DEFAULT_TRANSITION_TIME
+        };
+        Ok(Transition { rule, time })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONDON: &str = "GMT0BST,M3.5.0/1,M10.5.0";
+    const SYDNEY: &str = "AEST-10AEDT,M10.1.0,M4.1.0/3";
+
+    fn utc(year: i32, month: u8, day: u8, hour: i64, minute: i64) -> i64 {
+        days_from_civil(year, month, day) * SECONDS_PER_DAY + hour * 3600 + minute * 60
+    }
+
+    #[test]
+    fn civil_days_round_trip() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        for days in [-800_000, -1, 0, 11016, 11017, 19_782, 800_000] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+    }
+
+    #[test]
+    fn weekdays_and_month_lengths() {
+        // Thursday, then Sunday 2025-03-30
+        assert_eq!(weekday_from_days(0), 4);
+        assert_eq!(weekday_from_days(days_from_civil(2025, 3, 30)), 0);
+        assert_eq!(days_in_month(2024, 2), 29);
+        assert_eq!(days_in_month(2025, 2), 28);
+        assert_eq!(days_in_month(1900, 2), 28);
+        assert_eq!(days_in_month(2000, 2), 29);
+        assert_eq!(days_in_month(2025, 4), 30);
+        assert_eq!(days_in_month(2025, 12), 31);
+    }
+
+    #[test]
+    fn parses_fixed_offsets() {
+        let zone = TimeZone::parse("UTC0").unwrap();
+        assert_eq!(zone, TimeZone::UTC);
+        let zone = TimeZone::parse("<+0530>-5:30").unwrap();
+        assert_eq!(zone.offset_at(0), 5 * 3600 + 30 * 60);
+        let zone = TimeZone::parse("EST+5").unwrap();
+        assert_eq!(zone.offset_at(0), -5 * 3600);
+    }
+
+    #[test]
+    fn rejects_malformed_zones() {
+        for tz in [
+            "",
+            "AB0",
+            "GMT",
+            "GMT0BST,M3.5.0",
+            "GMT0BST,M13.1.0,M10.5.0",
+            "GMT0BST,M3.6.0,M10.5.0",
+            "GMT0BST,M3.5.7,M10.5.0",
+            "GMT0BST,J0,J100",
+            "GMT0BST,M3.5.0,M10.5.0x",
+            "<+05-5",
+        ] {
+            assert_eq!(TimeZone::parse(tz), Err(InvalidTimeZone), "{}", tz);
+        }
+    }
+
+    #[test]
+    fn month_week_day_rules() {
+        let last_sunday_of_march = Transition {
+            rule: Rule::MonthWeekDay {
+                month: 3,
+                week: 5,
+                weekday: 0,
+            },
+            time: 0,
+        };
+        assert_eq!(
+            last_sunday_of_march.local_seconds(2025),
+            utc(2025, 3, 30, 0, 0)
+        );
+        assert_eq!(
+            last_sunday_of_march.local_seconds(2024),
+            utc(2024, 3, 31, 0, 0)
+        );
+    }
+
+    #[test]
+    fn day_of_year_rules() {
+        let julian = |n| Transition {
+            rule: Rule::Julian(n),
+            time: 0,
+        };
+        let zero_based = |n| Transition {
+            rule: Rule::DayOfYear(n),
+            time: 0,
+        };
+        // J60 is always March 1st; plain 59 is February 29th in leap years
+        assert_eq!(julian(60).local_seconds(2024), utc(2024, 3, 1, 0, 0));
+        assert_eq!(julian(60).local_seconds(2025), utc(2025, 3, 1, 0, 0));
+        assert_eq!(zero_based(59).local_seconds(2024), utc(2024, 2, 29, 0, 0));
+        assert_eq!(zero_based(59).local_seconds(2025), utc(2025, 3, 1, 0, 0));
+    }
+
+    #[test]
+    fn london_transitions() {
+        let zone = TimeZone::parse(LONDON).unwrap();
+        // Clocks go forward at 01:00 GMT and back at 02:00 BST
+        assert!(!zone.is_dst(utc(2025, 3, 30, 1, 0) - 1));
+        assert!(zone.is_dst(utc(2025, 3, 30, 1, 0)));
+        assert!(zone.is_dst(utc(2025, 10, 26, 1, 0) - 1));
+        assert!(!zone.is_dst(utc(2025, 10, 26, 1, 0)));
+
+        let summer = zone.to_local(utc(2025, 7, 1, 12, 0));
+        assert_eq!(summer.hour, 13);
+        assert_eq!(summer.utc_offset, 3600);
+        assert_eq!(summer.to_unix(), utc(2025, 7, 1, 12, 0));
+    }
+
+    #[test]
+    fn southern_hemisphere_spans_new_year() {
+        let zone = TimeZone::parse(SYDNEY).unwrap();
+        assert_eq!(zone.offset_at(utc(2025, 1, 15, 0, 0)), 11 * 3600);
+        assert_eq!(zone.offset_at(utc(2025, 7, 15, 0, 0)), 10 * 3600);
+        // Back at 03:00 AEDT on April 6th, forward at 02:00 AEST on October 5th
+        assert!(zone.is_dst(utc(2025, 4, 5, 16, 0) - 1));
+        assert!(!zone.is_dst(utc(2025, 4, 5, 16, 0)));
+        assert!(!zone.is_dst(utc(2025, 10, 4, 16, 0) - 1));
+        assert!(zone.is_dst(utc(2025, 10, 4, 16, 0)));
+    }
+
+    #[test]
+    fn us_rules_by_default() {
+        let zone = TimeZone::parse("EST5EDT").unwrap();
+        // Second Sunday in March to the first Sunday in November, at 02:00 local
+        assert!(!zone.is_dst(utc(2025, 3, 9, 7, 0) - 1));
+        assert!(zone.is_dst(utc(2025, 3, 9, 7, 0)));
+        assert!(zone.is_dst(utc(2025, 11, 2, 6, 0) - 1));
+        assert!(!zone.is_dst(utc(2025, 11, 2, 6, 0)));
+    }
+
+    #[test]
+    fn local_times_round_trip() {
+        let zone = TimeZone::parse(LONDON).unwrap();
+        for unix in [utc(2025, 1, 1, 0, 0), utc(2025, 7, 1, 12, 0)] {
+            let local = unix + zone.offset_at(unix) as i64;
+            assert_eq!(zone.unix_from_local(local), unix);
+        }
+    }
+
+    #[test]
+    fn skipped_and_repeated_local_times() {
+        let zone = TimeZone::parse(LONDON).unwrap();
+        // 01:30 doesn't happen on March 30th, so it's read as GMT, i.e. 02:30 BST
+        assert_eq!(
+            zone.unix_from_local(utc(2025, 3, 30, 1, 30)),
+            utc(2025, 3, 30, 1, 30)
+        );
+        // 01:30 happens twice on October 26th; the first is still BST
+        assert_eq!(
+            zone.unix_from_local(utc(2025, 10, 26, 1, 30)),
+            utc(2025, 10, 26, 0, 30)
+        );
+    }
+
+    #[test]
+    fn displays_local_time() {
+        let time = DateTime::from_unix(utc(2025, 3, 9, 7, 5) + 9, -5 * 3600);
+        assert_eq!(alloc::format!("{}", time), "2025-03-09 02:05:09");
+    }
+}
@@ -0,0 +1,119 @@
+//
+// ADC1, shared by the battery monitor and a light-dependent resistor. Pins
+// are enabled by number before the converter starts, each at most once, then
+// each is read through its own `Sampler`.
+//
+use alloc::boxed::Box;
+use core::cell::RefCell;
+use critical_section::Mutex;
+use esp_hal::{
+    analog::adc::{Adc, AdcCalCurve, AdcChannel, AdcConfig, AdcPin, Attenuation},
+    gpio::AnalogPin,
+    peripherals::{ADC1, GPIO1, GPIO2, GPIO3, GPIO4, GPIO5, GPIO6, GPIO7, GPIO8},
+    Blocking,
+};
+
+// Polls of the ADC before giving up on a conversion
+const MAX_POLLS: u32 = 10_000;
+
+static ADC: Mutex<RefCell<Option<Adc<'static, ADC1<'static>, Blocking>>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Millivolts at one pin, whichever pin it is
+pub trait Sampler {
+    fn sample(&mut self) -> Option<u16>;
+}
+
+struct AdcSampler<PIN> {
+    pin: AdcPin<PIN, ADC1<'static>, AdcCalCurve<ADC1<'static>>>,
+}
+
+impl<PIN: AdcChannel> Sampler for AdcSampler<PIN> {
+    fn sample(&mut self) -> Option<u16> {
+        for _ in 0..MAX_POLLS {
+            // One poll at a time, so interrupts aren't held off until the
+            // conversion is done
+            let reading = critical_section::with(|cs| {
+                let mut adc = ADC.borrow_ref_mut(cs);
+                adc.as_mut().map(|adc| adc.read_oneshot(&mut self.pin))
+            })?;
+            if let Ok(millivolts) = reading {
+                return Some(millivolts);
+            }
+        }
+        None
+    }
+}
+
+/// ADC1's pins
+pub struct Pins {
+    pub gpio1: GPIO1<'static>,
+    pub gpio2: GPIO2<'static>,
+    pub gpio3: GPIO3<'static>,
+    pub gpio4: GPIO4<'static>,
+    pub gpio5: GPIO5<'static>,
+    pub gpio6: GPIO6<'static>,
+    pub gpio7: GPIO7<'static>,
+    pub gpio8: GPIO8<'static>,
+}
+
+/// The pins to read, gathered before the converter starts
+pub struct Inputs {
+    config: AdcConfig<ADC1<'static>>,
+    gpio1: Option<GPIO1<'static>>,
+    gpio2: Option<GPIO2<'static>>,
+    gpio3: Option<GPIO3<'static>>,
+    gpio4: Option<GPIO4<'static>>,
+    gpio5: Option<GPIO5<'static>>,
+    gpio6: Option<GPIO6<'static>>,
+    gpio7: Option<GPIO7<'static>>,
+    gpio8: Option<GPIO8<'static>>,
+}
+
+impl Inputs {
+    pub fn new(pins: Pins) -> Self {
+        Inputs {
+            config: AdcConfig::new(),
+            gpio1: Some(pins.gpio1),
+            gpio2: Some(pins.gpio2),
+            gpio3: Some(pins.gpio3),
+            gpio4: Some(pins.gpio4),
+            gpio5: Some(pins.gpio5),
+            gpio6: Some(pins.gpio6),
+            gpio7: Some(pins.gpio7),
+            gpio8: Some(pins.gpio8),
+        }
+    }
+
+    /// Readies GPIO `pin` for reading once the converter starts, unless it
+    /// isn't one of ADC1's or has already been enabled
+    pub fn enable(&mut self, pin: u8) -> Option<Box<dyn Sampler>> {
+        match pin {
+            1 => self.gpio1.take().map(|pin| self.sampler(pin)),
+            2 => self.gpio2.take().map(|pin| self.sampler(pin)),
+            3 => self.gpio3.take().map(|pin| self.sampler(pin)),
+            4 => self.gpio4.take().map(|pin| self.sampler(pin)),
+            5 => self.gpio5.take().map(|pin| self.sampler(pin)),
+            6 => self.gpio6.take().map(|pin| self.sampler(pin)),
+            7 => self.gpio7.take().map(|pin| self.sampler(pin)),
+            8 => self.gpio8.take().map(|pin| self.sampler(pin)),
+            _ => None,
+        }
+    }
+
+    fn sampler<PIN>(&mut self, pin: PIN) -> Box<dyn Sampler>
+    where
+        PIN: AdcChannel + AnalogPin + 'static,
+    {
+        let pin = self
+            .config
+            .enable_pin_with_cal::<_, AdcCalCurve<ADC1<'static>>>(pin, Attenuation::_11dB);
+        Box::new(AdcSampler { pin })
+    }
+
+    /// Starts the converter; samplers read nothing until then
+    pub fn start(self, adc: ADC1<'static>) {
+        let adc = Adc::new(adc, self.config);
+        critical_section::with(|cs| ADC.borrow(cs).replace(Some(adc)));
+    }
+}
@@ -0,0 +1,90 @@
+//
+// Battery monitoring through ADC1, for frames running from a LiPo cell. The
+// cell is wired to the pin through a resistor divider to stay within the
+// ADC's range.
+//
+use alloc::boxed::Box;
+use defmt::Format;
+
+use crate::analog::Sampler;
+
+// Samples averaged per reading, as the ADC is noisy
+const SAMPLES: u32 = 16;
+
+// Resting voltage of a typical LiPo cell against charge left, highest first
+const DISCHARGE_CURVE: [(u16, u8); 11] = [
+    (4200, 100),
+    (4110, 90),
+    (4020, 80),
+    (3950, 70),
+    (3870, 60),
+    (3840, 50),
+    (3800, 40),
+    (3770, 30),
+    (3730, 20),
+    (3690, 10),
+    (3300, 0),
+];
+
+// Marks a cell being left to rest, in memory that survives deep sleep
+const RESTING_MAGIC: u32 = 0x464c_4154;
+
+#[esp_hal::ram(rtc_fast, persistent)]
+static mut RESTING: u32 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Reading {
+    pub millivolts: u16,
+    pub percent: u8,
+}
+
+/// Charge left in a LiPo cell at rest, interpolated along its discharge curve
+pub fn percent(millivolts: u16) -> u8 {
+    let (full, _) = DISCHARGE_CURVE[0];
+    if millivolts >= full {
+        return 100;
+    }
+    for pair in DISCHARGE_CURVE.windows(2) {
+        let [(high_mv, high_pc), (low_mv, low_pc)] = [pair[0], pair[1]];
+        if millivolts >= low_mv {
+            let span = (high_pc - low_pc) as u32 * (millivolts - low_mv) as u32;
+            return low_pc + (span / (high_mv - low_mv) as u32) as u8;
+        }
+    }
+    0
+}
+
+/// Whether the frame went to sleep to let a flat cell rest
+pub fn is_resting() -> bool {
+    unsafe { RESTING == RESTING_MAGIC }
+}
+
+pub fn set_resting(resting: bool) {
+    unsafe {
+        RESTING = if resting { RESTING_MAGIC } else { 0 };
+    }
+}
+
+pub struct Battery {
+    sampler: Box<dyn Sampler>,
+    divider: f32,
+}
+
+impl Battery {
+    /// `divider` is the battery voltage over the voltage at the sampled pin
+    pub fn new(sampler: Box<dyn Sampler>, divider: f32) -> Self {
+        Self { sampler, divider }
+    }
+
+    pub fn read(&mut self) -> Option<Reading> {
+        let mut total = 0;
+        for _ in 0..SAMPLES {
+            total += self.sampler.sample()? as u32;
+        }
+        let millivolts = ((total / SAMPLES) as f32 * self.divider) as u16;
+        Some(Reading {
+            millivolts,
+            percent: percent(millivolts),
+        })
+    }
+}
@@ -0,0 +1,168 @@
+//
+// Push buttons: the BOOT button and optionally one more on a spare RTC GPIO,
+// both active low. Presses are debounced and told apart by how long the
+// button is held and whether a second press follows quickly:
+//
+//   short             refresh now
+//   double            show the next cached image
+//   long (3 s)        start the setup access point
+//   very long (10 s)  factory reset
+//
+// While the frame is in deep sleep the buttons wake it through EXT1, which
+// leaves EXT0 for a PIR sensor.
+//
+use alloc::vec::Vec;
+use defmt::{info, Format};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use esp_hal::{
+    gpio::{AnyPin, Input, RtcPin, RtcPinWithResistors},
+    rtc_cntl::{
+        sleep::{Ext0WakeupSource, Ext1WakeupSource, TimerWakeupSource, WakeSource, WakeupLevel},
+        wakeup_cause,
+    },
+    system::SleepSource,
+};
+
+use crate::clock;
+use crate::config;
+use crate::state::{self, Command};
+
+/// GPIO of the BOOT button on ESP32-S3 boards
+pub const BOOT_PIN: u8 = 0;
+// Contacts settle well within this
+const DEBOUNCE: Duration = Duration::from_millis(30);
+// Longest wait for the second press of a double press
+const DOUBLE_PRESS_GAP: Duration = Duration::from_millis(400);
+const LONG_PRESS: Duration = Duration::from_secs(3);
+const VERY_LONG_PRESS: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Press {
+    Short,
+    Double,
+    Long,
+    VeryLong,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Action {
+    Refresh,
+    NextImage,
+    Provision,
+    FactoryReset,
+}
+
+impl Press {
+    pub fn action(self) -> Action {
+        match self {
+            Press::Short => Action::Refresh,
+            Press::Double => Action::NextImage,
+            Press::Long => Action::Provision,
+            Press::VeryLong => Action::FactoryReset,
+        }
+    }
+}
+
+// Waits for the button to be pressed (or released) and stay that way
+async fn settle(input: &mut Input<'static>, pressed: bool) {
+    loop {
+        if pressed {
+            input.wait_for_low().await;
+        } else {
+            input.wait_for_high().await;
+        }
+        Timer::after(DEBOUNCE).await;
+        if input.is_low() == pressed {
+            return;
+        }
+    }
+}
+
+async fn next_press(input: &mut Input<'static>) -> Press {
+    settle(input, true).await;
+    let pressed_at = Instant::now();
+    if with_timeout(VERY_LONG_PRESS, settle(input, false))
+        .await
+        .is_err()
+    {
+        settle(input, false).await;
+        return Press::VeryLong;
+    }
+    if pressed_at.elapsed() >= LONG_PRESS {
+        return Press::Long;
+    }
+    match with_timeout(DOUBLE_PRESS_GAP, settle(input, true)).await {
+        Ok(()) => {
+            settle(input, false).await;
+            Press::Double
+        }
+        Err(_) => Press::Short,
+    }
+}
+
+fn perform(action: Action) {
+    match action {
+        Action::Refresh => state::send_command(Command::Refresh),
+        Action::NextImage => state::send_command(Command::NextImage),
+        Action::Provision => state::send_command(Command::Provision),
+        Action::FactoryReset => {
+            info!("Factory reset");
+            config::erase();
+            esp_hal::system::software_reset();
+        }
+    }
+}
+
+#[embassy_executor::task(pool_size = 2)]
+pub async fn button(mut input: Input<'static>) {
+    loop {
+        let press = next_press(&mut input).await;
+        let action = press.action();
+        info!("Button: {} press, {}", press, action);
+        perform(action);
+    }
+}
+
+/// Whether the frame came out of deep sleep because a button was pressed
+pub fn woke_frame() -> bool {
+    matches!(wakeup_cause(), SleepSource::Ext1)
+}
+
+/// Deep sleeps until `duration` has passed, a button on one of `pins` is
+/// pressed or the PIR sensor on `motion_pin`, if any, sees someone; the
+/// frame then starts again from reset
+pub fn sleep_deep(duration: Duration, pins: &[u8], motion_pin: Option<u8>) -> ! {
+    let timer = TimerWakeupSource::new(core::time::Duration::from_micros(duration.as_micros()));
+    // SAFETY: the button and motion tasks never run again, so their pins are free to take
+    let steal = |pin: u8| unsafe { AnyPin::steal(pin) };
+    let mut buttons: Vec<AnyPin<'static>> = pins.iter().map(|&pin| steal(pin)).collect();
+    // Held high through sleep, for buttons without their own pull-up
+    for pin in &buttons {
+        pin.rtcio_pullup(true);
+    }
+    let motion = motion_pin.map(steal);
+    if let Some(pin) = &motion {
+        pin.rtcio_pulldown(true);
+    }
+
+    let ext0 = motion.map(|pin| Ext0WakeupSource::new(pin, WakeupLevel::High));
+    let mut buttons: Vec<&mut dyn RtcPin> = buttons
+        .iter_mut()
+        .map(|pin| pin as &mut dyn RtcPin)
+        .collect();
+    let ext1 = if buttons.is_empty() {
+        None
+    } else {
+        Some(Ext1WakeupSource::new(&mut buttons, WakeupLevel::Low))
+    };
+    let mut sources: Vec<&dyn WakeSource> = Vec::new();
+    sources.push(&timer);
+    if let Some(ext0) = &ext0 {
+        sources.push(ext0);
+    }
+    if let Some(ext1) = &ext1 {
+        sources.push(ext1);
+    }
+    info!("Deep sleeping for {} s", duration.as_secs());
+    clock::sleep_deep(&sources)
+}
@@ -0,0 +1,71 @@
+//
+// The last few images shown, kept in PSRAM so a button press can step back
+// through them without the network. Lost on reset.
+//
+use alloc::{string::String, vec::Vec};
+use esp_alloc::EspHeap;
+
+pub struct CachedImage {
+    pub body: Vec<u8, &'static EspHeap>,
+    pub link: Option<String>,
+    pub image_id: Option<String>,
+}
+
+pub struct ImageCache {
+    allocator: &'static EspHeap,
+    capacity: usize,
+    // Newest first
+    images: Vec<CachedImage>,
+    // Index of the image on show, if it came from the cache
+    shown: Option<usize>,
+}
+
+impl ImageCache {
+    pub fn new(allocator: &'static EspHeap, capacity: usize) -> Self {
+        ImageCache {
+            allocator,
+            capacity,
+            images: Vec::new(),
+            shown: None,
+        }
+    }
+
+    /// Keeps a copy of a freshly fetched image, dropping the oldest if full
+    pub fn insert(&mut self, body: &[u8], link: Option<String>, image_id: Option<String>) {
+        self.shown = None;
+        if self.capacity == 0 {
+            return;
+        }
+        if self.images.len() == self.capacity {
+            self.images.pop();
+        }
+        let mut copy = Vec::new_in(self.allocator);
+        // Not worth failing a refresh over
+        if copy.try_reserve_exact(body.len()).is_err() {
+            return;
+        }
+        copy.extend_from_slice(body);
+        self.images.insert(
+            0,
+            CachedImage {
+                body: copy,
+                link,
+                image_id,
+            },
+        );
+    }
+
+    /// The image after the one on show, oldest wrapping round to newest
+    pub fn next(&mut self) -> Option<&CachedImage> {
+        if self.images.is_empty() {
+            return None;
+        }
+        // The newest is what a refresh put up, so start with the one before it
+        let index = match self.shown {
+            Some(shown) => (shown + 1) % self.images.len(),
+            None => 1 % self.images.len(),
+        };
+        self.shown = Some(index);
+        self.images.get(index)
+    }
+}
@@ -0,0 +1,117 @@
+//
+// Wall clock backed by the RTC. The RTC keeps counting through deep sleep,
+// so the time set by SNTP survives until the next power cycle.
+//
+use core::cell::{Cell, RefCell};
+use critical_section::Mutex;
+use embassy_time::Duration;
+use esp_hal::rtc_cntl::{sleep::WakeSource, Rtc};
+
+use crate::tz::{DateTime, TimeZone};
+
+// Anything earlier means the RTC has not been set since power on (2024-01-01)
+const MIN_VALID_UNIX: u64 = 1_704_067_200;
+
+// Marks `SLEEP` as written, in memory that survives deep sleep
+const SLEEP_MAGIC: u64 = 0x534c_4545_5000_0000;
+
+// The magic, then RTC times of the last refresh, going to sleep and the next
+// refresh being due, in microseconds
+#[esp_hal::ram(rtc_fast, persistent)]
+static mut SLEEP: [u64; 4] = [0; 4];
+
+static RTC: Mutex<RefCell<Option<Rtc<'static>>>> = Mutex::new(RefCell::new(None));
+static TIME_ZONE: Mutex<Cell<TimeZone>> = Mutex::new(Cell::new(TimeZone::UTC));
+
+/// Hands the RTC to the clock service
+pub fn init(rtc: Rtc<'static>, time_zone: TimeZone) {
+    critical_section::with(|cs| {
+        RTC.borrow_ref_mut(cs).replace(rtc);
+        TIME_ZONE.borrow(cs).set(time_zone);
+    });
+}
+
+pub fn time_zone() -> TimeZone {
+    critical_section::with(|cs| TIME_ZONE.borrow(cs).get())
+}
+
+/// Sets the wall clock, in microseconds since the Unix epoch
+pub fn set_unix_time_us(unix_us: u64) {
+    critical_section::with(|cs| {
+        if let Some(rtc) = RTC.borrow_ref(cs).as_ref() {
+            rtc.set_current_time_us(unix_us);
+        }
+    });
+}
+
+/// Seconds since the Unix epoch, or `None` if the clock has not been set
+pub fn unix_time() -> Option<u64> {
+    let unix_us =
+        critical_section::with(|cs| RTC.borrow_ref(cs).as_ref().map(|rtc| rtc.current_time_us()))?;
+    let unix = unix_us / 1_000_000;
+    (unix >= MIN_VALID_UNIX).then_some(unix)
+}
+
+/// The current time in the configured timezone
+pub fn local_time() -> Option<DateTime> {
+    let unix = unix_time()?;
+    Some(time_zone().to_local(unix as i64))
+}
+
+/// Powers down until one of `wake_sources` fires, then starts again from reset.
+/// The clock keeps running.
+pub fn sleep_deep(wake_sources: &[&dyn WakeSource]) -> ! {
+    let mut rtc =
+        critical_section::with(|cs| RTC.borrow_ref_mut(cs).take()).expect("clock not initialised");
+    rtc.sleep_deep(wake_sources)
+}
+
+/// Times around a deep sleep between refreshes, as they stand on waking
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slept {
+    pub since_refresh: Duration,
+    pub asleep: Duration,
+    /// Zero once the next refresh is due
+    pub until_due: Duration,
+}
+
+// Microseconds the RTC has counted, through deep sleep too. Jumps when the
+// wall clock is set.
+fn rtc_us() -> Option<u64> {
+    critical_section::with(|cs| RTC.borrow_ref(cs).as_ref().map(|rtc| rtc.current_time_us()))
+}
+
+/// Notes how long ago the frame last refreshed and how long until it's due
+/// to again, for `take_slept` after the frame wakes from deep sleep
+pub fn record_sleep(since_refresh: Duration, until_due: Duration) {
+    let Some(now) = rtc_us() else {
+        return;
+    };
+    unsafe {
+        SLEEP = [
+            SLEEP_MAGIC,
+            now.saturating_sub(since_refresh.as_micros()),
+            now,
+            now + until_due.as_micros(),
+        ];
+    }
+}
+
+/// What `record_sleep` noted before the frame last slept, measured from now,
+/// or `None` if it noted nothing or has been taken already
+pub fn take_slept() -> Option<Slept> {
+    let [magic, refreshed_at, slept_at, due_at] = unsafe { SLEEP };
+    unsafe {
+        SLEEP = [0; 4];
+    }
+    let now = rtc_us()?;
+    // Also ignored if the clock has been set back since
+    if magic != SLEEP_MAGIC || now < slept_at {
+        return None;
+    }
+    Some(Slept {
+        since_refresh: Duration::from_micros(now - refreshed_at),
+        asleep: Duration::from_micros(now - slept_at),
+        until_due: Duration::from_micros(due_at.saturating_sub(now)),
+    })
+}
@@ -0,0 +1,159 @@
+//
+// Settings that can be changed at runtime through the frame's HTTP server.
+// Build-time values are the defaults.
+//
+// Changes are kept in flash, so like the maintenance counters they survive
+// power cuts and firmware updates.
+//
+use alloc::{string::String, vec::Vec};
+use core::cell::RefCell;
+use critical_section::Mutex;
+use defmt::{warn, Format};
+use embassy_time::Duration;
+
+use crate::sequence::InitSequence;
+use crate::store;
+
+pub const MAX_URL_LEN: usize = 128;
+pub const MAX_SSID_LEN: usize = 32;
+pub const MAX_PASSWORD_LEN: usize = 64;
+pub const MAX_NAME_LEN: usize = 32;
+pub const MAX_PANEL_LEN: usize = 16;
+// First byte of the stored copy; change it if the layout below changes
+const LAYOUT: u8 = 1;
+// Shortest refresh interval accepted, to spare the panel
+pub const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum InvalidConfig {
+    UrlTooLong,
+    SsidTooLong,
+    PasswordTooLong,
+    NameTooLong,
+    IntervalTooShort,
+    UnknownPanel,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Where to fetch images from
+    pub server_url: String,
+    /// Time between refreshes when no schedule is set
+    pub refresh_interval: Duration,
+    pub wifi_ssid: String,
+    pub wifi_password: String,
+    /// Given by the user to tell frames apart, e.g. "Hallway"; empty for none
+    pub name: String,
+    /// Name of the panel's init sequence, used from the next boot; empty for the default
+    pub panel: String,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), InvalidConfig> {
+        if self.server_url.len() > MAX_URL_LEN {
+            return Err(InvalidConfig::UrlTooLong);
+        }
+        if self.wifi_ssid.len() > MAX_SSID_LEN {
+            return Err(InvalidConfig::SsidTooLong);
+        }
+        if self.wifi_password.len() > MAX_PASSWORD_LEN {
+            return Err(InvalidConfig::PasswordTooLong);
+        }
+        if self.name.len() > MAX_NAME_LEN {
+            return Err(InvalidConfig::NameTooLong);
+        }
+        if self.refresh_interval < MIN_REFRESH_INTERVAL {
+            return Err(InvalidConfig::IntervalTooShort);
+        }
+        if !self.panel.is_empty() && InitSequence::by_name(&self.panel).is_none() {
+            return Err(InvalidConfig::UnknownPanel);
+        }
+        Ok(())
+    }
+}
+
+static CONFIG: Mutex<RefCell<Option<Config>>> = Mutex::new(RefCell::new(None));
+
+/// Loads the stored settings, falling back to `defaults` if there are none
+pub fn init(defaults: Config) {
+    let config = load().unwrap_or(defaults);
+    critical_section::with(|cs| CONFIG.borrow_ref_mut(cs).replace(config));
+}
+
+/// The current settings
+pub fn get() -> Config {
+    critical_section::with(|cs| CONFIG.borrow_ref(cs).clone()).expect("config not initialised")
+}
+
+/// Replaces the settings and stores them
+pub fn set(config: Config) -> Result<(), InvalidConfig> {
+    config.validate()?;
+    if let Err(e) = store::save(store::CONFIG, &encode(&config)) {
+        warn!("Couldn't save settings: {:?}", e);
+    }
+    critical_section::with(|cs| CONFIG.borrow_ref_mut(cs).replace(config));
+    Ok(())
+}
+
+/// Forgets the stored settings, so the build-time defaults apply after a reset
+pub fn erase() {
+    if let Err(e) = store::clear(store::CONFIG) {
+        warn!("Couldn't erase settings: {:?}", e);
+    }
+}
+
+fn load() -> Option<Config> {
+    let mut buffer = [0u8; store::CONFIG.capacity()];
+    let len = match store::load(store::CONFIG, &mut buffer) {
+        Ok(len) => len?,
+        Err(e) => {
+            warn!("Couldn't load settings: {:?}", e);
+            return None;
+        }
+    };
+    let config = decode(&buffer[..len])?;
+    config.validate().ok().map(|_| config)
+}
+
+// The layout byte and refresh interval, then each string after its length. The
+// panel came later, so settings stored without it still load.
+fn encode(config: &Config) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.push(LAYOUT);
+    let refresh_secs = config.refresh_interval.as_secs().min(u32::MAX as u64) as u32;
+    bytes.extend_from_slice(&refresh_secs.to_le_bytes());
+    // Lengths are checked by `Config::validate` before storing
+    for value in [
+        &config.server_url,
+        &config.wifi_ssid,
+        &config.wifi_password,
+        &config.name,
+        &config.panel,
+    ] {
+        bytes.push(value.len() as u8);
+        bytes.extend_from_slice(value.as_bytes());
+    }
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Option<Config> {
+    let (&layout, rest) = bytes.split_first()?;
+    if layout != LAYOUT {
+        return None;
+    }
+    let (refresh_secs, mut rest) = rest.split_first_chunk::<4>()?;
+    let mut next = || {
+        let (&len, after) = rest.split_first()?;
+        let (value, after) = after.split_at_checked(len as usize)?;
+        rest = after;
+        core::str::from_utf8(value).ok().map(String::from)
+    };
+    Some(Config {
+        server_url: next()?,
+        refresh_interval: Duration::from_secs(u32::from_le_bytes(*refresh_secs) as u64),
+        wifi_ssid: next()?,
+        wifi_password: next()?,
+        name: next()?,
+        panel: next().unwrap_or_default(),
+    })
+}
@@ -0,0 +1,173 @@
+//
+// A command console on the USB serial port, for setting the frame up and
+// looking into it without the network. Open the port at any baud rate and
+// type `help`.
+//
+// Lines are parsed into requests by `commands`.
+//
+use alloc::{format, string::String, vec::Vec};
+use defmt::info;
+use embedded_io_async::{Read, Write};
+use esp_alloc::EspHeap;
+use esp_hal::{usb_serial_jtag::UsbSerialJtag, Async};
+
+use crate::commands::{self, ParseError, Request, COMMANDS};
+use crate::config::{self, InvalidConfig};
+use crate::draw::SharedDisplay;
+use crate::state::{self, Command};
+use crate::wifi;
+
+// Longest line kept; anything past it is dropped
+const MAX_LINE: usize = 256;
+const PROMPT: &str = "> ";
+
+fn describe(e: InvalidConfig) -> &'static str {
+    match e {
+        InvalidConfig::UrlTooLong => "url too long",
+        InvalidConfig::SsidTooLong => "ssid too long",
+        InvalidConfig::PasswordTooLong => "password too long",
+        InvalidConfig::NameTooLong => "name too long",
+        InvalidConfig::IntervalTooShort => "refresh interval too short",
+        InvalidConfig::UnknownPanel => "unknown panel",
+    }
+}
+
+// Carries out a request and says how it went
+async fn perform(request: Request<'_>, display: &SharedDisplay, psram: &EspHeap) -> String {
+    match request {
+        Request::Help => COMMANDS
+            .iter()
+            .map(|(usage, about)| format!("{:<28}{}\r\n", usage, about))
+            .collect(),
+        Request::SetWifi { ssid, password } => {
+            let mut new = config::get();
+            new.wifi_ssid = String::from(ssid);
+            new.wifi_password = String::from(password);
+            match config::set(new) {
+                Ok(()) => {
+                    info!("Wi-Fi settings changed from the console");
+                    wifi::restart();
+                    format!("Joining {}\r\n", ssid)
+                }
+                Err(e) => format!("Error: {}\r\n", describe(e)),
+            }
+        }
+        Request::SetUrl(url) => {
+            let mut new = config::get();
+            new.server_url = String::from(url);
+            match config::set(new) {
+                Ok(()) => String::from("Saved\r\n"),
+                Err(e) => format!("Error: {}\r\n", describe(e)),
+            }
+        }
+        Request::Refresh => {
+            state::send_command(Command::Refresh);
+            String::from("Refreshing\r\n")
+        }
+        Request::Clear(color) => {
+            let mut display = display.lock().await;
+            let result = match display.init().await {
+                Ok(()) => display.clear(color).await,
+                Err(e) => Err(e),
+            };
+            display.sleep().await.ok();
+            match result {
+                Ok(()) => String::from("Cleared\r\n"),
+                Err(e) => format!("Error: {:?}\r\n", e),
+            }
+        }
+        Request::Heap => format!(
+            "Internal:\r\n{}\r\nPSRAM:\r\n{}\r\n",
+            esp_alloc::HEAP.stats(),
+            psram.stats()
+        ),
+        Request::Scan => match wifi::scan().await {
+            Some(networks) if networks.is_empty() => String::from("No networks found\r\n"),
+            Some(networks) => networks
+                .iter()
+                .map(|network| {
+                    format!(
+                        "{:>4} dBm  {}{}\r\n",
+                        network.rssi,
+                        network.ssid,
+                        if network.secured { "" } else { " (open)" }
+                    )
+                })
+                .collect(),
+            None => String::from("Error: Wi-Fi is busy or the scan failed\r\n"),
+        },
+        Request::Reboot => {
+            info!("Rebooting from the console");
+            esp_hal::system::software_reset();
+        }
+        Request::FactoryReset => {
+            info!("Factory reset from the console");
+            config::erase();
+            esp_hal::system::software_reset();
+        }
+    }
+}
+
+fn complain(e: ParseError) -> String {
+    match e {
+        ParseError::Empty => String::new(),
+        ParseError::Unknown => String::from("Unknown command, try help\r\n"),
+        ParseError::Usage(usage) => format!("Usage: {}\r\n", usage),
+        ParseError::UnterminatedQuote => String::from("Missing closing quote\r\n"),
+    }
+}
+
+#[embassy_executor::task]
+pub async fn console(
+    usb: UsbSerialJtag<'static, Async>,
+    display: &'static SharedDisplay,
+    psram: &'static EspHeap,
+) {
+    let (mut rx, mut tx) = usb.split();
+    let mut line: Vec<u8> = Vec::with_capacity(MAX_LINE);
+    let mut buffer = [0u8; 64];
+    let mut last_cr = false;
+    tx.write_all(PROMPT.as_bytes()).await.ok();
+    loop {
+        let Ok(count) = rx.read(&mut buffer).await else {
+            continue;
+        };
+        for &byte in &buffer[..count] {
+            let after_cr = last_cr;
+            last_cr = byte == b'\r';
+            match byte {
+                // A CR LF pair is one line end
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    tx.write_all(b"\r\n").await.ok();
+                    let reply = match core::str::from_utf8(&line) {
+                        Ok(text) => match commands::parse(text) {
+                            Ok(request) => perform(request, display, psram).await,
+                            Err(e) => complain(e),
+                        },
+                        Err(_) => String::from("Not UTF-8\r\n"),
+                    };
+                    line.clear();
+                    tx.write_all(reply.as_bytes()).await.ok();
+                    tx.write_all(PROMPT.as_bytes()).await.ok();
+                }
+                // Backspace or delete
+                0x08 | 0x7f => {
+                    // A whole character, however many bytes it took
+                    while let Some(byte) = line.pop() {
+                        if byte & 0xc0 != 0x80 {
+                            tx.write_all(b"\x08 \x08").await.ok();
+                            break;
+                        }
+                    }
+                }
+                byte if line.len() < MAX_LINE && !byte.is_ascii_control() => {
+                    line.push(byte);
+                    tx.write_all(&[byte]).await.ok();
+                }
+                _ => {}
+            }
+        }
+        tx.flush().await.ok();
+    }
+}
@@ -1,18 +1,34 @@
 #![allow(clippy::all)]
 #![allow(dead_code)]
-use defmt::{info, println, Format};
-use embassy_time::{Duration, Timer};
+use defmt::{debug, info, println, warn, Format};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Timer};
 use esp_hal::gpio::{Input, Output};
-use esp_hal::spi::master::SpiDmaBus;
+use esp_hal::spi::master::{Address, Command, DataMode, SpiDmaBus};
 use esp_hal::Async;
+use static_cell::ConstStaticCell;
+
+use crate::color::Color;
+use crate::framebuffer::Framebuffer;
+use crate::maintenance;
+use crate::pattern::Pattern;
+use crate::sequence::{self, InitSequence, Step};
+use crate::temperature::{RefreshAdvice, Temperature, TemperatureSource};
 
 // Display resolution
-const EPD_WIDTH: u32 = 800;
-const EPD_HEIGHT: u32 = 480;
+pub(crate) const EPD_WIDTH: u32 = 800;
+pub(crate) const EPD_HEIGHT: u32 = 480;
 pub(crate) const DISPLAY_BUFFER_SIZE: usize = (EPD_WIDTH * EPD_HEIGHT / 2) as usize;
 
 const EPD_HEADER_SIZE: usize = 13;
 // const CHUNK_SIZE: usize = 32768;
+const ROW_BYTES: usize = (EPD_WIDTH / 2) as usize;
+// Rows generated at a time when filling. `write_async` copies them through the
+// 32000 byte DMA buffer in pieces, so this only trades RAM for fewer transfers.
+const FILL_ROWS: usize = 16;
+// Where the rows are generated, kept for the one panel rather than allocated per fill
+static FILL_CHUNK: ConstStaticCell<[u8; ROW_BYTES * FILL_ROWS]> =
+    ConstStaticCell::new([0; ROW_BYTES * FILL_ROWS]);
 static EPD_FILE_SIZE: usize = DISPLAY_BUFFER_SIZE + EPD_HEADER_SIZE;
 
 #[derive(Debug, Format)]
@@ -21,6 +37,7 @@ pub enum Error {
     InvalidVersion,
     InvalidDimensions,
     BufferTooSmall,
+    TemperatureOutOfRange(Temperature),
     // InvalidHeader,
     // UnsupportedBitDepth,
     // InvalidFileSize,
@@ -30,34 +47,22 @@ pub enum Error {
     SpiError(esp_hal::spi::Error),
 }
 
-impl From<esp_hal::spi::Error> for Error {
-    fn from(e: esp_hal::spi::Error) -> Self {
-        Error::SpiError(e)
+impl Error {
+    /// Whether the image itself was at fault, rather than the panel
+    pub fn is_bad_image(&self) -> bool {
+        matches!(
+            self,
+            Error::InvalidMagic
+                | Error::InvalidVersion
+                | Error::InvalidDimensions
+                | Error::BufferTooSmall
+        )
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum Color {
-    Black = 0x000000,
-    White = 0xffffff,
-    Green = 0x00ff00,
-    Blue = 0xff0000,
-    Red = 0x0000ff,
-    Yellow = 0x00ffff,
-    Orange = 0x0080ff,
-}
-
-impl Color {
-    pub fn to_byte(self) -> u8 {
-        match self {
-            Color::Black => 0x0,
-            Color::White => 0x1,
-            Color::Green => 0x2,
-            Color::Blue => 0x3,
-            Color::Red => 0x4,
-            Color::Yellow => 0x5,
-            Color::Orange => 0x6,
-        }
+impl From<esp_hal::spi::Error> for Error {
+    fn from(e: esp_hal::spi::Error) -> Self {
+        Error::SpiError(e)
     }
 }
 
@@ -76,12 +81,20 @@ impl Color {
 //     }
 // }
 
+/// The panel, shared between the refresh loop and the HTTP server
+pub type SharedDisplay = Mutex<CriticalSectionRawMutex, EPD7in3f<'static>>;
+
 pub struct EPD7in3f<'d> {
     spi: SpiDmaBus<'d, Async>,
     // cs: Output<'d>,
     dc: Output<'d>,
     rst: Output<'d>,
     busy: Input<'d>,
+    last_refresh: Option<Duration>,
+    init_sequence: &'static InitSequence,
+    temperature_source: TemperatureSource,
+    temperature: Option<Temperature>,
+    fill_chunk: &'static mut [u8; ROW_BYTES * FILL_ROWS],
 }
 
 impl<'d> EPD7in3f<'d> {
@@ -92,15 +105,26 @@ impl<'d> EPD7in3f<'d> {
         rst: Output<'d>,
         busy: Input<'d>,
     ) -> Self {
+        // Panics if called twice, as there's only the one panel
         Self {
             spi,
             // cs,
             dc,
             rst,
             busy,
+            last_refresh: None,
+            init_sequence: &sequence::INIT_DEFAULT,
+            temperature_source: TemperatureSource::Internal,
+            temperature: None,
+            fill_chunk: FILL_CHUNK.take(),
         }
     }
 
+    /// How long the panel held BUSY during the most recent refresh
+    pub fn last_refresh_duration(&self) -> Option<Duration> {
+        self.last_refresh
+    }
+
     // Hardware reset
     pub async fn reset(&mut self) {
         self.rst.set_high();
@@ -129,10 +153,7 @@ impl<'d> EPD7in3f<'d> {
     }
 
     async fn send_data_slice(&mut self, data: &[u8]) -> Result<(), Error> {
-        info!(
-            "sending {} {} {} {} {} {}",
-            data[0], data[1], data[2], data[3], data[4], data[5]
-        );
+        debug!("sending {} bytes", data.len());
 
         self.dc.set_high();
         // self.cs.set_low();
@@ -141,10 +162,12 @@ impl<'d> EPD7in3f<'d> {
         Ok(())
     }
 
-    async fn read_busy_h(&mut self) {
-        while self.busy.is_low() {
-            Timer::after(Duration::from_millis(5)).await;
-        }
+    // Waits for the BUSY pin to go high (idle), returning how long the panel was busy.
+    // Awaiting the edge lets the executor idle instead of polling during a refresh.
+    async fn read_busy_h(&mut self) -> Duration {
+        let start = Instant::now();
+        self.busy.wait_for_high().await;
+        start.elapsed()
     }
 
     async fn turn_on_display(&mut self) -> Result<(), Error> {
@@ -157,7 +180,9 @@ impl<'d> EPD7in3f<'d> {
         self.send_command(0x12).await?; // DISPLAY_REFRESH
         self.send_data(0x00).await?;
         // info!("Doing Refresh...");
-        self.read_busy_h().await;
+        let refresh = self.read_busy_h().await;
+        self.last_refresh = Some(refresh);
+        info!("Refresh took {} ms", refresh.as_millis());
 
         self.send_command(0x02).await?; // POWER_OFF
         self.send_data(0x00).await?;
@@ -167,106 +192,84 @@ impl<'d> EPD7in3f<'d> {
         Ok(())
     }
 
-    pub async fn init(&mut self) -> Result<(), Error> {
-        info!("Display init...");
-
-        self.reset().await;
-        self.read_busy_h().await;
-        Timer::after(Duration::from_millis(30)).await;
-
-        // Initialize display registers
-        self.send_command(0xAA).await?; // CMDH
-        self.send_data(0x49).await?;
-        self.send_data(0x55).await?;
-        self.send_data(0x20).await?;
-        self.send_data(0x08).await?;
-        self.send_data(0x09).await?;
-        self.send_data(0x18).await?;
-
-        // Continue with all initialization commands from original driver
-        self.send_command(0x01).await?;
-        self.send_data(0x3F).await?;
-        self.send_data(0x00).await?;
-        self.send_data(0x32).await?;
-        self.send_data(0x2A).await?;
-        self.send_data(0x0E).await?;
-        self.send_data(0x2A).await?;
-
-        // ... (remaining initialization commands)
-        self.send_command(0x00).await?;
-        self.send_data(0x5F).await?;
-        self.send_data(0x69).await?;
-
-        self.send_command(0x03).await?;
-        self.send_data(0x00).await?;
-        self.send_data(0x54).await?;
-        self.send_data(0x00).await?;
-        self.send_data(0x44).await?;
-
-        self.send_command(0x05).await?;
-        self.send_data(0x40).await?;
-        self.send_data(0x1F).await?;
-        self.send_data(0x1F).await?;
-        self.send_data(0x2C).await?;
-
-        self.send_command(0x06).await?;
-        self.send_data(0x6F).await?;
-        self.send_data(0x1F).await?;
-        self.send_data(0x1F).await?;
-        self.send_data(0x22).await?;
-
-        self.send_command(0x08).await?;
-        self.send_data(0x6F).await?;
-        self.send_data(0x1F).await?;
-        self.send_data(0x1F).await?;
-        self.send_data(0x22).await?;
-
-        self.send_command(0x13).await?; // IPC
-        self.send_data(0x00).await?;
-        self.send_data(0x04).await?;
-
-        self.send_command(0x30).await?;
-        self.send_data(0x3C).await?;
-
-        self.send_command(0x41).await?; //     # TSE
-        self.send_data(0x00).await?;
-
-        self.send_command(0x50).await?;
-        self.send_data(0x3F).await?;
+    /// Selects the init sequence used by subsequent calls to `init`
+    pub fn set_init_sequence(&mut self, sequence: &'static InitSequence) {
+        info!("Using init sequence: {}", sequence.name);
+        self.init_sequence = sequence;
+    }
 
-        self.send_command(0x60).await?;
-        self.send_data(0x02).await?;
-        self.send_data(0x00).await?;
+    /// Executes a table of panel steps in order
+    pub async fn run_sequence(&mut self, steps: &[Step]) -> Result<(), Error> {
+        sequence::run(self, steps).await
+    }
 
-        self.send_command(0x61).await?;
-        self.send_data(0x03).await?;
-        self.send_data(0x20).await?;
-        self.send_data(0x01).await?;
-        self.send_data(0xE0).await?;
+    pub async fn init(&mut self) -> Result<(), Error> {
+        info!("Display init...");
+        let steps = self.init_sequence.steps;
+        self.run_sequence(steps).await?;
+
+        if let TemperatureSource::Fixed(temperature) = self.temperature_source {
+            self.send_command(0x41).await?; // TSE: take the waveform temperature from TSSET
+            self.send_data(0x80).await?;
+            self.send_command(0xE6).await?; // TSSET
+            self.send_data(temperature.to_register()).await?;
+        }
+        info!("Init Complete.");
 
-        self.send_command(0x82).await?;
-        self.send_data(0x1E).await?;
+        Ok(())
+    }
 
-        self.send_command(0x84).await?;
-        self.send_data(0x00).await?;
+    /// Overrides the temperature the controller uses to pick its waveform.
+    /// Takes effect on the next `init`.
+    pub fn set_temperature_source(&mut self, source: TemperatureSource) {
+        self.temperature_source = source;
+    }
 
-        self.send_command(0x86).await?; // AGID
-        self.send_data(0x00).await?;
+    /// The most recent reading taken by `read_temperature`
+    pub fn temperature(&self) -> Option<Temperature> {
+        self.temperature
+    }
 
-        self.send_command(0xE3).await?;
-        self.send_data(0x2F).await?;
+    /// Reads the panel's temperature sensor. The panel must be initialised.
+    pub async fn read_temperature(&mut self) -> Result<Temperature, Error> {
+        self.send_command(0x40).await?; // TSC
+        self.read_busy_h().await;
 
-        self.send_command(0xE0).await?; //   # CCSET
-        self.send_data(0x00).await?;
+        // The panel answers on the bidirectional data line. The DMA bus has no
+        // async half-duplex read, so this one blocks, but only for the two bytes:
+        // a few tens of microseconds, with the bus already idle after the await
+        // above.
+        let mut raw = [0u8; 2];
+        self.dc.set_high();
+        self.spi
+            .half_duplex_read(DataMode::Single, Command::None, Address::None, 0, &mut raw)?;
 
-        self.send_command(0xE6).await?; //  # TSSET
-        self.send_data(0x00).await?;
-        info!("Init Complete.");
+        let temperature = Temperature::from_raw(raw);
+        info!("Panel temperature: {}", temperature);
+        self.temperature = Some(temperature);
+        Ok(temperature)
+    }
 
-        Ok(())
+    // Refuses to refresh outside the rated range, based on the last reading
+    fn check_temperature(&self) -> Result<(), Error> {
+        let Some(temperature) = self.temperature else {
+            return Ok(());
+        };
+        match RefreshAdvice::for_temperature(temperature) {
+            RefreshAdvice::Normal => Ok(()),
+            RefreshAdvice::Cold => {
+                warn!(
+                    "Panel is cold ({}), colours will be washed out",
+                    temperature
+                );
+                Ok(())
+            }
+            RefreshAdvice::OutOfRange => Err(Error::TemperatureOutOfRange(temperature)),
+        }
     }
 
     async fn display(&mut self, buffer: &[u8]) -> Result<(), Error> {
+        self.check_temperature()?;
         println!("Printing: {} bytes", buffer.len());
         self.send_command(0x10).await?;
         self.send_data_slice(buffer).await?;
@@ -335,80 +338,204 @@ impl<'d> EPD7in3f<'d> {
 
     /// Reads our custom EPD format and displays it
     pub async fn display_epd(&mut self, data: &[u8]) -> Result<(), Error> {
-        // Check minimum size for header (magic + version + dimensions)
-        // println!("{}", data[0..20]);
-        // println!("display: {=[u8]:x}", data[0..100]);
-        if data.len() < 13 {
-            return Err(Error::BufferTooSmall);
-        }
+        validate_epd(data)?;
 
-        println!("Got: {} Want: {}", data.len(), EPD_FILE_SIZE);
-        if data.len() < EPD_FILE_SIZE {
-            return Err(Error::BufferTooSmall);
+        // The rest of the data is already in the correct format for our display
+        // as we packed it that way in the converter
+        let display_data = &data[EPD_HEADER_SIZE..];
+
+        // Send the data to display
+        self.display(display_data).await?;
+
+        Ok(())
+    }
+
+    /// Shows a locally drawn image
+    pub async fn display_framebuffer(
+        &mut self,
+        framebuffer: &Framebuffer<'_>,
+    ) -> Result<(), Error> {
+        self.display(framebuffer.data()).await
+    }
+
+    /// Starts showing an EPD image that arrives in pieces, e.g. straight from
+    /// the network, without holding the whole image in memory
+    pub fn stream_epd(&mut self) -> EpdStream<'_, 'd> {
+        EpdStream {
+            display: self,
+            header: [0; EPD_HEADER_SIZE],
+            received: 0,
         }
+    }
+
+    pub async fn clear(&mut self, color: Color) -> Result<(), Error> {
+        self.fill(Pattern::Solid(color)).await
+    }
 
-        // Check magic number "EPD7"
-        println!("Got: {} Want: EPD7", data[0..4]);
+    /// Fills the whole panel with a generated pattern and refreshes it.
+    /// Rows are rendered a chunk at a time and sent in bulk, reusing the
+    /// chunk when every row is the same.
+    pub async fn fill(&mut self, pattern: Pattern) -> Result<(), Error> {
+        self.check_temperature()?;
+        info!("Sending pattern: {}", pattern);
 
-        if &data[0..4] != b"EPD7" {
-            return Err(Error::InvalidMagic);
+        let row_invariant = pattern.is_row_invariant();
+        if row_invariant {
+            pattern.render_rows(&mut self.fill_chunk[..], 0, EPD_WIDTH);
         }
 
-        // Check version
-        if data[4] != 1 {
-            return Err(Error::InvalidVersion);
+        self.send_command(0x10).await?;
+        for first_row in (0..EPD_HEIGHT).step_by(FILL_ROWS) {
+            let rows = (EPD_HEIGHT - first_row).min(FILL_ROWS as u32) as usize;
+            let part = &mut self.fill_chunk[..rows * ROW_BYTES];
+            if !row_invariant {
+                pattern.render_rows(part, first_row, EPD_WIDTH);
+            }
+            // As `send_data_slice`, borrowing only the fields it needs
+            self.dc.set_high();
+            self.spi.write_async(part).await?;
         }
+        info!("Sending Done!");
+
+        self.turn_on_display().await?;
 
-        // Read dimensions
-        let width = u32::from_le_bytes(data[5..9].try_into().unwrap());
-        let height = u32::from_le_bytes(data[9..13].try_into().unwrap());
+        Ok(())
+    }
 
-        // Verify dimensions
-        if width != EPD_WIDTH || height != EPD_HEIGHT {
-            return Err(Error::InvalidDimensions);
+    /// Cycles the panel through the deep clean frames to remove ghosting
+    pub async fn deep_clean(&mut self) -> Result<(), Error> {
+        info!("Starting deep clean");
+        for pattern in maintenance::DEEP_CLEAN_PATTERNS {
+            self.fill(*pattern).await?;
         }
+        info!("Deep clean complete");
+        Ok(())
+    }
 
-        // The rest of the data is already in the correct format for our display
-        // as we packed it that way in the converter
-        let display_data = &data[13..];
+    pub async fn sleep(&mut self) -> Result<(), Error> {
+        self.run_sequence(sequence::DEEP_SLEEP).await
+    }
+}
 
-        // Calculate expected data size (width * height / 2 because we pack 2 pixels per byte)
-        let expected_size = ((800 * 480) / 2) as usize;
-        if display_data.len() < expected_size {
-            println!("{} >> {}", expected_size, display_data.len());
-            return Err(Error::BufferTooSmall);
-        }
+impl sequence::Bus for EPD7in3f<'_> {
+    type Error = Error;
 
-        // Send the data to display
-        let _ = self.display(display_data).await;
+    async fn reset(&mut self) {
+        EPD7in3f::reset(self).await
+    }
+
+    fn set_dc(&mut self, data: bool) {
+        self.dc.set_level(data.into());
+    }
 
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.spi.write_async(bytes).await?;
         Ok(())
     }
 
-    pub async fn clear(&mut self, color: Color) -> Result<(), Error> {
-        // Fill buffer with color
-        let color_byte = (color.to_byte() << 4) | color.to_byte();
-        info!("Sending Image Data");
+    async fn wait_busy(&mut self) {
+        self.read_busy_h().await;
+    }
 
-        self.send_command(0x10).await?;
-        // Send the same color byte for the entire display
-        // We send width * height / 2 bytes because each byte contains 2 pixels
-        for _ in 0..(EPD_WIDTH * EPD_HEIGHT / 2) {
-            self.send_data(color_byte).await?;
-        }
-        info!("Sending Done!");
+    async fn delay(&mut self, ms: u64) {
+        Timer::after(Duration::from_millis(ms)).await
+    }
+}
 
-        self.turn_on_display().await?;
+/// An EPD image being received. The header is checked as soon as it is
+/// complete and pixels go to the panel as they arrive; the panel only
+/// refreshes once every pixel is in, so an aborted stream leaves the
+/// current picture alone.
+pub struct EpdStream<'a, 'd> {
+    display: &'a mut EPD7in3f<'d>,
+    header: [u8; EPD_HEADER_SIZE],
+    received: usize,
+}
+
+impl EpdStream<'_, '_> {
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        if self.received < EPD_HEADER_SIZE {
+            let take = data.len().min(EPD_HEADER_SIZE - self.received);
+            self.header[self.received..self.received + take].copy_from_slice(&data[..take]);
+            self.received += take;
+            data = &data[take..];
+            if self.received < EPD_HEADER_SIZE {
+                return Ok(());
+            }
+            validate_epd_header(&self.header)?;
+            self.display.check_temperature()?;
+            self.display.send_command(0x10).await?;
+        }
 
+        // Anything past the pixels is ignored, as with `validate_epd`
+        let take = data.len().min(EPD_FILE_SIZE - self.received);
+        if take > 0 {
+            self.display.send_data_slice(&data[..take]).await?;
+            self.received += take;
+        }
         Ok(())
     }
 
-    pub async fn sleep(&mut self) -> Result<(), Error> {
-        self.send_command(0x07).await?; // DEEP_SLEEP
-        self.send_data(0xA5).await?;
-        Timer::after(Duration::from_millis(2000)).await;
-        Ok(())
+    /// Refreshes the panel, if the whole image was received
+    pub async fn finish(self) -> Result<(), Error> {
+        if self.received < EPD_FILE_SIZE {
+            return Err(Error::BufferTooSmall);
+        }
+        self.display.turn_on_display().await
+    }
+}
+
+/// Checks the header and size of an image in our custom EPD format
+pub fn validate_epd(data: &[u8]) -> Result<(), Error> {
+    // Check minimum size for header (magic + version + dimensions)
+    // println!("{}", data[0..20]);
+    // println!("display: {=[u8]:x}", data[0..100]);
+    if data.len() < EPD_HEADER_SIZE {
+        return Err(Error::BufferTooSmall);
+    }
+
+    debug!("Got: {} Want: {}", data.len(), EPD_FILE_SIZE);
+    if data.len() < EPD_FILE_SIZE {
+        return Err(Error::BufferTooSmall);
     }
+
+    validate_epd_header(data)
+}
+
+/// Checks just the header of an EPD image, before the pixels have arrived
+pub fn validate_epd_header(data: &[u8]) -> Result<(), Error> {
+    if data.len() < EPD_HEADER_SIZE {
+        return Err(Error::BufferTooSmall);
+    }
+
+    // Check magic number "EPD7"
+    debug!("Got: {} Want: EPD7", data[0..4]);
+
+    if &data[0..4] != b"EPD7" {
+        return Err(Error::InvalidMagic);
+    }
+
+    // Check version
+    if data[4] != 1 {
+        return Err(Error::InvalidVersion);
+    }
+
+    // Read dimensions
+    let width = u32::from_le_bytes(data[5..9].try_into().unwrap());
+    let height = u32::from_le_bytes(data[9..13].try_into().unwrap());
+
+    // Verify dimensions
+    if width != EPD_WIDTH || height != EPD_HEIGHT {
+        return Err(Error::InvalidDimensions);
+    }
+
+    Ok(())
+}
+
+/// The packed pixel data of a valid EPD image, for drawing on before display
+pub fn epd_pixels_mut(data: &mut [u8]) -> Result<&mut [u8], Error> {
+    validate_epd(data)?;
+    Ok(&mut data[EPD_HEADER_SIZE..])
 }
 
 // // Add this helper function
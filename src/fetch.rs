@@ -0,0 +1,177 @@
+//
+// HTTP GET of an image from the server into a caller supplied buffer
+//
+// Redirects are followed. A server managing several frames can also hand a
+// frame its own URL to use from then on, with a permanent redirect or the
+// `X-Frame-Url` header.
+//
+use alloc::{format, string::String};
+use core::ops::Range;
+use defmt::{info, warn, Format};
+use embassy_net::{
+    dns::DnsSocket,
+    tcp::client::{TcpClient, TcpClientState},
+    Stack,
+};
+use reqwless::{
+    client::HttpClient,
+    request::{Method, RequestBuilder},
+};
+
+// Optional response header with a link to show as a QR code on the photo
+const LINK_HEADER: &str = "X-Frame-Link";
+// Optional response header naming the image, for reporting what's on show
+const IMAGE_ID_HEADER: &str = "X-Image-Id";
+// Optional response header with the URL this frame should fetch from next time
+const FRAME_URL_HEADER: &str = "X-Frame-Url";
+// Hops followed before giving up, in case the server redirects in a loop
+const MAX_REDIRECTS: usize = 4;
+
+#[derive(Debug, Format)]
+pub enum FetchError {
+    Http(reqwless::Error),
+    Status(u16),
+    TooManyRedirects,
+    /// A redirect without a usable Location header
+    BadRedirect,
+}
+
+impl From<reqwless::Error> for FetchError {
+    fn from(e: reqwless::Error) -> Self {
+        FetchError::Http(e)
+    }
+}
+
+pub struct Fetched<'b> {
+    pub body: &'b mut [u8],
+    pub link: Option<String>,
+    pub image_id: Option<String>,
+    /// URL the server assigned this frame, by permanent redirect or header
+    pub frame_url: Option<String>,
+}
+
+// Outcome of a single request
+enum Step {
+    Body {
+        range: Range<usize>,
+        link: Option<String>,
+        image_id: Option<String>,
+        frame_url: Option<String>,
+    },
+    Redirect {
+        location: String,
+        permanent: bool,
+    },
+}
+
+/// Resolves a Location header against the URL that sent it
+fn resolve(base: &str, location: &str) -> Option<String> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return Some(String::from(location));
+    }
+    let (scheme, rest) = base.split_once("://")?;
+    let host = rest.split('/').next()?;
+    if let Some(path) = location.strip_prefix('/') {
+        return Some(format!("{}://{}/{}", scheme, host, path));
+    }
+    // Relative to the directory of the base path, ignoring its query
+    let path = base.split(['?', '#']).next()?;
+    let directory = match path.rfind('/') {
+        Some(end) if end > scheme.len() + 2 => &path[..=end],
+        _ => return Some(format!("{}/{}", path, location)),
+    };
+    Some(format!("{}{}", directory, location))
+}
+
+/// Fetches `url`, reading the response body into `buffer`
+///
+/// `headers` go with every request, redirects included.
+pub async fn get<'b>(
+    stack: Stack<'_>,
+    url: &str,
+    headers: &[(&str, &str)],
+    buffer: &'b mut [u8],
+) -> Result<Fetched<'b>, FetchError> {
+    let client_state = TcpClientState::<1, 1024, 1024>::new();
+    let tcp_client = TcpClient::new(stack, &client_state);
+    let dns_client = DnsSocket::new(stack);
+    let mut http_client = HttpClient::new(&tcp_client, &dns_client);
+
+    let mut url = String::from(url);
+    // Only kept if every hop on the way was permanent
+    let mut moved_to = None;
+    let mut all_permanent = true;
+    for _ in 0..=MAX_REDIRECTS {
+        info!("GET {}", url.as_str());
+        // The body only borrows `buffer` once the last hop is known, so it's
+        // passed back as a range rather than a slice
+        let step = {
+            let start = buffer.as_ptr() as usize;
+            let mut request = http_client
+                .request(Method::GET, &url)
+                .await?
+                .headers(headers);
+            let response = request.send(buffer).await?;
+            let status = response.status.0;
+            let header = |wanted: &str| {
+                response
+                    .headers()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+                    .and_then(|(_, value)| core::str::from_utf8(value).ok())
+                    .map(String::from)
+            };
+            match status {
+                301 | 302 | 303 | 307 | 308 => Step::Redirect {
+                    location: header("Location")
+                        .and_then(|location| resolve(&url, location.trim()))
+                        .ok_or(FetchError::BadRedirect)?,
+                    permanent: matches!(status, 301 | 308),
+                },
+                _ if !response.status.is_successful() => return Err(FetchError::Status(status)),
+                _ => {
+                    let link = header(LINK_HEADER);
+                    let image_id = header(IMAGE_ID_HEADER);
+                    let frame_url = header(FRAME_URL_HEADER);
+                    let body = response.body().read_to_end().await?;
+                    let offset = body.as_ptr() as usize - start;
+                    Step::Body {
+                        range: offset..offset + body.len(),
+                        link,
+                        image_id,
+                        frame_url,
+                    }
+                }
+            }
+        };
+
+        match step {
+            Step::Redirect {
+                location,
+                permanent,
+            } => {
+                info!("Redirected to {}", location.as_str());
+                all_permanent &= permanent;
+                if all_permanent {
+                    moved_to = Some(location.clone());
+                }
+                url = location;
+            }
+            Step::Body {
+                range,
+                link,
+                image_id,
+                frame_url,
+            } => {
+                info!("Got body: {}", range.len());
+                return Ok(Fetched {
+                    body: &mut buffer[range],
+                    link,
+                    image_id,
+                    frame_url: frame_url.or(moved_to),
+                });
+            }
+        }
+    }
+    warn!("Too many redirects");
+    Err(FetchError::TooManyRedirects)
+}
@@ -0,0 +1,127 @@
+//
+// Drawing onto an image in the panel's packed format: two 4-bit colour
+// indices per byte, left pixel in the high nibble, rows top to bottom.
+//
+use crate::color::Color;
+use crate::draw::{DISPLAY_BUFFER_SIZE, EPD_HEIGHT, EPD_WIDTH};
+use crate::font::{self, GLYPH_HEIGHT, GLYPH_SPACING, GLYPH_WIDTH};
+use crate::qr::QrCode;
+
+// Light modules around a QR code so scanners can find its edges
+const QR_QUIET_ZONE: u32 = 4;
+
+pub struct Framebuffer<'a> {
+    buffer: &'a mut [u8],
+}
+
+impl<'a> Framebuffer<'a> {
+    /// Wraps packed pixel data for the full panel. Extra trailing bytes are ignored.
+    pub fn new(buffer: &'a mut [u8]) -> Option<Self> {
+        let buffer = buffer.get_mut(..DISPLAY_BUFFER_SIZE)?;
+        Some(Self { buffer })
+    }
+
+    pub fn width(&self) -> u32 {
+        EPD_WIDTH
+    }
+
+    pub fn height(&self) -> u32 {
+        EPD_HEIGHT
+    }
+
+    /// The packed pixel data, ready to send to the panel
+    pub fn data(&self) -> &[u8] {
+        self.buffer
+    }
+
+    pub fn fill(&mut self, color: Color) {
+        let byte = (color.to_byte() << 4) | color.to_byte();
+        self.buffer.fill(byte);
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> Option<Color> {
+        if x >= EPD_WIDTH || y >= EPD_HEIGHT {
+            return None;
+        }
+        let byte = self.buffer[((y * EPD_WIDTH + x) / 2) as usize];
+        let index = if x % 2 == 0 { byte >> 4 } else { byte & 0x0f };
+        Color::from_byte(index)
+    }
+
+    /// Sets a pixel, ignoring coordinates off the panel
+    pub fn set_pixel(&mut self, x: u32, y: u32, color: Color) {
+        if x >= EPD_WIDTH || y >= EPD_HEIGHT {
+            return;
+        }
+        let byte = &mut self.buffer[((y * EPD_WIDTH + x) / 2) as usize];
+        *byte = if x % 2 == 0 {
+            (*byte & 0x0f) | (color.to_byte() << 4)
+        } else {
+            (*byte & 0xf0) | color.to_byte()
+        };
+    }
+
+    pub fn fill_rect(&mut self, x: u32, y: u32, width: u32, height: u32, color: Color) {
+        let x_end = (x + width).min(EPD_WIDTH);
+        let y_end = (y + height).min(EPD_HEIGHT);
+        for py in y..y_end {
+            for px in x..x_end {
+                self.set_pixel(px, py, color);
+            }
+        }
+    }
+
+    /// Average luminance of a rectangle, used to pick contrasting colours
+    pub fn average_luminance(&self, x: u32, y: u32, width: u32, height: u32) -> u8 {
+        let (mut total, mut count) = (0u32, 0u32);
+        for py in y..(y + height).min(EPD_HEIGHT) {
+            for px in x..(x + width).min(EPD_WIDTH) {
+                if let Some(color) = self.pixel(px, py) {
+                    total += color.luminance() as u32;
+                    count += 1;
+                }
+            }
+        }
+        if count == 0 {
+            0
+        } else {
+            (total / count) as u8
+        }
+    }
+
+    /// Draws text with its top left corner at (x, y), each font pixel `scale` pixels square
+    pub fn draw_text(&mut self, x: u32, y: u32, text: &str, scale: u32, color: Color) {
+        let advance = (GLYPH_WIDTH + GLYPH_SPACING) * scale;
+        for (i, c) in text.chars().enumerate() {
+            let glyph_x = x + i as u32 * advance;
+            for (column, bits) in font::glyph(c).iter().enumerate() {
+                for row in 0..GLYPH_HEIGHT {
+                    if bits & (1 << row) != 0 {
+                        let px = glyph_x + column as u32 * scale;
+                        self.fill_rect(px, y + row * scale, scale, scale, color);
+                    }
+                }
+            }
+        }
+    }
+    /// Draws a QR code, quiet zone included, with its top left corner at
+    /// (x, y) and each module `scale` pixels square
+    pub fn draw_qr(&mut self, x: u32, y: u32, code: &QrCode, scale: u32) {
+        let side = qr_side(code, scale);
+        self.fill_rect(x, y, side, side, Color::White);
+        let origin = QR_QUIET_ZONE * scale;
+        for my in 0..code.size() {
+            for mx in 0..code.size() {
+                if code.module(mx, my) {
+                    let (px, py) = (x + origin + mx * scale, y + origin + my * scale);
+                    self.fill_rect(px, py, scale, scale, Color::Black);
+                }
+            }
+        }
+    }
+}
+
+/// Width and height in pixels of a QR code drawn by `draw_qr`
+pub fn qr_side(code: &QrCode, scale: u32) -> u32 {
+    (code.size() + QR_QUIET_ZONE * 2) * scale
+}
@@ -0,0 +1,32 @@
+//
+// Identity of this frame: a stable ID derived from the factory MAC address,
+// and the name the user gave it, if any
+//
+use alloc::{format, string::String};
+use esp_hal::efuse::Efuse;
+
+use crate::config;
+
+// Sent with every image request, so the server can tell frames apart
+pub const ID_HEADER: &str = "X-Frame-Id";
+pub const NAME_HEADER: &str = "X-Frame-Name";
+pub const PANEL_HEADER: &str = "X-Frame-Panel";
+pub const SIZE_HEADER: &str = "X-Frame-Size";
+/// The panel model, for servers driving more than one kind
+pub const PANEL: &str = "epd7in3f";
+
+/// Short unique ID, e.g. "frame-a1b2c3", used for MQTT topics and discovery
+pub fn frame_id() -> String {
+    let mac = Efuse::mac_address();
+    format!("frame-{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5])
+}
+
+/// What to call the frame: its configured name, or its ID without one
+pub fn frame_name() -> String {
+    let name = config::get().name;
+    if name.is_empty() {
+        frame_id()
+    } else {
+        name
+    }
+}
@@ -0,0 +1,198 @@
+//
+// Status patterns on the on-board RGB LED. The refresh loop and friends say
+// what the frame is doing with `show`, and a task plays the matching
+// animation:
+//
+//   connecting    blue, breathing
+//   fetching      cyan, quick blink
+//   refreshing    green, slow breathing
+//   error         red, blinking the error's code (see `error_code`)
+//   provisioning  magenta, breathing
+//   updating      yellow, quick blink
+//
+// On battery everything but provisioning and updates goes dark after a while.
+//
+use alloc::boxed::Box;
+use defmt::Format;
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::{
+    rmt::{Channel as RmtChannel, TxChannel},
+    Blocking,
+};
+use smart_leds::{SmartLedsWriteAsync, RGB8};
+
+use crate::led::SmartLedsAdapterAsync;
+use crate::state::RefreshOutcome;
+
+// Animation step
+const FRAME: Duration = Duration::from_millis(20);
+// Timing of error codes: short blinks, then a pause before repeating
+const CODE_ON_MS: u64 = 200;
+const CODE_OFF_MS: u64 = 300;
+const CODE_PAUSE_MS: u64 = 1500;
+
+const BLUE: RGB8 = RGB8::new(0, 0, 255);
+const CYAN: RGB8 = RGB8::new(0, 255, 255);
+const GREEN: RGB8 = RGB8::new(0, 255, 0);
+const RED: RGB8 = RGB8::new(255, 0, 0);
+const MAGENTA: RGB8 = RGB8::new(255, 0, 255);
+const YELLOW: RGB8 = RGB8::new(255, 160, 0);
+const BLACK: RGB8 = RGB8::new(0, 0, 0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Status {
+    Off,
+    Connecting,
+    Fetching,
+    Refreshing,
+    Error(RefreshOutcome),
+    Provisioning,
+    Updating,
+}
+
+static STATUS: Channel<CriticalSectionRawMutex, Status, 4> = Channel::new();
+
+/// Tells the LED what the frame is doing now
+pub fn show(status: Status) {
+    // Only the latest status matters, so a backlog can go
+    if STATUS.try_send(status).is_err() {
+        STATUS.clear();
+        STATUS.try_send(status).ok();
+    }
+}
+
+/// Number of blinks for each kind of failure
+pub fn error_code(outcome: RefreshOutcome) -> u64 {
+    match outcome {
+        RefreshOutcome::Ok => 0,
+        RefreshOutcome::WifiNotConfigured => 1,
+        RefreshOutcome::WifiUnavailable => 2,
+        RefreshOutcome::ServerUnreachable => 3,
+        RefreshOutcome::BadImage => 4,
+        RefreshOutcome::PanelError => 5,
+        RefreshOutcome::LowBattery => 6,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Animation {
+    Dark,
+    /// Fades up and down once per period
+    Breathe(u64),
+    /// Equal on and off times
+    Blink(u64),
+    /// Blinks a number of times, then pauses
+    Code(u64),
+}
+
+impl Animation {
+    // Brightness out of 255, `ms` into the animation
+    fn level(self, ms: u64) -> u8 {
+        match self {
+            Animation::Dark => 0,
+            Animation::Breathe(period) => {
+                let phase = (ms % period) * 510 / period;
+                // The LED's gamma correction makes a straight ramp look smooth
+                (if phase <= 255 { phase } else { 510 - phase }) as u8
+            }
+            Animation::Blink(half) => match (ms / half) % 2 {
+                0 => 255,
+                _ => 0,
+            },
+            Animation::Code(count) => {
+                let blink = CODE_ON_MS + CODE_OFF_MS;
+                let position = ms % (count * blink + CODE_PAUSE_MS);
+                if position < count * blink && position % blink < CODE_ON_MS {
+                    255
+                } else {
+                    0
+                }
+            }
+        }
+    }
+}
+
+impl Status {
+    fn pattern(self) -> (RGB8, Animation) {
+        match self {
+            Status::Off => (BLACK, Animation::Dark),
+            Status::Connecting => (BLUE, Animation::Breathe(2000)),
+            Status::Fetching => (CYAN, Animation::Blink(150)),
+            Status::Refreshing => (GREEN, Animation::Breathe(3000)),
+            Status::Error(outcome) => (RED, Animation::Code(error_code(outcome).max(1))),
+            Status::Provisioning => (MAGENTA, Animation::Breathe(1500)),
+            Status::Updating => (YELLOW, Animation::Blink(100)),
+        }
+    }
+
+    // Worth the power even on battery
+    fn always_on(self) -> bool {
+        matches!(self, Status::Provisioning | Status::Updating)
+    }
+}
+
+fn scale(colour: RGB8, level: u8) -> RGB8 {
+    let channel = |c: u8| (c as u16 * level as u16 / 255) as u8;
+    RGB8::new(channel(colour.r), channel(colour.g), channel(colour.b))
+}
+
+/// Something that can show one colour
+// Only driven from the indicator task, so no Send bounds are needed
+#[allow(async_fn_in_trait)]
+pub trait Light {
+    async fn set(&mut self, colour: RGB8);
+}
+
+impl<TX: TxChannel, const BUFFER_SIZE: usize> Light for SmartLedsAdapterAsync<TX, BUFFER_SIZE> {
+    async fn set(&mut self, colour: RGB8) {
+        // Every LED on the line, so a strip lights up along with the board's
+        let leds = self.leds();
+        self.write(core::iter::repeat_n(colour, leds)).await.ok();
+    }
+}
+
+/// The status LED as `main` sets it up
+pub type StatusLed = SmartLedsAdapterAsync<RmtChannel<Blocking, 0>, { crate::LED_BUFFER_SIZE }>;
+
+/// Plays the status patterns and, if `auto_off` is set, only for that long
+/// after each change
+///
+/// The LED comes boxed so its pulse buffer stays out of the task arena.
+#[embassy_executor::task]
+pub async fn run(mut light: Box<StatusLed>, auto_off: Option<Duration>) {
+    play(light.as_mut(), auto_off).await
+}
+
+// Tasks can't be generic, so the patterns are played from here for any light
+async fn play<L: Light>(light: &mut L, auto_off: Option<Duration>) {
+    let mut status = Status::Off;
+    let mut since = Instant::now();
+    let mut shown = None;
+    loop {
+        let (colour, animation) = status.pattern();
+        let elapsed = since.elapsed();
+        let expired = !status.always_on() && auto_off.is_some_and(|limit| elapsed >= limit);
+        let animation = if expired { Animation::Dark } else { animation };
+        let level = animation.level(elapsed.as_millis());
+        let colour = scale(colour, level);
+        if shown != Some(colour) {
+            light.set(colour).await;
+            shown = Some(colour);
+        }
+
+        let next = match animation {
+            Animation::Dark => Some(STATUS.receive().await),
+            _ => match select(STATUS.receive(), Timer::after(FRAME)).await {
+                Either::First(next) => Some(next),
+                Either::Second(()) => None,
+            },
+        };
+        // Starting over on the same status would make breathing stutter
+        if let Some(next) = next.filter(|&next| next != status) {
+            status = next;
+            since = Instant::now();
+        }
+    }
+}
@@ -1,50 +1,72 @@
 //
 // !! Originally from https://github.com/esp-rs/esp-hal-community/blob/main/esp-hal-smartled/src/lib.rs
-// with small modification to allow compiling on esp32s3 with latest esp-hal beta.
+// with small modification to allow compiling on esp32s3 with latest esp-hal beta,
+// an async adapter that yields to other tasks while the LEDs are sent, with the
+// RMT memory refilled from an interrupt so chains of any length fit, and
+// options for other colour orders, chip timings, gamma and brightness, which
+// are in `pulse`.
 //
-use core::{fmt::Debug, slice::IterMut};
+use core::{cell::RefCell, future::poll_fn, marker::PhantomData, task::Poll};
+use critical_section::Mutex;
 use defmt::error;
+use embassy_sync::waitqueue::AtomicWaker;
 use esp_hal::{
     clock::Clocks,
     gpio::{interconnect::PeripheralOutput, Level},
-    rmt::{PulseCode, TxChannel, TxChannelConfig, TxChannelCreator},
+    handler,
+    rmt::{Event, TxChannel, TxChannelConfig, TxChannelCreator, TxChannelInternal},
 };
-use smart_leds_trait::{SmartLedsWrite, RGB8};
-
-const SK68XX_CODE_PERIOD: u32 = 1200;
-const SK68XX_T0H_NS: u32 = 320;
-const SK68XX_T0L_NS: u32 = SK68XX_CODE_PERIOD - SK68XX_T0H_NS;
-const SK68XX_T1H_NS: u32 = 640;
-const SK68XX_T1L_NS: u32 = SK68XX_CODE_PERIOD - SK68XX_T1H_NS;
-
-/// All types of errors that can happen during the conversion and transmission
-/// of LED commands
-#[derive(Debug)]
-pub enum LedAdapterError {
-    /// Raised in the event that the provided data container is not large enough
-    BufferSizeExceeded,
-    /// Raised if something goes wrong in the transmission,
-    TransmissionError,
-}
+use smart_leds_trait::{SmartLedsWrite, SmartLedsWriteAsync, RGB8};
+
+use crate::pulse::{encode, LedAdapterError, LedConfig, Timing};
+
+// RMT memory on the ESP32-S3, and the pulse codes in each channel's block of it
+const RMT_RAM_START: usize = 0x6001_6800;
+const CHANNEL_RAM_SIZE: usize = 48;
+// Blocks a transmit channel takes at most from the channels after it. Half of
+// them is refilled while the other half goes out, so more means more time for
+// the interrupt to come round.
+const MAX_MEMSIZE: usize = 4;
 
 /// Macro to allocate a buffer sized for a specific number of LEDs to be
-/// addressed.
+/// addressed, optionally with the number of colour channels per LED (3 if
+/// not given, 4 for RGBW).
 ///
 /// Attempting to use more LEDs that the buffer is configured for will result in
 /// an `LedAdapterError:BufferSizeExceeded` error.
 #[macro_export]
 macro_rules! smartLedBuffer {
-    ( $buffer_size: literal ) => {
+    ( $buffer_size: expr ) => {
+        $crate::smartLedBuffer!($buffer_size, 3)
+    };
+    ( $buffer_size: expr, $channels: expr ) => {
         // The size we're assigning here is calculated as following
         //  (
         //   Nr. of LEDs
-        //   * channels (r,g,b -> 3)
+        //   * channels (r,g,b -> 3, or r,g,b,w -> 4)
         //   * pulses per channel 8)
         //  ) + 1 additional pulse for the end delimiter
-        [0u32; $buffer_size * 24 + 1]
+        [0u32; $buffer_size * $channels * 8 + 1]
     };
 }
 
+// Pulse codes for a 0 and a 1 bit, assuming the RMT peripheral is set up to
+// use the APB clock
+fn bit_pulses(timing: &Timing) -> (u32, u32) {
+    let clocks = Clocks::get();
+    let src_clock = clocks.apb_clock.as_hz() / 1_000_000; // convert to the MHz value to simplify nanosecond calculations
+    timing.pulses(src_clock)
+}
+
+fn channel_config(memsize: u8) -> TxChannelConfig {
+    TxChannelConfig::default()
+        .with_clk_divider(1)
+        .with_idle_output_level(Level::Low)
+        .with_carrier_modulation(false)
+        .with_idle_output(true)
+        .with_memsize(memsize)
+}
+
 /// Adapter taking an RMT channel and a specific pin and providing RGB LED
 /// interaction functionality using the `smart-leds` crate
 pub struct SmartLedsAdapter<TX, const BUFFER_SIZE: usize>
@@ -53,6 +75,7 @@ where
 {
     channel: Option<TX>,
     rmt_buffer: [u32; BUFFER_SIZE],
+    config: LedConfig,
     pulses: (u32, u32),
 }
 
@@ -65,68 +88,34 @@ where
         channel: C,
         pin: impl PeripheralOutput<'d>,
         rmt_buffer: [u32; BUFFER_SIZE],
-    ) -> SmartLedsAdapter<TX, BUFFER_SIZE>
+    ) -> Result<SmartLedsAdapter<TX, BUFFER_SIZE>, LedAdapterError>
     where
         C: TxChannelCreator<'d, TX>,
     {
-        let config = TxChannelConfig::default()
-            .with_clk_divider(1)
-            .with_idle_output_level(Level::Low)
-            .with_carrier_modulation(false)
-            .with_idle_output(true);
-
-        let channel = channel.configure(pin, config).unwrap();
+        Self::with_config(channel, pin, rmt_buffer, LedConfig::default())
+    }
 
-        // Assume the RMT peripheral is set up to use the APB clock
-        let clocks = Clocks::get();
-        let src_clock = clocks.apb_clock.as_hz() / 1_000_000; // convert to the MHz value to simplify nanosecond calculations
+    /// Like `new`, for LEDs other than the GRB SK68XX on ESP32-S3 boards
+    pub fn with_config<C>(
+        channel: C,
+        pin: impl PeripheralOutput<'d>,
+        rmt_buffer: [u32; BUFFER_SIZE],
+        config: LedConfig,
+    ) -> Result<SmartLedsAdapter<TX, BUFFER_SIZE>, LedAdapterError>
+    where
+        C: TxChannelCreator<'d, TX>,
+    {
+        // Longer sequences are refilled while they're sent, so one block will do
+        let channel = channel
+            .configure(pin, channel_config(1))
+            .map_err(|_| LedAdapterError::ChannelConfig)?;
 
-        Self {
+        Ok(Self {
             channel: Some(channel),
             rmt_buffer,
-            pulses: (
-                PulseCode::new(
-                    Level::High,
-                    ((SK68XX_T0H_NS * src_clock) / 1000) as u16,
-                    Level::Low,
-                    ((SK68XX_T0L_NS * src_clock) / 1000) as u16,
-                ),
-                PulseCode::new(
-                    Level::High,
-                    ((SK68XX_T1H_NS * src_clock) / 1000) as u16,
-                    Level::Low,
-                    ((SK68XX_T1L_NS * src_clock) / 1000) as u16,
-                ),
-            ),
-        }
-    }
-
-    fn convert_rgb_to_pulse(
-        value: RGB8,
-        mut_iter: &mut IterMut<u32>,
-        pulses: (u32, u32),
-    ) -> Result<(), LedAdapterError> {
-        Self::convert_rgb_channel_to_pulses(value.g, mut_iter, pulses)?;
-        Self::convert_rgb_channel_to_pulses(value.r, mut_iter, pulses)?;
-        Self::convert_rgb_channel_to_pulses(value.b, mut_iter, pulses)?;
-
-        Ok(())
-    }
-
-    fn convert_rgb_channel_to_pulses(
-        channel_value: u8,
-        mut_iter: &mut IterMut<u32>,
-        pulses: (u32, u32),
-    ) -> Result<(), LedAdapterError> {
-        for position in [128, 64, 32, 16, 8, 4, 2, 1] {
-            *mut_iter.next().ok_or(LedAdapterError::BufferSizeExceeded)? =
-                match channel_value & position {
-                    0 => pulses.0,
-                    _ => pulses.1,
-                }
-        }
-
-        Ok(())
+            pulses: bit_pulses(&config.timing),
+            config,
+        })
     }
 }
 
@@ -145,22 +134,18 @@ where
         T: IntoIterator<Item = I>,
         I: Into<Self::Color>,
     {
-        // We always start from the beginning of the buffer
-        let mut seq_iter = self.rmt_buffer.iter_mut();
-
-        // Add all converted iterator items to the buffer.
-        // This will result in an `BufferSizeExceeded` error in case
-        // the iterator provides more elements than the buffer can take.
-        for item in iterator {
-            Self::convert_rgb_to_pulse(item.into(), &mut seq_iter, self.pulses)?;
-        }
-
-        // Finally, add an end element.
-        *seq_iter.next().ok_or(LedAdapterError::BufferSizeExceeded)? = PulseCode::empty();
+        let len = encode(&mut self.rmt_buffer, iterator, &self.config, self.pulses)?;
 
         // Perform the actual RMT operation. We use the u32 values here right away.
-        let channel = self.channel.take().unwrap();
-        match channel.transmit(&self.rmt_buffer).unwrap().wait() {
+        let channel = self
+            .channel
+            .take()
+            .ok_or(LedAdapterError::ChannelUnavailable)?;
+        let transaction = channel.transmit(&self.rmt_buffer[..len]).map_err(|e| {
+            error!("Error while starting LED sequence: {}", e);
+            LedAdapterError::TransmissionError
+        })?;
+        match transaction.wait() {
             Ok(chan) => {
                 self.channel = Some(chan);
                 Ok(())
@@ -173,3 +158,216 @@ where
         }
     }
 }
+
+// A sequence longer than the channel's RMT memory, being sent by
+// `SmartLedsAdapterAsync`. The interrupt handler copies the rest in half a
+// block at a time as the channel passes the middle and the end of its memory.
+struct Stream {
+    next: *const u32,
+    remaining: usize,
+    ram: *mut u32,
+    ram_index: usize,
+    half: usize,
+    finished: bool,
+    failed: bool,
+    service: fn(&mut Stream),
+}
+
+// Only touched inside critical sections, and the adapter stops the channel
+// before the codes `next` points into can go away
+unsafe impl Send for Stream {}
+
+static STREAM: Mutex<RefCell<Option<Stream>>> = Mutex::new(RefCell::new(None));
+static FINISHED: AtomicWaker = AtomicWaker::new();
+
+impl Stream {
+    fn refill(&mut self) {
+        let count = self.remaining.min(self.half);
+        for idx in 0..count {
+            unsafe {
+                let code = self.next.add(idx).read();
+                self.ram.add(self.ram_index + idx).write_volatile(code);
+            }
+        }
+        self.next = unsafe { self.next.add(count) };
+        self.remaining -= count;
+        // Alternates between the two halves; once nothing's left it's unused
+        self.ram_index = self.half - self.ram_index;
+    }
+
+    fn service<TX: TxChannelInternal>(&mut self) {
+        if TX::is_error() || TX::is_done() {
+            TX::unlisten_interrupt(Event::End | Event::Error | Event::Threshold);
+            // Ending early means a stop code in the middle, or a refill too late
+            self.failed = TX::is_error() || self.remaining > 0;
+            self.finished = true;
+        } else if TX::is_threshold_set() {
+            TX::reset_threshold_set();
+            self.refill();
+            if self.remaining == 0 {
+                TX::unlisten_interrupt(Event::Threshold);
+            }
+        }
+    }
+}
+
+/// RMT interrupt handler for [`SmartLedsAdapterAsync`], to be set on the RMT
+/// peripheral with `set_interrupt_handler` before sending anything
+#[handler]
+pub fn interrupt_handler() {
+    let finished = critical_section::with(|cs| match STREAM.borrow_ref_mut(cs).as_mut() {
+        Some(stream) => {
+            (stream.service)(stream);
+            stream.finished
+        }
+        None => false,
+    });
+    if finished {
+        FINISHED.wake();
+    }
+}
+
+// Stops the channel if a transmission is dropped before it's finished, so the
+// interrupt handler doesn't go on reading codes that are gone
+struct Transmission<TX: TxChannelInternal>(PhantomData<TX>);
+
+impl<TX: TxChannelInternal> Drop for Transmission<TX> {
+    fn drop(&mut self) {
+        let stream = critical_section::with(|cs| STREAM.replace(cs, None));
+        if !stream.is_some_and(|s| s.finished) {
+            TX::unlisten_interrupt(Event::End | Event::Error | Event::Threshold);
+            TX::stop();
+        }
+    }
+}
+
+/// Like [`SmartLedsAdapter`], but lets other tasks run while the LEDs are
+/// sent rather than busy waiting
+///
+/// The channel takes as many blocks of RMT memory as the LEDs need from the
+/// channels after it, up to four. Longer chains are topped up from
+/// [`interrupt_handler`] as they're sent, so the RMT peripheral has to stay
+/// in blocking mode with that handler set.
+pub struct SmartLedsAdapterAsync<TX, const BUFFER_SIZE: usize>
+where
+    TX: TxChannel,
+{
+    channel: TX,
+    rmt_buffer: [u32; BUFFER_SIZE],
+    config: LedConfig,
+    pulses: (u32, u32),
+}
+
+impl<'d, TX, const BUFFER_SIZE: usize> SmartLedsAdapterAsync<TX, BUFFER_SIZE>
+where
+    TX: TxChannel,
+{
+    /// Create a new adapter object that drives the pin using the RMT channel.
+    pub fn new<C>(
+        channel: C,
+        pin: impl PeripheralOutput<'d>,
+        rmt_buffer: [u32; BUFFER_SIZE],
+    ) -> Result<SmartLedsAdapterAsync<TX, BUFFER_SIZE>, LedAdapterError>
+    where
+        C: TxChannelCreator<'d, TX>,
+    {
+        Self::with_config(channel, pin, rmt_buffer, LedConfig::default())
+    }
+
+    /// Like `new`, for LEDs other than the GRB SK68XX on ESP32-S3 boards
+    pub fn with_config<C>(
+        channel: C,
+        pin: impl PeripheralOutput<'d>,
+        rmt_buffer: [u32; BUFFER_SIZE],
+        config: LedConfig,
+    ) -> Result<SmartLedsAdapterAsync<TX, BUFFER_SIZE>, LedAdapterError>
+    where
+        C: TxChannelCreator<'d, TX>,
+    {
+        let pulses = config.pulse_count();
+        if pulses > BUFFER_SIZE {
+            return Err(LedAdapterError::BufferSizeExceeded);
+        }
+        let memsize = pulses.div_ceil(CHANNEL_RAM_SIZE).min(MAX_MEMSIZE) as u8;
+        let channel = channel
+            .configure(pin, channel_config(memsize))
+            .map_err(|_| LedAdapterError::ChannelConfig)?;
+
+        Ok(Self {
+            channel,
+            rmt_buffer,
+            pulses: bit_pulses(&config.timing),
+            config,
+        })
+    }
+
+    /// Number of LEDs on the line
+    pub fn leds(&self) -> usize {
+        self.config.count
+    }
+}
+
+impl<TX, const BUFFER_SIZE: usize> SmartLedsWriteAsync for SmartLedsAdapterAsync<TX, BUFFER_SIZE>
+where
+    TX: TxChannel,
+{
+    type Error = LedAdapterError;
+    type Color = RGB8;
+
+    /// Convert all RGB8 items of the iterator to the RMT format and send
+    /// them, waiting for the transmission to finish without blocking.
+    async fn write<T, I>(&mut self, iterator: T) -> Result<(), Self::Error>
+    where
+        T: IntoIterator<Item = I>,
+        I: Into<Self::Color>,
+    {
+        let len = encode(&mut self.rmt_buffer, iterator, &self.config, self.pulses)?;
+        let data = &self.rmt_buffer[..len];
+
+        // The first memory's worth goes in now, the rest from the interrupt
+        let memsize = CHANNEL_RAM_SIZE * TX::memsize() as usize;
+        let sent = len.min(memsize);
+        let ram = (RMT_RAM_START + TX::CHANNEL as usize * CHANNEL_RAM_SIZE * 4) as *mut u32;
+        critical_section::with(|cs| {
+            STREAM.replace(
+                cs,
+                Some(Stream {
+                    next: data[sent..].as_ptr(),
+                    remaining: len - sent,
+                    ram,
+                    ram_index: 0,
+                    half: memsize / 2,
+                    finished: false,
+                    failed: false,
+                    service: Stream::service::<TX>,
+                }),
+            )
+        });
+        let _transmission = Transmission::<TX>(PhantomData);
+
+        TX::clear_interrupts();
+        if len > sent {
+            TX::listen_interrupt(Event::End | Event::Error | Event::Threshold);
+        } else {
+            TX::listen_interrupt(Event::End | Event::Error);
+        }
+        TX::send_raw(data, false, 0).map_err(|e| {
+            error!("Error while starting LED sequence: {}", e);
+            LedAdapterError::TransmissionError
+        })?;
+
+        let failed = poll_fn(|cx| {
+            FINISHED.register(cx.waker());
+            critical_section::with(|cs| match STREAM.borrow_ref(cs).as_ref() {
+                Some(stream) if stream.finished => Poll::Ready(stream.failed),
+                _ => Poll::Pending,
+            })
+        })
+        .await;
+        if failed {
+            error!("Error while transmitting LED sequence");
+            return Err(LedAdapterError::TransmissionError);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+//
+// Ambient light, so the frame can hold off refreshing while nobody could see
+// the picture. Either an I2C sensor (BH1750 or VEML7700) or a light-dependent
+// resistor on an ADC1 pin, wired so the voltage rises with the light.
+//
+// Levels are lux from a sensor and millivolts from an LDR. The room counts as
+// dark below the threshold, and as lit again only once twice as bright, so a
+// level near the threshold doesn't flip back and forth.
+//
+use alloc::boxed::Box;
+use defmt::{info, warn, Format};
+use embassy_time::{Duration, Timer};
+use esp_hal::{
+    i2c::master::{Error, I2c},
+    Async,
+};
+
+use crate::analog::{Inputs, Sampler};
+
+const BH1750_ADDRESS: u8 = 0x23;
+const BH1750_POWER_ON: u8 = 0x01;
+// A single reading at 1 lx resolution, after which the sensor powers down
+const BH1750_ONE_TIME_HIGH_RES: u8 = 0x20;
+// Longest the reading takes
+const BH1750_MEASUREMENT: Duration = Duration::from_millis(180);
+
+const VEML7700_ADDRESS: u8 = 0x10;
+const VEML7700_CONFIG: u8 = 0x00;
+const VEML7700_ALS: u8 = 0x04;
+// Gain 1 and 100 ms integration, powered on or shut down
+const VEML7700_ON: u16 = 0x0000;
+const VEML7700_SHUT_DOWN: u16 = 0x0001;
+// Integration time with some margin
+const VEML7700_MEASUREMENT: Duration = Duration::from_millis(120);
+
+// LDR samples averaged per reading
+const SAMPLES: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Kind {
+    Bh1750,
+    Veml7700,
+    Ldr,
+}
+
+impl Kind {
+    /// Looks up a kind of sensor by name, e.g. "bh1750"
+    pub fn by_name(name: &str) -> Option<Kind> {
+        match name {
+            "bh1750" => Some(Kind::Bh1750),
+            "veml7700" => Some(Kind::Veml7700),
+            "ldr" => Some(Kind::Ldr),
+            _ => None,
+        }
+    }
+
+    /// Level below which a room is dark, for when none is configured
+    pub fn default_threshold(self) -> u32 {
+        match self {
+            // A dim lamp is 10-50 lx
+            Kind::Bh1750 | Kind::Veml7700 => 5,
+            Kind::Ldr => 200,
+        }
+    }
+}
+
+pub enum Sensor {
+    Bh1750(I2c<'static, Async>),
+    Veml7700(I2c<'static, Async>),
+    Ldr(Box<dyn Sampler>),
+}
+
+/// A light-dependent resistor on ADC1 GPIO `pin` (1-8), unless the pin is
+/// already taken
+pub fn ldr(inputs: &mut Inputs, pin: u8) -> Option<Sensor> {
+    inputs.enable(pin).map(Sensor::Ldr)
+}
+
+async fn read_bh1750(i2c: &mut I2c<'static, Async>) -> Result<u32, Error> {
+    i2c.write_async(BH1750_ADDRESS, &[BH1750_POWER_ON]).await?;
+    i2c.write_async(BH1750_ADDRESS, &[BH1750_ONE_TIME_HIGH_RES])
+        .await?;
+    Timer::after(BH1750_MEASUREMENT).await;
+    let mut raw = [0; 2];
+    i2c.read_async(BH1750_ADDRESS, &mut raw).await?;
+    // 1.2 counts per lux
+    Ok(u16::from_be_bytes(raw) as u32 * 10 / 12)
+}
+
+async fn read_veml7700(i2c: &mut I2c<'static, Async>) -> Result<u32, Error> {
+    let [low, high] = VEML7700_ON.to_le_bytes();
+    i2c.write_async(VEML7700_ADDRESS, &[VEML7700_CONFIG, low, high])
+        .await?;
+    Timer::after(VEML7700_MEASUREMENT).await;
+    let mut raw = [0; 2];
+    let result = i2c
+        .write_read_async(VEML7700_ADDRESS, &[VEML7700_ALS], &mut raw)
+        .await;
+    // Draws next to nothing shut down, so it's left that way between readings
+    let [low, high] = VEML7700_SHUT_DOWN.to_le_bytes();
+    i2c.write_async(VEML7700_ADDRESS, &[VEML7700_CONFIG, low, high])
+        .await
+        .ok();
+    result?;
+    // 0.0576 lux per count at this gain and integration time
+    Ok(u16::from_le_bytes(raw) as u32 * 576 / 10_000)
+}
+
+impl Sensor {
+    /// Light level now: lux from a sensor, millivolts from an LDR
+    pub async fn read(&mut self) -> Option<u32> {
+        let result = match self {
+            Sensor::Bh1750(i2c) => read_bh1750(i2c).await,
+            Sensor::Veml7700(i2c) => read_veml7700(i2c).await,
+            Sensor::Ldr(sampler) => {
+                let mut total = 0;
+                for _ in 0..SAMPLES {
+                    total += sampler.sample()? as u32;
+                }
+                return Some(total / SAMPLES);
+            }
+        };
+        result
+            .map_err(|e| warn!("Failed to read light sensor: {:?}", e))
+            .ok()
+    }
+}
+
+/// Whether the room is dark, with hysteresis
+pub struct Darkness {
+    sensor: Sensor,
+    threshold: u32,
+    dark: bool,
+}
+
+impl Darkness {
+    pub fn new(sensor: Sensor, threshold: u32) -> Self {
+        Darkness {
+            sensor,
+            threshold,
+            dark: false,
+        }
+    }
+
+    /// Reads the sensor and says whether it's too dark to bother refreshing
+    pub async fn is_dark(&mut self) -> bool {
+        // Better to refresh in the dark than never, if the sensor has gone
+        let Some(level) = self.sensor.read().await else {
+            self.dark = false;
+            return false;
+        };
+        let dark = if self.dark {
+            level < self.threshold * 2
+        } else {
+            level < self.threshold
+        };
+        if dark != self.dark {
+            info!(
+                "Light level {}, now {}",
+                level,
+                if dark { "dark" } else { "lit" }
+            );
+        }
+        self.dark = dark;
+        dark
+    }
+}
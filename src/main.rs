@@ -13,48 +13,195 @@
 
 use defmt::info;
 use embassy_executor::Spawner;
-use embassy_time::{Duration, Timer};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 
 extern crate alloc;
-use panic_rtt_target as _;
 
 // This creates a default app-descriptor required by the esp-idf bootloader.
 // For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
-// esp_bootloader_esp_idf::esp_app_desc!();
+esp_bootloader_esp_idf::esp_app_desc!();
 
+mod analog;
+mod battery;
+mod button;
+mod cache;
+mod clock;
+mod config;
+mod console;
 mod draw;
+mod fetch;
+mod font;
+mod framebuffer;
+mod identity;
+mod indicator;
 mod led;
+mod light;
+mod maintenance;
+mod mdns;
+mod motion;
+mod ota;
+mod overlay;
+mod pattern;
+mod provision;
+mod qr;
+mod remote;
+mod server;
+mod sntp;
+mod state;
+mod status;
+mod store;
+mod telemetry;
+mod temperature;
 mod wifi;
-use alloc::vec::Vec;
+
+// Hardware-free, and tested on the host
+use photo_frame_core::{color, commands, dns, mqtt, pulse, records, schedule, sequence, tz};
+
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
 
 use defmt::{error, println, warn};
-use embassy_net::{
-    dns::DnsSocket,
-    tcp::client::{TcpClient, TcpClientState},
-    StackResources,
-};
+use embassy_futures::select::{select, Either};
+use embassy_net::{Stack, StackResources};
+use embassy_sync::mutex::Mutex;
 use esp_hal::{
     clock::CpuClock,
     dma::{DmaRxBuf, DmaTxBuf},
     dma_buffers,
-    gpio::{Input, InputConfig, Level, Output, OutputConfig},
+    gpio::{Input, InputConfig, Level, Output, OutputConfig, Pin, Pull},
+    i2c::master::I2c,
     psram::PsramConfig,
     rmt::Rmt,
+    rtc_cntl::Rtc,
     spi::{
         master::{Config, Spi},
         Mode,
     },
     time::Rate,
     timer::{systimer::SystemTimer, timg::TimerGroup},
+    usb_serial_jtag::UsbSerialJtag,
 };
 
-use draw::EPD7in3f;
+use battery::Battery;
+use cache::{CachedImage, ImageCache};
+use color::Color;
+use draw::{EPD7in3f, SharedDisplay};
 use esp_wifi::{config::PowerSaveMode, init, EspWifiController};
-use led::SmartLedsAdapter;
-use reqwless::{client::HttpClient, request::Method};
-use smart_leds::{SmartLedsWrite, RGB8};
+use fetch::Fetched;
+use framebuffer::Framebuffer;
+use led::SmartLedsAdapterAsync;
+use light::Darkness;
+use maintenance::{CleanSchedule, Maintenance};
+use overlay::{Corner, Overlay, OverlayText};
+use pulse::{ColorOrder, LedConfig, Timing};
+use schedule::{DailyWindow, Schedule};
+use sequence::InitSequence;
+use server::ConfigAccess;
+use state::{Command, RefreshOutcome};
+use status::{DeviceInfo, FailureTracker, RefreshFailure, StatusScreen};
+use telemetry::Telemetry;
+use temperature::{Temperature, TemperatureSource};
+use tz::TimeZone;
 use wifi::{connection, net_task};
 
+// Image server; set it empty to look for one advertising `_photoframe._tcp` over mDNS
+const SERVER_URL: &str = match option_env!("FRAME_SERVER_URL") {
+    Some(url) => url,
+    None => "http://192.168.68.66:3005/recent",
+};
+// Name to tell this frame apart by, sent with each request; can be changed from the setup page
+const FRAME_NAME: &str = match option_env!("FRAME_NAME") {
+    Some(name) => name,
+    None => "",
+};
+const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+// Panel init sequence by name until one is picked on the setup page; "default" if unset
+const INIT_SEQUENCE: Option<&str> = option_env!("EPD_INIT_SEQUENCE");
+// How long to wait for Wi-Fi before giving up on this refresh
+const WIFI_TIMEOUT: Duration = Duration::from_secs(60);
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const DEEP_CLEAN_INTERVAL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+// Refresh times, ';' separated "HH:MM" or cron expressions; empty for every REFRESH_INTERVAL
+const SCHEDULE: &str = match option_env!("FRAME_SCHEDULE") {
+    Some(schedule) => schedule,
+    None => "",
+};
+// Local time window to stay asleep, e.g. "22:00-07:00"
+const QUIET_HOURS: Option<&str> = option_env!("FRAME_QUIET_HOURS");
+// Overlay drawn on each image: "date", "time", "datetime", "refreshed" or custom text
+const OVERLAY: Option<&str> = option_env!("FRAME_OVERLAY");
+// "top-left", "top-right", "bottom-left" or "bottom-right"
+const OVERLAY_CORNER: Option<&str> = option_env!("FRAME_OVERLAY_CORNER");
+// Text colour from the panel palette; picked for contrast if unset
+const OVERLAY_COLOR: Option<&str> = option_env!("FRAME_OVERLAY_COLOR");
+// Where to put the QR code for a link sent with a photo
+const LINK_CORNER: Option<&str> = option_env!("FRAME_LINK_CORNER");
+// Times the HTTP server listens, ';' separated "HH:MM-HH:MM"; always if unset
+const AWAKE_WINDOWS: &str = match option_env!("FRAME_AWAKE_WINDOWS") {
+    Some(windows) => windows,
+    None => "",
+};
+// Token a settings change over the LAN must carry; they're refused if unset
+const CONFIG_TOKEN: Option<&str> = option_env!("FRAME_CONFIG_TOKEN");
+// ADC1 GPIO (1-8) wired to the battery through a divider; no monitoring if unset
+const BATTERY_PIN: Option<&str> = option_env!("FRAME_BATTERY_PIN");
+// Battery voltage over the voltage at the pin; 2 for two equal resistors if unset
+const BATTERY_DIVIDER: Option<&str> = option_env!("FRAME_BATTERY_DIVIDER");
+// "bh1750" or "veml7700" on I2C (SDA GPIO41, SCL GPIO42), or "ldr" on LIGHT_PIN;
+// refreshes whatever the light if unset
+const LIGHT_SENSOR: Option<&str> = option_env!("FRAME_LIGHT_SENSOR");
+// ADC1 GPIO (1-8) wired to an LDR, other than the battery's
+const LIGHT_PIN: Option<&str> = option_env!("FRAME_LIGHT_PIN");
+// Refreshes wait while the light is below this: lux from a sensor, mV at the pin from an LDR
+const DARK_LEVEL: Option<&str> = option_env!("FRAME_DARK_LEVEL");
+// How often to look for the lights coming on while a refresh waits
+const LIGHT_POLL: Duration = Duration::from_secs(60);
+// Spare RTC GPIO (1-8, 15-18 or 21) wired to a PIR sensor's output; no motion sensing if unset
+const MOTION_PIN: Option<&str> = option_env!("FRAME_MOTION_PIN");
+// Least seconds between refreshes for motion, so people coming and going don't keep
+// the panel busy; 15 minutes if unset
+const MOTION_INTERVAL: Option<&str> = option_env!("FRAME_MOTION_INTERVAL");
+// How often to look again at a PIR sensor still high when the frame went to sleep
+const MOTION_POLL: Duration = Duration::from_secs(60);
+// Spare RTC GPIO (15-18 or 21) with a second button to ground; just BOOT if unset.
+// With this or MOTION_PIN set the frame deep sleeps between refreshes, so it can't
+// be reached over the network until the next one.
+const BUTTON_PIN: Option<&str> = option_env!("FRAME_BUTTON_PIN");
+// LEDs on the LED pin: the on-board one, then any edge lighting strip chained after
+// it; 1 if unset
+const LED_COUNT: Option<&str> = option_env!("FRAME_LED_COUNT");
+// Most LEDs the pulse buffer has room for, with a white channel
+const MAX_LED_COUNT: usize = 60;
+// Pulse codes for that many, 8 to a colour channel, and the end marker
+const LED_BUFFER_SIZE: usize = MAX_LED_COUNT * 4 * 8 + 1;
+// "rgb", "grb", "rgbw" or "grbw"; GRB like the on-board LED if unset
+const LED_ORDER: Option<&str> = option_env!("FRAME_LED_ORDER");
+// "ws2812b", "sk6812" or "ws2811"; SK6812 like the on-board LED if unset
+const LED_TIMING: Option<&str> = option_env!("FRAME_LED_TIMING");
+// Cap on the LEDs' brightness, out of 255
+const LED_BRIGHTNESS: Option<&str> = option_env!("FRAME_LED_BRIGHTNESS");
+// On battery the status LED goes dark this long after each change
+const LED_AUTO_OFF: Duration = Duration::from_secs(30);
+// Recent images kept in PSRAM for stepping back through with the button
+const IMAGE_CACHE_SIZE: usize = 4;
+// How often to look again at whether the setup access point is still up
+const PROVISIONING_POLL: Duration = Duration::from_secs(10);
+// Below this charge refreshes are spaced BATTERY_STRETCH times further apart
+const BATTERY_LOW_PERCENT: u8 = 20;
+const BATTERY_STRETCH: u32 = 4;
+// Below this the frame shows a low-battery screen and stops refreshing
+const BATTERY_CUTOFF_PERCENT: u8 = 5;
+// Charge above the cutoff needed before refreshing resumes
+const BATTERY_RECOVERY_MARGIN: u8 = 5;
+// MQTT broker as "host" or "host:port"; no MQTT if unset
+const MQTT_BROKER: Option<&str> = option_env!("FRAME_MQTT_BROKER");
+const MQTT_USERNAME: Option<&str> = option_env!("FRAME_MQTT_USERNAME");
+const MQTT_PASSWORD: Option<&str> = option_env!("FRAME_MQTT_PASSWORD");
+// POSIX TZ string for local time, e.g. "GMT0BST,M3.5.0/1,M10.5.0"
+const TIME_ZONE: &str = match option_env!("FRAME_TZ") {
+    Some(tz) => tz,
+    None => "UTC0",
+};
+
 macro_rules! mk_static {
     ($t:ty,$val:expr) => {{
         static STATIC_CELL: static_cell::StaticCell<$t> = static_cell::StaticCell::new();
@@ -87,6 +234,9 @@ async fn main(spawner: Spawner) {
             esp_alloc::MemoryCapability::External.into(),
         ));
     }
+    // A freshly updated firmware has to connect and refresh once before it's kept;
+    // its trial starts before anything that could hang or panic
+    let mut on_trial = ota::start_trial();
 
     info!("Starting Wifi");
     let timg0 = TimerGroup::new(p.TIMG0);
@@ -100,6 +250,7 @@ async fn main(spawner: Spawner) {
     let (mut wifi_controller, interfaces) =
         esp_wifi::wifi::new(esp_wifi_ctrl, p.WIFI).expect("Failed to initialize WIFI controller");
     let wifi_interface = interfaces.sta;
+    let ap_interface = interfaces.ap;
     wifi_controller
         .set_mode(esp_wifi::wifi::WifiMode::Sta)
         .expect("Failed to set wifi mode");
@@ -107,6 +258,12 @@ async fn main(spawner: Spawner) {
         .set_power_saving(PowerSaveMode::Minimum)
         .expect("Failed to set power mode");
 
+    let time_zone = TimeZone::parse(TIME_ZONE).unwrap_or_else(|_| {
+        warn!("Invalid timezone {}, using UTC", TIME_ZONE);
+        TimeZone::UTC
+    });
+    clock::init(Rtc::new(p.LPWR), time_zone);
+
     let systimer = SystemTimer::new(p.SYSTIMER);
     esp_hal_embassy::init(systimer.alarm0);
 
@@ -119,12 +276,29 @@ async fn main(spawner: Spawner) {
     //     .ok();
 
     // Setup onBoard LED
-    let rmt = Rmt::new(p.RMT, Rate::from_mhz(80)).unwrap();
-    let rmt_buffer = smartLedBuffer!(1);
-    let mut led = SmartLedsAdapter::new(rmt.channel0, p.GPIO48, rmt_buffer);
-
-    // Make LED Blue
-    led.write([RGB8::new(0, 0, 0)]).ok();
+    // Blocking, as the LED's own interrupt handler keeps long chains fed
+    let mut rmt = Rmt::new(p.RMT, Rate::from_mhz(80)).unwrap();
+    rmt.set_interrupt_handler(led::interrupt_handler);
+    let mut led_config = LedConfig {
+        gamma: Some(&pulse::GAMMA_2_8),
+        brightness: LED_BRIGHTNESS.and_then(|b| b.parse().ok()).unwrap_or(32),
+        count: LED_COUNT.and_then(|n| n.parse().ok()).unwrap_or(1),
+        ..LedConfig::default()
+    };
+    if let Some(name) = LED_ORDER {
+        match ColorOrder::by_name(name) {
+            Some(order) => led_config.order = order,
+            None => warn!("Unknown LED colour order {}, using GRB", name),
+        }
+    }
+    if let Some(name) = LED_TIMING {
+        match Timing::by_name(name) {
+            Some(timing) => led_config.timing = timing,
+            None => warn!("Unknown LED timing {}, using SK6812", name),
+        }
+    }
+    let rmt_buffer = [0u32; LED_BUFFER_SIZE];
+    let led = SmartLedsAdapterAsync::with_config(rmt.channel0, p.GPIO48, rmt_buffer, led_config);
 
     let stats: esp_alloc::HeapStats = esp_alloc::HEAP.stats();
     // HeapStats implements the Display and defmt::Format traits, so you can pretty-print the heap stats.
@@ -141,7 +315,9 @@ async fn main(spawner: Spawner) {
     let spi = Spi::new(p.SPI2, Config::default().with_mode(Mode::_0))
         .unwrap()
         .with_sck(p.GPIO12)
-        .with_mosi(p.GPIO11)
+        // The panel's SDA is bidirectional: SIO0 drives it like MOSI for writes,
+        // and is read from in half-duplex for the temperature sensor
+        .with_sio0(p.GPIO11)
         .with_cs(p.GPIO10)
         .with_dma(dma_channel)
         .with_buffers(dma_rx_buf, dma_tx_buf)
@@ -151,6 +327,234 @@ async fn main(spawner: Spawner) {
     let rst = Output::new(p.GPIO13, Level::High, OutputConfig::default());
     let busy = Input::new(p.GPIO9, InputConfig::default());
     let mut display = EPD7in3f::new(spi, dc, rst, busy);
+    // Boards that can't read back the panel's sensor can pin the waveform temperature instead
+    if let Some(celsius) = option_env!("EPD_TEMPERATURE_C").and_then(|c| c.parse().ok()) {
+        display
+            .set_temperature_source(TemperatureSource::Fixed(Temperature::from_celsius(celsius)));
+    }
+    // Also used by the HTTP server to show pushed images
+    let shared_display = &*mk_static!(SharedDisplay, Mutex::new(display));
+
+    let mut analog = analog::Inputs::new(analog::Pins {
+        gpio1: p.GPIO1,
+        gpio2: p.GPIO2,
+        gpio3: p.GPIO3,
+        gpio4: p.GPIO4,
+        gpio5: p.GPIO5,
+        gpio6: p.GPIO6,
+        gpio7: p.GPIO7,
+        gpio8: p.GPIO8,
+    });
+    let divider = BATTERY_DIVIDER.and_then(|d| d.parse().ok()).unwrap_or(2.0);
+    let mut battery = BATTERY_PIN.and_then(|pin| {
+        let sampler = pin.parse().ok().and_then(|pin| analog.enable(pin));
+        if sampler.is_none() {
+            warn!("Battery pin must be an unused ADC1 GPIO (1-8)");
+        }
+        sampler.map(|sampler| Battery::new(sampler, divider))
+    });
+
+    let light_kind = LIGHT_SENSOR.and_then(|name| {
+        let kind = light::Kind::by_name(name);
+        if kind.is_none() {
+            warn!(
+                "Unknown light sensor {}, refreshing whatever the light",
+                name
+            );
+        }
+        kind
+    });
+    let i2c = || {
+        I2c::new(p.I2C0, esp_hal::i2c::master::Config::default())
+            .map(|i2c| i2c.with_sda(p.GPIO41).with_scl(p.GPIO42).into_async())
+            .ok()
+    };
+    let light_sensor = match light_kind {
+        None => None,
+        Some(light::Kind::Bh1750) => i2c().map(light::Sensor::Bh1750),
+        Some(light::Kind::Veml7700) => i2c().map(light::Sensor::Veml7700),
+        Some(light::Kind::Ldr) => LIGHT_PIN
+            .and_then(|pin| pin.parse().ok())
+            .and_then(|pin| light::ldr(&mut analog, pin)),
+    };
+    if light_kind == Some(light::Kind::Ldr) && light_sensor.is_none() {
+        warn!("Light pin must be an ADC1 GPIO (1-8) other than the battery's");
+    }
+    let mut darkness = light_kind.zip(light_sensor).map(|(kind, sensor)| {
+        let threshold = DARK_LEVEL
+            .and_then(|level| level.parse().ok())
+            .unwrap_or(kind.default_threshold());
+        Darkness::new(sensor, threshold)
+    });
+    analog.start(p.ADC1);
+
+    let auto_off = battery.is_some().then_some(LED_AUTO_OFF);
+    match led {
+        Ok(led) => {
+            spawner.spawn(indicator::run(Box::new(led), auto_off)).ok();
+        }
+        Err(e) => warn!("No status LED: {:?}", e),
+    }
+
+    let usb = UsbSerialJtag::new(p.USB_DEVICE).into_async();
+    spawner
+        .spawn(console::console(usb, shared_display, &PSRAM_ALLOCATOR))
+        .ok();
+
+    let mut button_pins = vec![button::BOOT_PIN];
+    spawner
+        .spawn(button::button(Input::new(
+            p.GPIO0,
+            InputConfig::default().with_pull(Pull::Up),
+        )))
+        .ok();
+    let extra_button = match BUTTON_PIN.map(str::parse) {
+        None => None,
+        Some(Ok(15)) => Some(p.GPIO15.degrade()),
+        Some(Ok(16)) => Some(p.GPIO16.degrade()),
+        Some(Ok(17)) => Some(p.GPIO17.degrade()),
+        Some(Ok(18)) => Some(p.GPIO18.degrade()),
+        Some(Ok(21)) => Some(p.GPIO21.degrade()),
+        Some(_) => {
+            warn!("Button pin must be a spare RTC GPIO (15-18 or 21)");
+            None
+        }
+    };
+    if let Some(pin) = extra_button {
+        button_pins.push(pin.number());
+        spawner
+            .spawn(button::button(Input::new(
+                pin,
+                InputConfig::default().with_pull(Pull::Up),
+            )))
+            .ok();
+    }
+    if button::woke_frame() {
+        info!("Woken by a button");
+    }
+
+    let taken =
+        [BATTERY_PIN, LIGHT_PIN, BUTTON_PIN].map(|pin| pin.and_then(|pin| pin.parse().ok()));
+    let motion_input = MOTION_PIN.and_then(|pin| {
+        let pin = pin
+            .parse()
+            .ok()
+            .filter(|pin| !taken.contains(&Some(*pin)))?;
+        motion::input(pin).map(|input| (pin, input))
+    });
+    if MOTION_PIN.is_some() && motion_input.is_none() {
+        warn!("Motion pin must be a spare RTC GPIO (1-8, 15-18 or 21)");
+    }
+    let motion_pin = motion_input.map(|(pin, input)| {
+        spawner.spawn(motion::motion(input)).ok();
+        pin
+    });
+    let motion_interval = Duration::from_secs(
+        MOTION_INTERVAL
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(15 * 60),
+    );
+    if motion::woke_frame() {
+        info!("Woken by motion");
+    }
+
+    // Weekly by default, or every N refreshes if configured at build time
+    let clean_schedule =
+        match option_env!("DEEP_CLEAN_EVERY_REFRESHES").and_then(|n| n.parse().ok()) {
+            Some(refreshes) => CleanSchedule::EveryRefreshes(refreshes),
+            None => CleanSchedule::Every(DEEP_CLEAN_INTERVAL),
+        };
+    let mut maintenance = Maintenance::new(clean_schedule);
+
+    // Back from deep sleep between refreshes; to sleep again unless the refresh
+    // is due, or wanted sooner by a button or by motion long enough after the last
+    if let Some(slept) = clock::take_slept() {
+        maintenance.record_elapsed(slept.asleep);
+        // The timer can wake the frame a little early
+        let due = slept.until_due < Duration::from_secs(1);
+        let motion = motion::woke_frame() && slept.since_refresh >= motion_interval;
+        if !due && !motion && !button::woke_frame() {
+            info!("Too soon to refresh, sleeping again");
+            sleep_until_due(
+                slept.since_refresh,
+                slept.until_due,
+                motion_interval,
+                &button_pins,
+                motion_pin,
+            );
+        }
+        // Held in the dark as when awake, but a flat cell is left to rest
+        let dark = match darkness.as_mut() {
+            Some(darkness) if !button::woke_frame() && !battery::is_resting() => {
+                darkness.is_dark().await
+            }
+            _ => false,
+        };
+        if dark {
+            info!("Too dark to refresh, sleeping again");
+            sleep_until_due(
+                slept.since_refresh,
+                LIGHT_POLL,
+                motion_interval,
+                &button_pins,
+                motion_pin,
+            );
+        }
+    }
+
+    config::init(config::Config {
+        server_url: String::from(SERVER_URL),
+        refresh_interval: REFRESH_INTERVAL,
+        wifi_ssid: String::from(wifi::SSID),
+        wifi_password: String::from(wifi::PASSWORD),
+        name: String::from(FRAME_NAME),
+        panel: String::from(INIT_SEQUENCE.unwrap_or("")),
+    });
+    // Set before the first refresh inits the panel
+    let panel = config::get().panel;
+    if !panel.is_empty() {
+        match InitSequence::by_name(&panel) {
+            Some(sequence) => shared_display.lock().await.set_init_sequence(sequence),
+            None => warn!("Unknown init sequence {}, using default", panel.as_str()),
+        }
+    }
+
+    let mut schedule = Schedule::new(REFRESH_INTERVAL)
+        .with_entries(SCHEDULE)
+        .unwrap_or_else(|_| {
+            warn!("Invalid schedule {}, refreshing every interval", SCHEDULE);
+            Schedule::new(REFRESH_INTERVAL)
+        });
+    if let Some(window) = QUIET_HOURS {
+        match DailyWindow::parse(window) {
+            Ok(quiet_hours) => schedule = schedule.with_quiet_hours(quiet_hours),
+            Err(_) => warn!("Invalid quiet hours {}", window),
+        }
+    }
+
+    let overlay = OVERLAY.map(|text| {
+        let corner = OVERLAY_CORNER
+            .and_then(Corner::parse)
+            .unwrap_or(Corner::BottomRight);
+        let mut overlay = Overlay::new(OverlayText::parse(text), corner);
+        overlay.foreground = OVERLAY_COLOR.and_then(Color::from_name);
+        overlay
+    });
+
+    let awake_windows = AWAKE_WINDOWS
+        .split(';')
+        .map(str::trim)
+        .filter(|w| !w.is_empty())
+        .filter_map(|w| {
+            DailyWindow::parse(w)
+                .inspect_err(|_| warn!("Invalid awake window {}", w))
+                .ok()
+        })
+        .collect();
+
+    let link_corner = LINK_CORNER
+        .and_then(Corner::parse)
+        .unwrap_or(Corner::BottomLeft);
 
     //
     // Setup Wifi
@@ -163,48 +567,180 @@ async fn main(spawner: Spawner) {
     let (stack, runner) = embassy_net::new(
         wifi_interface,
         config,
-        mk_static!(StackResources<3>, StackResources::<3>::new()),
+        mk_static!(StackResources<10>, StackResources::<10>::new()),
+        seed,
+    );
+
+    // The setup access point has its own interface, address and DHCP server
+    let (ap_stack, ap_runner) = embassy_net::new(
+        ap_interface,
+        provision::network_config(),
+        mk_static!(StackResources<4>, StackResources::<4>::new()),
         seed,
     );
 
     spawner.spawn(connection(wifi_controller)).ok();
     spawner.spawn(net_task(runner)).ok();
-
-    info!("Waiting to start WiFi...");
-
-    loop {
-        if stack.is_link_up() {
-            break;
-        }
-        Timer::after(Duration::from_millis(500)).await;
-    }
-
-    info!("Waiting to get IP address...");
-    loop {
-        if let Some(config) = stack.config_v4() {
-            info!("Got IP: {}", config.address);
-            break;
+    spawner.spawn(net_task(ap_runner)).ok();
+    spawner
+        .spawn(server::server(
+            stack,
+            shared_display,
+            awake_windows,
+            CONFIG_TOKEN.map_or(ConfigAccess::Closed, ConfigAccess::Token),
+        ))
+        .ok();
+    spawner
+        .spawn(server::server(
+            ap_stack,
+            shared_display,
+            Vec::new(),
+            ConfigAccess::Open,
+        ))
+        .ok();
+    spawner.spawn(provision::dhcp_server(ap_stack)).ok();
+    let frame_id = identity::frame_id();
+    let advertisement = mdns::Advertisement {
+        txt: vec![
+            format!("id={}", frame_id),
+            format!("name={}", identity::frame_name()),
+            format!("fw={}", FIRMWARE_VERSION),
+            String::from("path=/"),
+        ],
+        name: frame_id.clone(),
+        port: server::PORT,
+    };
+    spawner.spawn(mdns::responder(stack, advertisement)).ok();
+    if let Some(broker) = MQTT_BROKER {
+        match remote::Settings::parse(broker, MQTT_USERNAME, MQTT_PASSWORD) {
+            Some(settings) => {
+                spawner
+                    .spawn(remote::mqtt(stack, shared_display, settings))
+                    .ok();
+            }
+            None => warn!("Invalid MQTT broker {}", broker),
         }
-        Timer::after(Duration::from_millis(500)).await;
     }
 
     let stats: esp_alloc::HeapStats = esp_alloc::HEAP.stats();
     println!("{}", stats);
 
+    let mut failures = FailureTracker::new();
+    // Last image server found over mDNS, kept in case it misses a lookup
+    let mut found_server: Option<String> = None;
+    // URL the image server gave this frame, and the server URL it replaces
+    let mut assigned: Option<(String, String)> = None;
+    let mut cache = ImageCache::new(&PSRAM_ALLOCATOR, IMAGE_CACHE_SIZE);
+    // Set while the battery is too low to refresh, and kept through deep sleep
+    let mut flat = battery::is_resting();
+    if flat {
+        // The low battery screen is still up from before the frame slept
+        failures.record_failure(&StatusScreen::LowBattery { percent: 0 });
+    }
+
     loop {
-        let client_state = TcpClientState::<1, 1024, 1024>::new();
-        let tcp_client = TcpClient::new(stack, &client_state);
-        let dns_client = DnsSocket::new(stack);
+        let config = config::get();
+        schedule.set_interval(config.refresh_interval);
+
+        let reading = battery.as_mut().and_then(Battery::read);
+        if let Some(reading) = reading {
+            info!("Battery: {} mV, {}%", reading.millivolts, reading.percent);
+            state::record_battery(reading);
+        }
+        // A flat cell is left to rest, with WiFi off, until it has had some charge
+        let was_flat = flat;
+        flat = reading.is_some_and(|r| {
+            r.percent <= BATTERY_CUTOFF_PERCENT
+                || (was_flat && r.percent < BATTERY_CUTOFF_PERCENT + BATTERY_RECOVERY_MARGIN)
+        });
+        if flat {
+            wifi::set_enabled(false);
+        } else if was_flat {
+            info!("Battery charged, refreshing again");
+            wifi::set_enabled(true);
+        }
 
-        let mut http_client = HttpClient::new(&tcp_client, &dns_client);
+        if !flat && wifi::is_configured() {
+            indicator::show(indicator::Status::Connecting);
+        }
+        let connected = !flat
+            && wifi::is_configured()
+            && with_timeout(WIFI_TIMEOUT, wifi::wait_for_connection(stack))
+                .await
+                .is_ok();
 
-        info!("sentting up requests");
-        let url = "http://192.168.68.66:3005/recent";
-        // if let Err(e) = draw::display_epd_streaming(&mut display, &mut http_client, url).await {
-        //     warn!("Failed to display EPD: {:?}", e);
-        // }
+        if connected {
+            match sntp::request_time(stack).await {
+                Ok(unix_us) => clock::set_unix_time_us(unix_us),
+                Err(e) => warn!("Failed to sync time: {:?}", e),
+            }
+        }
+        if let Some(now) = clock::local_time() {
+            info!("Local time: {}", now);
+        }
 
-        let mut request = http_client.request(Method::GET, url).await.unwrap();
+        let server_url = if !config.server_url.is_empty() {
+            Some(config.server_url.clone())
+        } else {
+            if connected {
+                match mdns::find_server(stack).await {
+                    Some(service) => {
+                        let url = service.url();
+                        info!("Found image server at {}", url.as_str());
+                        found_server = Some(url);
+                    }
+                    None => warn!("No image server found over mDNS"),
+                }
+            }
+            found_server.clone()
+        };
+
+        // The panel is held only while it's in use, so PUT /image, MQTT and the
+        // console can have it while the image is fetched
+        let mut display = shared_display.lock().await;
+        // Woken up front so its temperature can be reported with the fetch
+        if let Err(e) = display.init().await {
+            error!("Failed to init display: {:?}", e);
+        }
+        let temperature = match display.read_temperature().await {
+            Ok(temperature) => Some(temperature),
+            Err(e) => {
+                warn!("Failed to read panel temperature: {:?}", e);
+                None
+            }
+        };
+        let _ = display.sleep().await;
+        drop(display);
+        let status = state::get();
+        let heap = esp_alloc::HEAP.stats();
+        let telemetry = Telemetry {
+            frame_id: &frame_id,
+            firmware: FIRMWARE_VERSION,
+            battery: reading,
+            rssi: status.rssi,
+            temperature,
+            last_error: status.last_error,
+            free_heap: heap.size - heap.current_usage,
+        };
+        if assigned
+            .as_ref()
+            .is_some_and(|(base, _)| Some(base) != server_url.as_ref())
+        {
+            assigned = None;
+        }
+        let url = assigned
+            .as_ref()
+            .map(|(_, url)| url)
+            .or(server_url.as_ref())
+            .map(|url| telemetry.apply(url));
+        let frame_name = identity::frame_name();
+        let panel_size = format!("{}x{}", draw::EPD_WIDTH, draw::EPD_HEIGHT);
+        let headers = [
+            (identity::ID_HEADER, frame_id.as_str()),
+            (identity::NAME_HEADER, frame_name.as_str()),
+            (identity::PANEL_HEADER, identity::PANEL),
+            (identity::SIZE_HEADER, panel_size.as_str()),
+        ];
 
         let stats: esp_alloc::HeapStats = esp_alloc::HEAP.stats();
         // HeapStats implements the Display and defmt::Format traits, so you can pretty-print the heap stats.
@@ -230,48 +766,270 @@ async fn main(spawner: Spawner) {
         // HeapStats implements the Display and defmt::Format traits, so you can pretty-print the heap stats.
         println!("PSRAM: {}", stats);
 
-        info!("send request");
-        Timer::after(Duration::from_secs(1)).await;
+        let result = if let Some(reading) = reading.filter(|_| flat) {
+            Err(RefreshFailure::Screen(StatusScreen::LowBattery {
+                percent: reading.percent,
+            }))
+        } else if !wifi::is_configured() {
+            Err(RefreshFailure::Screen(StatusScreen::WifiNotConfigured))
+        } else if !connected {
+            Err(RefreshFailure::Screen(StatusScreen::WifiUnavailable {
+                ssid: config.wifi_ssid.clone(),
+            }))
+        } else if let Some(url) = url {
+            indicator::show(indicator::Status::Fetching);
+            match fetch::get(stack, &url, &headers, &mut vec).await {
+                Ok(Fetched {
+                    body,
+                    link,
+                    image_id,
+                    frame_url,
+                }) => {
+                    if let (Some(frame_url), Some(base)) = (frame_url, &server_url) {
+                        let frame_url = Telemetry::strip(&frame_url);
+                        info!("Image server assigned {}", frame_url.as_str());
+                        assigned = Some((base.clone(), frame_url));
+                    }
+                    if draw::validate_epd(body).is_ok() {
+                        cache.insert(body, link.clone(), image_id.clone());
+                    }
+                    decorate(body, overlay.as_ref(), link.as_deref(), link_corner);
 
-        let response = match request.send(&mut vec).await {
-            Ok(file) => file,
-            Err(e) => {
-                info!("Failed to make request");
-                info!("Err: {}", e);
-                return;
-            }
-        };
-        info!("sent request");
+                    Timer::after(Duration::from_secs(10)).await;
+                    indicator::show(indicator::Status::Refreshing);
 
-        let body = response.body().read_to_end().await.unwrap();
-        println!("Got body: {}", body.len());
+                    let mut display = shared_display.lock().await;
+                    if let Err(e) = display.init().await {
+                        error!("Failed to init display: {:?}", e);
+                    }
+                    if maintenance.is_due() {
+                        match display.deep_clean().await {
+                            Ok(()) => maintenance.record_clean(),
+                            Err(e) => error!("Failed to deep clean display: {:?}", e),
+                        }
+                    }
 
-        let stats: esp_alloc::HeapStats = esp_alloc::HEAP.stats();
-        // HeapStats implements the Display and defmt::Format traits, so you can pretty-print the heap stats.
-        println!("{}", stats);
-
-        Timer::after(Duration::from_secs(10)).await;
-        led.write([RGB8::new(0, 0, 10)]).ok();
-        let _ = display.init().await;
+                    let result = match display.display_epd(body).await {
+                        Ok(()) => {
+                            state::record_image(image_id);
+                            Ok(())
+                        }
+                        Err(e) if e.is_bad_image() => {
+                            Err(RefreshFailure::Screen(StatusScreen::BadImage {
+                                reason: format!("{:?}", e),
+                            }))
+                        }
+                        Err(e) => {
+                            error!("Failed to display EPD: {:?}", e);
+                            Err(RefreshFailure::Panel)
+                        }
+                    };
+                    info!("Now Sleeping!");
+                    let _ = display.sleep().await;
+                    result
+                }
+                Err(e) => {
+                    warn!("Failed to fetch image: {:?}", e);
+                    // Back to the server's own URL in case the assigned one has gone
+                    assigned = None;
+                    Err(RefreshFailure::Screen(StatusScreen::ServerUnreachable {
+                        url: server_url.unwrap_or_default(),
+                    }))
+                }
+            }
+        } else {
+            Err(RefreshFailure::Screen(StatusScreen::ServerUnreachable {
+                url: String::from(mdns::SERVER_SERVICE),
+            }))
+        };
 
-        // // Display the EPD format image
-        // println!("display sen: {=[u8]:x}", body[0..10]);
+        let refreshed = result.is_ok();
+        match result {
+            Ok(()) => {
+                info!("Display updated successfully");
+                maintenance.record_refresh();
+                failures.record_success();
+                state::record_refresh(RefreshOutcome::Ok);
+                indicator::show(indicator::Status::Off);
+            }
+            Err(RefreshFailure::Panel) => {
+                state::record_refresh(RefreshOutcome::PanelError);
+                indicator::show(indicator::Status::Error(RefreshOutcome::PanelError));
+            }
+            Err(RefreshFailure::Screen(screen)) => {
+                error!("Refresh failed: {}", screen.title());
+                state::record_refresh(screen.outcome());
+                indicator::show(indicator::Status::Error(screen.outcome()));
+                if failures.record_failure(&screen) {
+                    let ip = stack
+                        .config_v4()
+                        .map(|c| format!("{}", c.address.address()));
+                    let setup_url = ip.as_ref().map(|ip| format!("http://{}/", ip));
+                    let info = DeviceInfo {
+                        ip,
+                        firmware: FIRMWARE_VERSION,
+                        setup_url: setup_url.as_deref(),
+                    };
+                    let mut display = shared_display.lock().await;
+                    let result = match display.init().await {
+                        Ok(()) => show_status(&mut display, &mut vec, &screen, &info).await,
+                        Err(e) => Err(e),
+                    };
+                    info!("Now Sleeping!");
+                    let _ = display.sleep().await;
+                    match result {
+                        Ok(()) => maintenance.record_refresh(),
+                        Err(e) => error!("Failed to display status: {:?}", e),
+                    }
+                }
+            }
+        }
 
-        // for a in 0..body.len() / 1024 {
-        //     println!("{=[u8]:x}", body[(a + 1) * 1024..((a + 1) * 1024) + 1024]);
-        // }
+        if on_trial {
+            on_trial = false;
+            if refreshed {
+                if let Err(e) = ota::confirm() {
+                    error!("Failed to confirm firmware: {:?}", e);
+                }
+            } else {
+                warn!("Updated firmware failed to refresh, rolling back");
+                match ota::roll_back() {
+                    Ok(()) => esp_hal::system::software_reset(),
+                    Err(e) => error!("Failed to roll back firmware: {:?}", e),
+                }
+            }
+        }
 
-        if let Err(e) = display.display_epd(body).await {
-            error!("Failed to display EPD: {:?}", e);
-        } else {
-            info!("Display updated successfully");
+        // Sleep until the next scheduled refresh, with WiFi off if that spans quiet hours
+        let now = clock::unix_time();
+        let time_zone = clock::time_zone();
+        let mut wake = schedule.next_wake(now, &time_zone);
+        if reading.is_some_and(|r| r.percent <= BATTERY_LOW_PERCENT) {
+            wake = wake * BATTERY_STRETCH;
         }
-        info!("Now Sleeping!");
-        let _ = display.sleep().await;
-        led.write([RGB8::new(0, 0, 0)]).ok();
+        let quiet = !flat
+            && now.is_some_and(|now| {
+                let now = now as i64;
+                schedule.is_quiet_between(now, now + wake.as_secs() as i64, &time_zone)
+            });
+        if quiet {
+            wifi::set_enabled(false);
+        }
+        info!("Next refresh in {} s", wake.as_secs());
+        battery::set_resting(flat);
+        // Nothing to do until the cell has had some charge, so use as little as
+        // possible. With a PIR or a second button to wake the frame, it deep sleeps
+        // too unless it's being set up or there's a command to carry out first.
+        let wakes_early = motion_pin.is_some() || button_pins.len() > 1;
+        if flat || (wakes_early && !wifi::is_provisioning() && !state::has_command()) {
+            // The LED keeps its colour through deep sleep unless turned off first
+            indicator::show(indicator::Status::Off);
+            Timer::after(Duration::from_millis(100)).await;
+            sleep_until_due(
+                Duration::from_secs(0),
+                wake,
+                motion_interval,
+                &button_pins,
+                motion_pin,
+            );
+        }
+        let slept_at = Instant::now();
+        let mut wake_at = slept_at + wake;
+        let mut wifi_off = quiet;
+        let mut waiting_for_light = false;
+        loop {
+            match select(Timer::at(wake_at), state::wait_for_command()).await {
+                // Hold off refreshing while someone is setting the frame up
+                Either::First(()) if wifi::is_provisioning() => {
+                    wake_at = Instant::now() + PROVISIONING_POLL;
+                }
+                Either::First(()) => {
+                    let dark = match darkness.as_mut() {
+                        Some(darkness) => darkness.is_dark().await,
+                        None => false,
+                    };
+                    if !dark {
+                        break;
+                    }
+                    // Refresh once the lights come on, rather than for an empty room
+                    if !waiting_for_light {
+                        info!("Too dark to refresh, waiting for the lights");
+                        waiting_for_light = true;
+                    }
+                    wake_at = Instant::now() + LIGHT_POLL;
+                }
+                Either::Second(Command::Refresh) => break,
+                // Refreshed as if due now, so still held while setting up or in the dark
+                Either::Second(Command::Motion) if slept_at.elapsed() >= motion_interval => {
+                    wake_at = Instant::now();
+                }
+                Either::Second(Command::Motion) => {}
+                Either::Second(Command::Sleep) => {
+                    info!("Sleeping with WiFi off until the next refresh");
+                    wifi::set_enabled(false);
+                    wifi_off = true;
+                }
+                Either::Second(Command::Update) => {
+                    if let Some(url) = ota::take_request() {
+                        if wifi_off {
+                            wifi::set_enabled(true);
+                            wifi_off = false;
+                        }
+                        install_update(stack, &url).await;
+                    }
+                }
+                Either::Second(Command::NextImage) => {
+                    let Some(image) = cache.next() else {
+                        info!("No cached images to show");
+                        continue;
+                    };
+                    let result = show_cached(
+                        shared_display,
+                        image,
+                        &mut vec,
+                        overlay.as_ref(),
+                        link_corner,
+                    )
+                    .await;
+                    match result {
+                        Ok(()) => {
+                            state::record_image(image.image_id.clone());
+                            maintenance.record_refresh();
+                        }
+                        Err(e) => error!("Failed to show cached image: {:?}", e),
+                    }
+                }
+                Either::Second(Command::Provision) => {
+                    let ssid = provision::ssid(&frame_id);
+                    let password = format!("{:08x}", rng.random());
+                    // The access point brings the radio back up
+                    wifi_off = false;
+                    wifi::start_provisioning(ssid.clone(), password.clone());
 
-        // Put the display to sleep for 1hr when done
-        Timer::after(Duration::from_secs(60 * 60)).await;
+                    let screen = StatusScreen::Provisioning { ssid, password };
+                    let setup_url = provision::setup_url();
+                    let info = DeviceInfo {
+                        ip: Some(format!("{}", provision::ADDRESS)),
+                        firmware: FIRMWARE_VERSION,
+                        setup_url: Some(&setup_url),
+                    };
+                    let mut display = shared_display.lock().await;
+                    let result = match display.init().await {
+                        Ok(()) => show_status(&mut display, &mut vec, &screen, &info).await,
+                        Err(e) => Err(e),
+                    };
+                    display.sleep().await.ok();
+                    match result {
+                        Ok(()) => maintenance.record_refresh(),
+                        Err(e) => error!("Failed to display setup screen: {:?}", e),
+                    }
+                }
+            }
+        }
+        maintenance.record_elapsed(slept_at.elapsed());
+        if wifi_off {
+            wifi::set_enabled(true);
+        }
 
         // info!("Writing Red!");
         // led.write([RGB8::new(50, 0, 0)]).ok();
@@ -333,6 +1091,106 @@ async fn main(spawner: Spawner) {
     }
 }
 
+// Deep sleeps until the next refresh is due, noting for after the frame wakes
+// how long ago the last one was. A PIR sensor still high from the last motion
+// would wake the frame straight away, so it's left out, and the frame wakes to
+// look again once motion would count.
+fn sleep_until_due(
+    since_refresh: Duration,
+    until_due: Duration,
+    motion_interval: Duration,
+    button_pins: &[u8],
+    motion_pin: Option<u8>,
+) -> ! {
+    clock::record_sleep(since_refresh, until_due);
+    let still_high = motion_pin.is_some_and(motion::is_high);
+    let duration = if still_high {
+        let unheeded = motion_interval
+            .checked_sub(since_refresh)
+            .unwrap_or(Duration::MIN);
+        until_due.min(unheeded.max(MOTION_POLL))
+    } else {
+        until_due
+    };
+    button::sleep_deep(duration, button_pins, motion_pin.filter(|_| !still_high))
+}
+
+// Restarts rather than halting, so that a frame gets going again by itself and
+// an update that panics gets rolled back
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    error!("{}", defmt::Display2Format(info));
+    esp_hal::system::software_reset()
+}
+
+// Draws the overlay, and the QR code for the image's link, onto an EPD image
+fn decorate(body: &mut [u8], overlay: Option<&Overlay>, link: Option<&str>, link_corner: Corner) {
+    let Some(mut framebuffer) = draw::epd_pixels_mut(body).ok().and_then(Framebuffer::new) else {
+        return;
+    };
+    if let Some(overlay) = overlay {
+        overlay.draw(&mut framebuffer, clock::local_time());
+    }
+    if let Some(link) = link {
+        overlay::draw_link(&mut framebuffer, link, link_corner);
+    }
+}
+
+// Puts an image from the cache back up, with the overlay drawn afresh
+async fn show_cached(
+    display: &SharedDisplay,
+    image: &CachedImage,
+    buffer: &mut [u8],
+    overlay: Option<&Overlay>,
+    link_corner: Corner,
+) -> Result<(), draw::Error> {
+    let body = &mut buffer[..image.body.len()];
+    body.copy_from_slice(&image.body);
+    decorate(body, overlay, image.link.as_deref(), link_corner);
+
+    let mut display = display.lock().await;
+    let result = match display.init().await {
+        Ok(()) => display.display_epd(body).await,
+        Err(e) => Err(e),
+    };
+    display.sleep().await.ok();
+    result
+}
+
+// Draws a status screen over the whole panel, using `buffer` for the frame
+async fn show_status(
+    display: &mut EPD7in3f<'_>,
+    buffer: &mut [u8],
+    screen: &StatusScreen,
+    info: &DeviceInfo<'_>,
+) -> Result<(), draw::Error> {
+    let Some(mut framebuffer) = Framebuffer::new(buffer) else {
+        return Ok(());
+    };
+    status::render(&mut framebuffer, screen, info);
+    display.display_framebuffer(&framebuffer).await
+}
+
+// Only returns if the update failed; otherwise restarts into the new firmware
+async fn install_update(stack: Stack<'static>, url: &str) {
+    if with_timeout(WIFI_TIMEOUT, wifi::wait_for_connection(stack))
+        .await
+        .is_err()
+    {
+        warn!("No WiFi for firmware update");
+        return;
+    }
+    indicator::show(indicator::Status::Updating);
+    match ota::update(stack, url).await {
+        Ok(()) => {
+            info!("Restarting into new firmware");
+            esp_hal::system::software_reset();
+        }
+        Err(e) => error!("Firmware update failed: {:?}", e),
+    }
+    indicator::show(indicator::Status::Off);
+}
+
 #[embassy_executor::task]
 async fn blinker(mut led: Output<'static>, interval: Duration) {
     loop {
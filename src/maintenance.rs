@@ -0,0 +1,133 @@
+//
+// Anti-ghosting maintenance. Seven-colour panels build up ghosting after many
+// updates; cycling the panel through solid and inverted frames clears it.
+//
+// The counters are kept in flash so they survive deep sleep and power cuts.
+// They start from zero on a new frame, or if the stored copy fails its check.
+//
+use defmt::{warn, Format};
+use embassy_time::Duration;
+
+use crate::color::Color;
+use crate::pattern::Pattern;
+use crate::store;
+
+/// Frames shown during a deep clean, before the next image is drawn
+pub static DEEP_CLEAN_PATTERNS: &[Pattern] = &[
+    Pattern::Solid(Color::Black),
+    Pattern::Solid(Color::White),
+    Pattern::Checkerboard(Color::Black, Color::White, 16),
+    Pattern::Checkerboard(Color::White, Color::Black, 16),
+    Pattern::Solid(Color::Red),
+    Pattern::Solid(Color::Green),
+    Pattern::Solid(Color::Blue),
+    Pattern::Solid(Color::Yellow),
+    Pattern::Solid(Color::Orange),
+    Pattern::Solid(Color::White),
+];
+
+/// When a deep clean should run
+#[derive(Debug, Clone, Copy, Format)]
+pub enum CleanSchedule {
+    /// Never clean automatically
+    Never,
+    /// After this many refreshes since the last clean
+    EveryRefreshes(u32),
+    /// After this much time has passed since the last clean
+    Every(Duration),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Counters {
+    refreshes_since_clean: u32,
+    seconds_since_clean: u32,
+    total_refreshes: u32,
+}
+
+impl Counters {
+    fn encode(&self) -> [u8; 12] {
+        let mut bytes = [0; 12];
+        bytes[0..4].copy_from_slice(&self.refreshes_since_clean.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.seconds_since_clean.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.total_refreshes.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let word = |i: usize| Some(u32::from_le_bytes(bytes.get(i..i + 4)?.try_into().ok()?));
+        Some(Self {
+            refreshes_since_clean: word(0)?,
+            seconds_since_clean: word(4)?,
+            total_refreshes: word(8)?,
+        })
+    }
+}
+
+/// Tracks refreshes and elapsed time against a `CleanSchedule`
+pub struct Maintenance {
+    schedule: CleanSchedule,
+    counters: Counters,
+}
+
+impl Maintenance {
+    pub fn new(schedule: CleanSchedule) -> Self {
+        let mut buffer = [0u8; 12];
+        let counters = match store::load(store::COUNTERS, &mut buffer) {
+            Ok(Some(len)) => Counters::decode(&buffer[..len]).unwrap_or_default(),
+            Ok(None) => Counters::default(),
+            Err(e) => {
+                warn!("Couldn't load maintenance counters: {:?}", e);
+                Counters::default()
+            }
+        };
+        Self { schedule, counters }
+    }
+
+    fn save(&self) {
+        if let Err(e) = store::save(store::COUNTERS, &self.counters.encode()) {
+            warn!("Couldn't save maintenance counters: {:?}", e);
+        }
+    }
+
+    /// Number of refreshes since the counters were first stored
+    pub fn total_refreshes(&self) -> u32 {
+        self.counters.total_refreshes
+    }
+
+    /// Counts a completed refresh of the panel
+    pub fn record_refresh(&mut self) {
+        let counters = &mut self.counters;
+        counters.refreshes_since_clean = counters.refreshes_since_clean.saturating_add(1);
+        counters.total_refreshes = counters.total_refreshes.saturating_add(1);
+        self.save();
+    }
+
+    /// Adds time spent sleeping between refreshes
+    pub fn record_elapsed(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs().min(u32::MAX as u64) as u32;
+        self.counters.seconds_since_clean =
+            self.counters.seconds_since_clean.saturating_add(seconds);
+        self.save();
+    }
+
+    /// Starts the schedule again after a deep clean
+    pub fn record_clean(&mut self) {
+        self.counters.refreshes_since_clean = 0;
+        self.counters.seconds_since_clean = 0;
+        self.save();
+    }
+
+    /// Whether a deep clean should run before the next image
+    pub fn is_due(&self) -> bool {
+        let Counters {
+            refreshes_since_clean: refreshes,
+            seconds_since_clean: seconds,
+            ..
+        } = self.counters;
+        match self.schedule {
+            CleanSchedule::Never => false,
+            CleanSchedule::EveryRefreshes(n) => refreshes >= n,
+            CleanSchedule::Every(interval) => seconds as u64 >= interval.as_secs(),
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//
+// Multicast DNS (RFC 6762) and DNS-SD (RFC 6763), just enough to find the
+// image server as `_photoframe._tcp.local` and to advertise the frame as
+// `_photoframe-device._tcp.local`.
+//
+// Lookups are one-shot "legacy unicast" queries from an ephemeral port, so
+// they don't compete with the responder for port 5353. The messages themselves
+// are built and parsed in `dns`.
+//
+use alloc::{format, string::String, vec, vec::Vec};
+use defmt::{info, warn};
+use embassy_futures::select::select;
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    IpAddress, IpEndpoint, Ipv4Address, Stack,
+};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+use crate::dns::{
+    encode_query, encode_response, locate, parse, same_name, Question, RData, Record, Service,
+    TYPE_A, TYPE_ANY, TYPE_PTR, TYPE_SRV, TYPE_TXT,
+};
+
+pub const SERVER_SERVICE: &str = "_photoframe._tcp.local";
+pub const DEVICE_SERVICE: &str = "_photoframe-device._tcp.local";
+
+const MDNS_GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const PACKET_SIZE: usize = 1500;
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+// Queries sent per lookup, each asking for whatever is still missing
+const LOOKUP_ATTEMPTS: usize = 3;
+
+/// Looks for an image server advertising `_photoframe._tcp` on the LAN
+pub async fn find_server(stack: Stack<'_>) -> Option<Service> {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = vec![0u8; PACKET_SIZE * 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = vec![0u8; PACKET_SIZE];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(0).ok()?;
+
+    let mut records = Vec::new();
+    let mut packet = vec![0u8; PACKET_SIZE];
+    for _ in 0..LOOKUP_ATTEMPTS {
+        let questions = match locate(&records, SERVER_SERVICE) {
+            Ok(service) => return Some(service),
+            Err(questions) => questions,
+        };
+        socket
+            .send_to(&encode_query(&questions), (MDNS_GROUP, MDNS_PORT))
+            .await
+            .ok()?;
+
+        // Take every answer that arrives in the window; several servers may reply
+        let deadline = Instant::now() + LOOKUP_TIMEOUT;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Ok(Ok((len, _))) = with_timeout(remaining, socket.recv_from(&mut packet)).await
+            else {
+                break;
+            };
+            if let Some(message) = parse(&packet[..len]).filter(|m| m.is_response) {
+                records.extend(message.records);
+            }
+            if locate(&records, SERVER_SERVICE).is_ok() {
+                break;
+            }
+        }
+    }
+    locate(&records, SERVER_SERVICE).ok()
+}
+
+/// What the frame advertises about itself
+pub struct Advertisement {
+    /// Instance and host name, e.g. "frame-a1b2c3"
+    pub name: String,
+    /// Port of the frame's HTTP server
+    pub port: u16,
+    /// `key=value` TXT entries
+    pub txt: Vec<String>,
+}
+
+impl Advertisement {
+    fn instance(&self) -> String {
+        format!("{}.{}", self.name, DEVICE_SERVICE)
+    }
+
+    fn host(&self) -> String {
+        format!("{}.local", self.name)
+    }
+
+    // Whether `question` is about us
+    fn answers(&self, question: &Question) -> bool {
+        let any = question.qtype == TYPE_ANY;
+        (same_name(&question.name, DEVICE_SERVICE) && (any || question.qtype == TYPE_PTR))
+            || (same_name(&question.name, &self.instance())
+                && (any || question.qtype == TYPE_SRV || question.qtype == TYPE_TXT))
+            || (same_name(&question.name, &self.host()) && (any || question.qtype == TYPE_A))
+    }
+
+    fn records(&self, address: [u8; 4]) -> Vec<Record> {
+        let instance = self.instance();
+        let host = self.host();
+        vec![
+            Record {
+                name: String::from(DEVICE_SERVICE),
+                data: RData::Ptr(instance.clone()),
+            },
+            Record {
+                name: instance.clone(),
+                data: RData::Srv {
+                    port: self.port,
+                    target: host.clone(),
+                },
+            },
+            Record {
+                name: instance,
+                data: RData::Txt(self.txt.clone()),
+            },
+            Record {
+                name: host,
+                data: RData::A(address),
+            },
+        ]
+    }
+}
+
+fn own_address(stack: Stack<'_>) -> Option<[u8; 4]> {
+    stack.config_v4().map(|c| c.address.address().octets())
+}
+
+#[embassy_executor::task]
+pub async fn responder(stack: Stack<'static>, advertisement: Advertisement) {
+    stack.wait_config_up().await;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = vec![0u8; PACKET_SIZE * 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = vec![0u8; PACKET_SIZE * 2];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if socket.bind(MDNS_PORT).is_err() {
+        warn!("mDNS: failed to bind port {}", MDNS_PORT);
+        return;
+    }
+    let group = IpEndpoint::new(IpAddress::Ipv4(MDNS_GROUP), MDNS_PORT);
+    let mut packet = vec![0u8; PACKET_SIZE];
+
+    // Each time Wi-Fi connects, as the network may have forgotten us
+    loop {
+        // Joining again reports the membership to the new network
+        stack.leave_multicast_group(MDNS_GROUP).ok();
+        if stack.join_multicast_group(MDNS_GROUP).is_err() {
+            warn!("mDNS: failed to join multicast group");
+            return;
+        }
+
+        // Announce ourselves, twice a second apart as RFC 6762 section 8.3 suggests
+        for _ in 0..2 {
+            if let Some(address) = own_address(stack) {
+                let response = encode_response(None, &advertisement.records(address));
+                socket.send_to(&response, group).await.ok();
+            }
+            Timer::after(Duration::from_secs(1)).await;
+        }
+        info!("mDNS: advertising {}", advertisement.instance().as_str());
+
+        let answer = async {
+            loop {
+                let Ok((len, meta)) = socket.recv_from(&mut packet).await else {
+                    continue;
+                };
+                let raw = &packet[..len];
+                let Some(query) = parse(raw).filter(|m| !m.is_response) else {
+                    continue;
+                };
+                if !query.questions.iter().any(|q| advertisement.answers(q)) {
+                    continue;
+                }
+                let Some(address) = own_address(stack) else {
+                    continue;
+                };
+                let records = advertisement.records(address);
+                // Queries from port 5353 get a multicast answer, anything else a unicast one
+                let (response, to) = match meta.endpoint.port {
+                    MDNS_PORT => (encode_response(None, &records), group),
+                    _ => (
+                        encode_response(Some((raw, &query)), &records),
+                        meta.endpoint,
+                    ),
+                };
+                socket.send_to(&response, to).await.ok();
+            }
+        };
+        select(answer, stack.wait_config_down()).await;
+        stack.wait_config_up().await;
+    }
+}
@@ -0,0 +1,60 @@
+//
+// A PIR sensor on a spare RTC GPIO, so the frame can refresh when someone
+// comes into the room rather than only on its schedule. The sensor's output
+// goes high on motion and stays high for a while after, depending on the
+// module.
+//
+// In deep sleep the sensor wakes the frame through EXT0. That wakes on a level
+// rather than an edge, so it's left out while the output is still high, or
+// the frame would wake straight away.
+//
+use defmt::info;
+use esp_hal::{
+    gpio::{AnyPin, Input, InputConfig, Pull},
+    rtc_cntl::wakeup_cause,
+    system::SleepSource,
+};
+
+use crate::state::{self, Command};
+
+// RTC GPIOs not taken by the panel, the BOOT button or USB
+const RTC_PINS: [u8; 13] = [1, 2, 3, 4, 5, 6, 7, 8, 15, 16, 17, 18, 21];
+
+/// The sensor's input on GPIO `pin`, if that's an RTC GPIO it can use
+///
+/// The pin mustn't be in use for anything else.
+pub fn input(pin: u8) -> Option<Input<'static>> {
+    if !RTC_PINS.contains(&pin) {
+        return None;
+    }
+    // SAFETY: nothing else has the pin, as above
+    let pin = unsafe { AnyPin::steal(pin) };
+    Some(Input::new(
+        pin,
+        InputConfig::default().with_pull(Pull::Down),
+    ))
+}
+
+/// Whether the sensor on GPIO `pin` still sees someone
+///
+/// Only for just before deep sleep, once the motion task won't run again.
+pub fn is_high(pin: u8) -> bool {
+    // SAFETY: the motion task is done with the pin, as above
+    let pin = unsafe { AnyPin::steal(pin) };
+    Input::new(pin, InputConfig::default().with_pull(Pull::Down)).is_high()
+}
+
+#[embassy_executor::task]
+pub async fn motion(mut input: Input<'static>) {
+    loop {
+        input.wait_for_rising_edge().await;
+        info!("Motion");
+        state::send_command(Command::Motion);
+        input.wait_for_low().await;
+    }
+}
+
+/// Whether the frame came out of deep sleep because the sensor saw someone
+pub fn woke_frame() -> bool {
+    matches!(wakeup_cause(), SleepSource::Ext0)
+}
@@ -0,0 +1,304 @@
+//
+// Over-the-air firmware updates into the inactive OTA app partition.
+//
+// An update is an app image (as written by `espflash save-image`) served over
+// HTTP with its Ed25519 signature, hex encoded in the `X-Firmware-Signature`
+// header. Frames are built with only the public key, hex encoded in
+// FRAME_OTA_PUBLIC_KEY, so reading one frame's flash doesn't let anyone sign
+// updates for the others. With OpenSSL:
+//
+//   openssl genpkey -algorithm ed25519 -out ota.pem
+//   openssl pkey -in ota.pem -pubout -outform DER | tail -c 32 | xxd -p -c 32
+//   openssl pkeyutl -sign -rawin -inkey ota.pem -in photo-frame.bin | xxd -p -c 64
+//
+// Once written and verified the new slot is selected in otadata and marked
+// new. It has to prove itself on the next boot: `confirm` after a good
+// refresh, or `roll_back` to the previous slot if the refresh fails. While on
+// trial the RTC watchdog restarts a frame that hangs, a panic restarts it too,
+// and an update that hasn't been confirmed after a few boots is rolled back.
+//
+use alloc::{string::String, vec};
+use core::cell::RefCell;
+use critical_section::Mutex;
+use defmt::{error, info, warn, Format};
+use ed25519_compact::{PublicKey, Signature};
+use embassy_net::{
+    dns::DnsSocket,
+    tcp::client::{TcpClient, TcpClientState},
+    Stack,
+};
+use embedded_io_async::Read;
+use embedded_storage::Storage;
+use esp_bootloader_esp_idf::{
+    ota::{Ota, OtaImageState, Slot},
+    partitions::{
+        self, AppPartitionSubType, DataPartitionSubType, PartitionEntry, PartitionTable,
+        PartitionType, PARTITION_TABLE_MAX_LEN,
+    },
+};
+use esp_hal::rtc_cntl::{Rwdt, RwdtStage, RwdtStageAction};
+use esp_storage::FlashStorage;
+use reqwless::{client::HttpClient, request::Method};
+
+use crate::state::{self, Command};
+
+const SIGNATURE_HEADER: &str = "X-Firmware-Signature";
+const PUBLIC_KEY: Option<&str> = option_env!("FRAME_OTA_PUBLIC_KEY");
+// First byte of every ESP app image
+const IMAGE_MAGIC: u8 = 0xe9;
+const SECTOR_SIZE: usize = FlashStorage::SECTOR_SIZE as usize;
+// Longest a trial boot may take to connect and refresh before it's restarted
+const TRIAL_TIMEOUT: esp_hal::time::Duration = esp_hal::time::Duration::from_secs(5 * 60);
+// Boots an update gets to refresh before it's rolled back
+const TRIAL_BOOTS: u32 = 3;
+// Marks `TRIAL_COUNT` as counting, in memory that survives a restart
+const TRIAL_MAGIC: u32 = 0x5452_494c;
+
+// The magic, then boots of the update on trial so far
+#[esp_hal::ram(rtc_fast, persistent)]
+static mut TRIAL_COUNT: [u32; 2] = [0; 2];
+
+#[derive(Debug, Format)]
+pub enum OtaError {
+    /// Built without a valid FRAME_OTA_PUBLIC_KEY, so nothing could be verified
+    NotConfigured,
+    Partition,
+    Flash,
+    Http(reqwless::Error),
+    Status(u16),
+    MissingSignature,
+    MissingLength,
+    TooLarge,
+    NotAnImage,
+    Truncated,
+    BadSignature,
+}
+
+impl From<reqwless::Error> for OtaError {
+    fn from(e: reqwless::Error) -> Self {
+        OtaError::Http(e)
+    }
+}
+
+impl From<partitions::Error> for OtaError {
+    fn from(_: partitions::Error) -> Self {
+        OtaError::Partition
+    }
+}
+
+static REQUESTED: Mutex<RefCell<Option<String>>> = Mutex::new(RefCell::new(None));
+
+/// Asks the refresh loop to update from `url` when it next wakes
+pub fn request(url: String) {
+    critical_section::with(|cs| REQUESTED.borrow_ref_mut(cs).replace(url));
+    state::send_command(Command::Update);
+}
+
+pub fn take_request() -> Option<String> {
+    critical_section::with(|cs| REQUESTED.borrow_ref_mut(cs).take())
+}
+
+fn find<'a>(
+    table: &PartitionTable<'a>,
+    kind: PartitionType,
+) -> Result<PartitionEntry<'a>, OtaError> {
+    table.find_partition(kind)?.ok_or(OtaError::Partition)
+}
+
+fn app_partition(slot: Slot) -> PartitionType {
+    PartitionType::App(match slot {
+        Slot::Slot1 => AppPartitionSubType::Ota1,
+        _ => AppPartitionSubType::Ota0,
+    })
+}
+
+// Runs `f` with the OTA data from the partition table
+fn with_ota<R>(
+    f: impl FnOnce(&mut Ota<'_, FlashStorage>) -> Result<R, OtaError>,
+) -> Result<R, OtaError> {
+    let mut flash = FlashStorage::new();
+    let mut buffer = vec![0u8; PARTITION_TABLE_MAX_LEN];
+    let table = partitions::read_partition_table(&mut flash, &mut buffer)?;
+    let otadata = find(&table, PartitionType::Data(DataPartitionSubType::Ota))?;
+    let mut region = otadata.as_embedded_storage(&mut flash);
+    let mut ota = Ota::new(&mut region)?;
+    f(&mut ota)
+}
+
+fn decode_hex<const N: usize>(hex: &[u8]) -> Option<[u8; N]> {
+    let hex = hex.trim_ascii();
+    if hex.len() != N * 2 {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks_exact(2)) {
+        let pair = core::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+// Whether this boot is a run of an update that hasn't been confirmed yet
+fn is_on_trial() -> bool {
+    with_ota(|ota| {
+        let slot = ota.current_slot()?;
+        let state = ota.current_ota_state()?;
+        Ok(
+            slot != Slot::None
+                && matches!(state, OtaImageState::New | OtaImageState::PendingVerify),
+        )
+    })
+    .unwrap_or(false)
+}
+
+// Counts this boot of an update on trial, returning how many there have been
+fn count_trial_boot() -> u32 {
+    let [magic, count] = unsafe { TRIAL_COUNT };
+    let count = if magic == TRIAL_MAGIC { count + 1 } else { 1 };
+    unsafe {
+        TRIAL_COUNT = [TRIAL_MAGIC, count];
+    }
+    count
+}
+
+// Stops the watchdog and the count of trial boots
+fn end_trial() {
+    Rwdt::new().disable();
+    unsafe {
+        TRIAL_COUNT = [0; 2];
+    }
+}
+
+/// Says whether the running firmware is an update on trial, and if so arms
+/// the watchdog so that a hang restarts the frame for another try. Rolls back
+/// and restarts once the update has had its tries.
+///
+/// To be called early, before anything that could hang or panic.
+pub fn start_trial() -> bool {
+    if !is_on_trial() {
+        end_trial();
+        return false;
+    }
+    let boots = count_trial_boot();
+    if boots > TRIAL_BOOTS {
+        warn!("Updated firmware never refreshed in {} boots", TRIAL_BOOTS);
+        match roll_back() {
+            Ok(()) => esp_hal::system::software_reset(),
+            Err(e) => {
+                error!("Failed to roll back firmware: {:?}", e);
+                return false;
+            }
+        }
+    }
+    info!("Running updated firmware on trial, boot {}", boots);
+    let mut rwdt = Rwdt::new();
+    // Leaves the RTC memory holding the count alone
+    rwdt.set_stage_action(RwdtStage::Stage0, RwdtStageAction::ResetCore);
+    rwdt.set_timeout(RwdtStage::Stage0, TRIAL_TIMEOUT);
+    rwdt.enable();
+    true
+}
+
+/// Keeps the running firmware for future boots
+pub fn confirm() -> Result<(), OtaError> {
+    end_trial();
+    with_ota(|ota| Ok(ota.set_current_ota_state(OtaImageState::Valid)?))?;
+    info!("Firmware confirmed");
+    Ok(())
+}
+
+/// Marks the running firmware bad and goes back to the previous slot; takes effect on reset
+pub fn roll_back() -> Result<(), OtaError> {
+    end_trial();
+    with_ota(|ota| {
+        let previous = ota.current_slot()?.next();
+        ota.set_current_ota_state(OtaImageState::Invalid)?;
+        ota.set_current_slot(previous)?;
+        Ok(())
+    })?;
+    info!("Firmware rolled back");
+    Ok(())
+}
+
+/// Downloads, verifies and selects the firmware at `url`; takes effect on reset
+pub async fn update(stack: Stack<'_>, url: &str) -> Result<(), OtaError> {
+    let key = PUBLIC_KEY
+        .and_then(|key| decode_hex(key.as_bytes()))
+        .map(PublicKey::new)
+        .ok_or(OtaError::NotConfigured)?;
+
+    let mut flash = FlashStorage::new();
+    let mut table_buffer = vec![0u8; PARTITION_TABLE_MAX_LEN];
+    let table = partitions::read_partition_table(&mut flash, &mut table_buffer)?;
+    let otadata = find(&table, PartitionType::Data(DataPartitionSubType::Ota))?;
+    let next = {
+        let mut region = otadata.as_embedded_storage(&mut flash);
+        Ota::new(&mut region)?.current_slot()?.next()
+    };
+    let target = find(&table, app_partition(next))?;
+
+    let client_state = TcpClientState::<1, 1024, 1024>::new();
+    let tcp_client = TcpClient::new(stack, &client_state);
+    let dns_client = DnsSocket::new(stack);
+    let mut http_client = HttpClient::new(&tcp_client, &dns_client);
+
+    info!("Updating firmware from {}", url);
+    let mut header_buffer = vec![0u8; 2048];
+    let mut request = http_client.request(Method::GET, url).await?;
+    let response = request.send(&mut header_buffer).await?;
+    if !response.status.is_successful() {
+        return Err(OtaError::Status(response.status.0));
+    }
+    let signature = response
+        .headers()
+        .find(|(name, _)| name.eq_ignore_ascii_case(SIGNATURE_HEADER))
+        .and_then(|(_, value)| decode_hex(value))
+        .map(Signature::new)
+        .ok_or(OtaError::MissingSignature)?;
+    // Checked before anything is erased, so a bad response leaves the slot alone
+    let len = response.content_length.ok_or(OtaError::MissingLength)?;
+    if len == 0 {
+        return Err(OtaError::NotAnImage);
+    }
+    if len > target.len() as usize {
+        return Err(OtaError::TooLarge);
+    }
+
+    // Written a sector at a time, so each is erased only once
+    let mut verifier = key
+        .verify_incremental(&signature)
+        .map_err(|_| OtaError::BadSignature)?;
+    let mut region = target.as_embedded_storage(&mut flash);
+    let mut reader = response.body().reader();
+    let mut sector = vec![0u8; SECTOR_SIZE];
+    let mut written = 0;
+    while written < len {
+        let want = (len - written).min(SECTOR_SIZE);
+        let mut filled = 0;
+        while filled < want {
+            match reader.read(&mut sector[filled..want]).await? {
+                0 => return Err(OtaError::Truncated),
+                read => filled += read,
+            }
+        }
+        if written == 0 && sector[0] != IMAGE_MAGIC {
+            return Err(OtaError::NotAnImage);
+        }
+        verifier.absorb(&sector[..want]);
+        region
+            .write(written as u32, &sector[..want])
+            .map_err(|_| OtaError::Flash)?;
+        written += want;
+        if written % (64 * 1024) == 0 {
+            info!("Firmware: {} of {} bytes", written, len);
+        }
+    }
+    verifier.verify().map_err(|_| OtaError::BadSignature)?;
+
+    let mut region = otadata.as_embedded_storage(&mut flash);
+    let mut ota = Ota::new(&mut region)?;
+    ota.set_current_slot(next)?;
+    ota.set_current_ota_state(OtaImageState::New)?;
+    info!("Firmware update written, {} bytes", len);
+    Ok(())
+}
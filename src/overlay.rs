@@ -0,0 +1,191 @@
+//
+// Overlay compositor: draws a date/time, refresh timestamp or custom text
+// in a corner of the fetched image, on a box that contrasts with it.
+//
+use alloc::{format, string::String};
+use defmt::Format;
+
+use crate::color::Color;
+use crate::font;
+use crate::framebuffer::{self, Framebuffer};
+use crate::qr::{EccLevel, QrCode};
+use crate::tz::DateTime;
+
+// Gap between the box and the edge of the panel
+const MARGIN: u32 = 12;
+// Gap between the text and the edge of its box
+const PADDING: u32 = 6;
+const DEFAULT_SCALE: u32 = 3;
+// Pixels per module for QR codes of links
+const LINK_SCALE: u32 = 3;
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Corner {
+    /// Parses "top-left", "top-right", "bottom-left" or "bottom-right"
+    pub fn parse(corner: &str) -> Option<Self> {
+        match corner {
+            "top-left" => Some(Corner::TopLeft),
+            "top-right" => Some(Corner::TopRight),
+            "bottom-left" => Some(Corner::BottomLeft),
+            "bottom-right" => Some(Corner::BottomRight),
+            _ => None,
+        }
+    }
+
+    /// Top left corner of a `width` x `height` box placed in this corner
+    fn position(&self, framebuffer: &Framebuffer, width: u32, height: u32) -> (u32, u32) {
+        let right = framebuffer.width().saturating_sub(width + MARGIN);
+        let bottom = framebuffer.height().saturating_sub(height + MARGIN);
+        match self {
+            Corner::TopLeft => (MARGIN, MARGIN),
+            Corner::TopRight => (right, MARGIN),
+            Corner::BottomLeft => (MARGIN, bottom),
+            Corner::BottomRight => (right, bottom),
+        }
+    }
+}
+
+/// What the overlay shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum OverlayText {
+    /// e.g. "Sat 5 Jul 2025"
+    Date,
+    /// e.g. "14:05"
+    Time,
+    /// e.g. "Sat 5 Jul 14:05"
+    DateTime,
+    /// When the image was drawn, e.g. "Updated 14:05"
+    Refreshed,
+    Custom(&'static str),
+}
+
+impl OverlayText {
+    /// "date", "time", "datetime" or "refreshed"; anything else is shown as is
+    pub fn parse(spec: &'static str) -> Self {
+        match spec {
+            "date" => OverlayText::Date,
+            "time" => OverlayText::Time,
+            "datetime" => OverlayText::DateTime,
+            "refreshed" => OverlayText::Refreshed,
+            text => OverlayText::Custom(text),
+        }
+    }
+
+    /// The text to draw, or `None` if it needs the time and the clock is not set
+    pub fn render(&self, now: Option<DateTime>) -> Option<String> {
+        let text = match (self, now) {
+            (OverlayText::Custom(text), _) => String::from(*text),
+            (_, None) => return None,
+            (OverlayText::Date, Some(now)) => format!(
+                "{} {} {} {}",
+                weekday_name(&now),
+                now.day,
+                month_name(&now),
+                now.year
+            ),
+            (OverlayText::Time, Some(now)) => format!("{:02}:{:02}", now.hour, now.minute),
+            (OverlayText::DateTime, Some(now)) => format!(
+                "{} {} {} {:02}:{:02}",
+                weekday_name(&now),
+                now.day,
+                month_name(&now),
+                now.hour,
+                now.minute
+            ),
+            (OverlayText::Refreshed, Some(now)) => {
+                format!("Updated {:02}:{:02}", now.hour, now.minute)
+            }
+        };
+        Some(text)
+    }
+}
+
+fn weekday_name(date: &DateTime) -> &'static str {
+    WEEKDAYS[date.weekday as usize]
+}
+
+fn month_name(date: &DateTime) -> &'static str {
+    MONTHS[date.month as usize - 1]
+}
+
+pub struct Overlay {
+    pub text: OverlayText,
+    pub corner: Corner,
+    pub scale: u32,
+    /// Text colour, or `None` to contrast with the box
+    pub foreground: Option<Color>,
+    /// Box colour, or `None` to contrast with the text if that is set, and
+    /// otherwise to match the lightness of the image under it
+    pub background: Option<Color>,
+}
+
+impl Overlay {
+    pub fn new(text: OverlayText, corner: Corner) -> Self {
+        Self {
+            text,
+            corner,
+            scale: DEFAULT_SCALE,
+            foreground: None,
+            background: None,
+        }
+    }
+
+    /// Draws the overlay onto the image, if there is anything to show
+    pub fn draw(&self, framebuffer: &mut Framebuffer, now: Option<DateTime>) {
+        if let Some(text) = self.text.render(now) {
+            self.draw_text(framebuffer, &text);
+        }
+    }
+
+    fn draw_text(&self, framebuffer: &mut Framebuffer, text: &str) {
+        let (text_width, text_height) = font::text_size(text, self.scale);
+        let (box_width, box_height) = (text_width + PADDING * 2, text_height + PADDING * 2);
+        let (x, y) = self.corner.position(framebuffer, box_width, box_height);
+
+        // A light box on light areas and a dark one on dark areas disturbs the
+        // photo least, while the text always contrasts with the box. A fixed
+        // text colour picks the box instead.
+        let background = self.background.unwrap_or_else(|| {
+            let light = match self.foreground {
+                Some(foreground) => foreground.luminance() < 128,
+                None => framebuffer.average_luminance(x, y, box_width, box_height) >= 128,
+            };
+            if light {
+                Color::White
+            } else {
+                Color::Black
+            }
+        });
+        let foreground = self.foreground.unwrap_or(if background.luminance() >= 128 {
+            Color::Black
+        } else {
+            Color::White
+        });
+
+        framebuffer.fill_rect(x, y, box_width, box_height, background);
+        framebuffer.draw_text(x + PADDING, y + PADDING, text, self.scale, foreground);
+    }
+}
+
+/// Draws a QR code for a link sent with the photo, e.g. to the original or
+/// its album. Links too long to encode are skipped.
+pub fn draw_link(framebuffer: &mut Framebuffer, link: &str, corner: Corner) {
+    let Ok(code) = QrCode::encode(link.as_bytes(), EccLevel::Medium) else {
+        return;
+    };
+    let side = framebuffer::qr_side(&code, LINK_SCALE);
+    let (x, y) = corner.position(framebuffer, side, side);
+    framebuffer.draw_qr(x, y, &code, LINK_SCALE);
+}
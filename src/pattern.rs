@@ -0,0 +1,87 @@
+//
+// Generated full-screen patterns, used for clearing the panel, burn-in and
+// ghosting removal. Patterns are produced a row at a time in the panel's
+// packed 4-bit format so they can be streamed without a framebuffer.
+//
+use defmt::Format;
+
+use crate::color::Color;
+
+// 4x4 Bayer matrix, used to dither between neighbouring gradient colours
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+// Palette ordered roughly by lightness, so a gradient reads as a ramp
+const GRADIENT: [Color; 7] = [
+    Color::Black,
+    Color::Blue,
+    Color::Red,
+    Color::Green,
+    Color::Orange,
+    Color::Yellow,
+    Color::White,
+];
+
+#[derive(Debug, Clone, Copy, Format)]
+pub enum Pattern {
+    /// The whole panel in one colour
+    Solid(Color),
+    /// Vertical bars of every palette colour
+    ColourBars,
+    /// Alternating squares of two colours, `size` pixels wide
+    Checkerboard(Color, Color, u32),
+    /// A left-to-right ramp through the palette with dithered transitions
+    Gradient,
+}
+
+impl Pattern {
+    /// Colour of the pixel at (x, y) on a panel `width` pixels wide
+    pub fn pixel(&self, x: u32, y: u32, width: u32) -> Color {
+        match *self {
+            Pattern::Solid(color) => color,
+            Pattern::ColourBars => {
+                let bar = (x * Color::ALL.len() as u32) / width;
+                Color::ALL[bar as usize]
+            }
+            Pattern::Checkerboard(a, b, size) => {
+                let size = size.max(1);
+                if ((x / size) + (y / size)) % 2 == 0 {
+                    a
+                } else {
+                    b
+                }
+            }
+            Pattern::Gradient => {
+                // Position along the ramp in 1/16ths of a palette step
+                let steps = (GRADIENT.len() - 1) as u32;
+                let position = (x * steps * 16) / width;
+                let (index, fraction) = ((position / 16) as usize, (position % 16) as u8);
+                let threshold = BAYER_4X4[(y % 4) as usize][(x % 4) as usize];
+                if fraction > threshold {
+                    GRADIENT[(index + 1).min(GRADIENT.len() - 1)]
+                } else {
+                    GRADIENT[index]
+                }
+            }
+        }
+    }
+
+    /// Whether every row of the pattern is the same, so a rendered chunk can be resent as is
+    pub fn is_row_invariant(&self) -> bool {
+        matches!(self, Pattern::Solid(_) | Pattern::ColourBars)
+    }
+
+    /// Renders rows starting at `first_row` into `buffer`, which must hold a whole
+    /// number of packed rows of `width` pixels
+    pub fn render_rows(&self, buffer: &mut [u8], first_row: u32, width: u32) {
+        let row_bytes = (width / 2) as usize;
+        for (row, bytes) in buffer.chunks_exact_mut(row_bytes).enumerate() {
+            let y = first_row + row as u32;
+            for (column, byte) in bytes.iter_mut().enumerate() {
+                let x = column as u32 * 2;
+                let left = self.pixel(x, y, width).to_byte();
+                let right = self.pixel(x + 1, y, width).to_byte();
+                *byte = (left << 4) | right;
+            }
+        }
+    }
+}
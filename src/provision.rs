@@ -0,0 +1,260 @@
+//
+// Setup access point, for joining the frame to a network from a phone.
+//
+// The frame runs its own WPA2 network at 192.168.4.1 with the setup page on
+// port 80, and hands out addresses to a handful of clients with the minimal
+// DHCP server below (RFC 2131, just DISCOVER/REQUEST/RELEASE).
+//
+use alloc::{format, string::String, vec, vec::Vec};
+use defmt::{info, warn};
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    IpAddress, IpEndpoint, Ipv4Address, Ipv4Cidr, Stack, StaticConfigV4,
+};
+
+pub const ADDRESS: Ipv4Address = Ipv4Address::new(192, 168, 4, 1);
+const PREFIX_LEN: u8 = 24;
+const NETMASK: [u8; 4] = [255, 255, 255, 0];
+// Clients get 192.168.4.2 onwards
+const POOL_START: u8 = 2;
+const POOL_SIZE: usize = 8;
+const LEASE_SECS: u32 = 60 * 60;
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+// Fixed header up to and including the magic cookie
+const HEADER_LEN: usize = 240;
+const MAGIC_COOKIE: [u8; 4] = [0x63, 0x82, 0x53, 0x63];
+// Some clients ignore anything shorter than a BOOTP packet
+const MIN_PACKET_LEN: usize = 300;
+const PACKET_SIZE: usize = 576;
+
+const BOOT_REQUEST: u8 = 1;
+const BOOT_REPLY: u8 = 2;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+const RELEASE: u8 = 7;
+
+/// Name of the setup network for the frame with ID `frame_id`
+pub fn ssid(frame_id: &str) -> String {
+    format!("{}-setup", frame_id)
+}
+
+/// Network settings for the access point's interface
+pub fn network_config() -> embassy_net::Config {
+    embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(ADDRESS, PREFIX_LEN),
+        gateway: None,
+        dns_servers: Default::default(),
+    })
+}
+
+/// Where the setup page is while the access point is up
+pub fn setup_url() -> String {
+    format!("http://{}/", ADDRESS)
+}
+
+/// The parts of a client's message the server cares about
+#[derive(Debug, PartialEq, Eq)]
+struct Message {
+    kind: u8,
+    xid: [u8; 4],
+    flags: [u8; 2],
+    mac: [u8; 6],
+    client_address: [u8; 4],
+    requested: Option<[u8; 4]>,
+    server: Option<[u8; 4]>,
+}
+
+fn parse(packet: &[u8]) -> Option<Message> {
+    if packet.len() < HEADER_LEN || packet[0] != BOOT_REQUEST || packet[236..240] != MAGIC_COOKIE {
+        return None;
+    }
+    // Ethernet addresses only
+    if packet[1] != 1 || packet[2] != 6 {
+        return None;
+    }
+    let mut message = Message {
+        kind: 0,
+        xid: packet[4..8].try_into().ok()?,
+        flags: packet[10..12].try_into().ok()?,
+        mac: packet[28..34].try_into().ok()?,
+        client_address: packet[12..16].try_into().ok()?,
+        requested: None,
+        server: None,
+    };
+
+    let mut options = &packet[HEADER_LEN..];
+    while let Some((&code, rest)) = options.split_first() {
+        match code {
+            OPTION_PAD => {
+                options = rest;
+                continue;
+            }
+            OPTION_END => break,
+            _ => {}
+        }
+        let (&len, rest) = rest.split_first()?;
+        let value = rest.get(..len as usize)?;
+        match (code, value.len()) {
+            (OPTION_MESSAGE_TYPE, 1) => message.kind = value[0],
+            (OPTION_REQUESTED_ADDRESS, 4) => message.requested = value.try_into().ok(),
+            (OPTION_SERVER_ID, 4) => message.server = value.try_into().ok(),
+            _ => {}
+        }
+        options = &rest[len as usize..];
+    }
+    (message.kind != 0).then_some(message)
+}
+
+fn encode_reply(request: &Message, kind: u8, address: [u8; 4]) -> Vec<u8> {
+    let server = ADDRESS.octets();
+    let mut packet = vec![0u8; HEADER_LEN];
+    packet[0] = BOOT_REPLY;
+    packet[1] = 1;
+    packet[2] = 6;
+    packet[4..8].copy_from_slice(&request.xid);
+    packet[10..12].copy_from_slice(&request.flags);
+    if kind != NAK {
+        packet[16..20].copy_from_slice(&address);
+        packet[20..24].copy_from_slice(&server);
+    }
+    packet[28..34].copy_from_slice(&request.mac);
+    packet[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+    let mut option = |code: u8, value: &[u8]| {
+        packet.push(code);
+        packet.push(value.len() as u8);
+        packet.extend_from_slice(value);
+    };
+    option(OPTION_MESSAGE_TYPE, &[kind]);
+    option(OPTION_SERVER_ID, &server);
+    if kind != NAK {
+        option(OPTION_LEASE_TIME, &LEASE_SECS.to_be_bytes());
+        option(OPTION_SUBNET_MASK, &NETMASK);
+        option(OPTION_ROUTER, &server);
+        option(OPTION_DNS, &server);
+    }
+    packet.push(OPTION_END);
+    if packet.len() < MIN_PACKET_LEN {
+        packet.resize(MIN_PACKET_LEN, 0);
+    }
+    packet
+}
+
+/// Which client has each address in the pool
+struct Leases {
+    holders: [Option<[u8; 6]>; POOL_SIZE],
+}
+
+impl Leases {
+    fn new() -> Self {
+        Leases {
+            holders: [None; POOL_SIZE],
+        }
+    }
+
+    fn address(index: usize) -> [u8; 4] {
+        let [a, b, c, _] = ADDRESS.octets();
+        [a, b, c, POOL_START + index as u8]
+    }
+
+    /// The client's address, given out now if it doesn't have one yet
+    fn assign(&mut self, mac: [u8; 6]) -> Option<[u8; 4]> {
+        let index = match self.holders.iter().position(|h| *h == Some(mac)) {
+            Some(index) => index,
+            None => {
+                let index = self.holders.iter().position(Option::is_none)?;
+                self.holders[index] = Some(mac);
+                index
+            }
+        };
+        Some(Self::address(index))
+    }
+
+    fn release(&mut self, mac: [u8; 6]) {
+        for holder in self.holders.iter_mut().filter(|h| **h == Some(mac)) {
+            *holder = None;
+        }
+    }
+}
+
+// The reply to a client's message, if it gets one
+fn respond(leases: &mut Leases, message: &Message) -> Option<Vec<u8>> {
+    match message.kind {
+        DISCOVER => {
+            let address = leases.assign(message.mac)?;
+            Some(encode_reply(message, OFFER, address))
+        }
+        REQUEST => {
+            // Meant for another server
+            if message.server.is_some_and(|s| s != ADDRESS.octets()) {
+                return None;
+            }
+            let wanted = message
+                .requested
+                .or(Some(message.client_address).filter(|a| *a != [0; 4]));
+            match leases.assign(message.mac) {
+                Some(address) if wanted.is_none_or(|w| w == address) => {
+                    Some(encode_reply(message, ACK, address))
+                }
+                _ => Some(encode_reply(message, NAK, [0; 4])),
+            }
+        }
+        RELEASE => {
+            leases.release(message.mac);
+            None
+        }
+        _ => None,
+    }
+}
+
+#[embassy_executor::task]
+pub async fn dhcp_server(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = vec![0u8; PACKET_SIZE * 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = vec![0u8; PACKET_SIZE * 2];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if socket.bind(SERVER_PORT).is_err() {
+        warn!("DHCP: failed to bind port {}", SERVER_PORT);
+        return;
+    }
+    // Clients have no address yet, so every reply is broadcast
+    let clients = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::BROADCAST), CLIENT_PORT);
+
+    let mut leases = Leases::new();
+    let mut packet = vec![0u8; PACKET_SIZE];
+    loop {
+        let Ok((len, _)) = socket.recv_from(&mut packet).await else {
+            continue;
+        };
+        let Some(message) = parse(&packet[..len]) else {
+            continue;
+        };
+        if let Some(reply) = respond(&mut leases, &message) {
+            info!("DHCP: answering {} from {:02x}", message.kind, message.mac);
+            socket.send_to(&reply, clients).await.ok();
+        }
+    }
+}
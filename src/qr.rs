@@ -0,0 +1,564 @@
+//
+// QR code encoder for short byte strings: Wi-Fi join codes, setup pages and
+// links sent with photos. Byte mode only, versions 1 to 10 (up to 57x57
+// modules, 271 bytes at the lowest error correction level).
+//
+use alloc::{string::String, vec, vec::Vec};
+use defmt::Format;
+
+const MAX_VERSION: usize = 10;
+
+// Indexed by error correction level, then version; index 0 is unused
+const ECC_CODEWORDS_PER_BLOCK: [[u8; MAX_VERSION + 1]; 4] = [
+    [0, 7, 10, 15, 20, 26, 18, 20, 24, 30, 18],
+    [0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26],
+    [0, 13, 22, 18, 26, 18, 24, 18, 22, 20, 24],
+    [0, 17, 28, 22, 16, 22, 28, 26, 26, 24, 28],
+];
+const ERROR_CORRECTION_BLOCKS: [[u8; MAX_VERSION + 1]; 4] = [
+    [0, 1, 1, 1, 1, 1, 2, 2, 2, 2, 4],
+    [0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5],
+    [0, 1, 1, 2, 2, 4, 4, 6, 6, 8, 8],
+    [0, 1, 1, 2, 4, 4, 4, 5, 6, 8, 8],
+];
+
+// Weights used to score masks
+const PENALTY_RUN: i32 = 3;
+const PENALTY_BLOCK: i32 = 3;
+const PENALTY_FINDER: i32 = 40;
+const PENALTY_BALANCE: i32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct DataTooLong;
+
+/// How much of the code can be damaged and still read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum EccLevel {
+    /// About 7%
+    Low,
+    /// About 15%
+    Medium,
+    /// About 25%
+    Quartile,
+    /// About 30%
+    High,
+}
+
+impl EccLevel {
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    fn format_bits(self) -> u32 {
+        match self {
+            EccLevel::Low => 1,
+            EccLevel::Medium => 0,
+            EccLevel::Quartile => 3,
+            EccLevel::High => 2,
+        }
+    }
+}
+
+pub struct QrCode {
+    size: u32,
+    modules: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encodes `data` in the smallest version that fits at the given level
+    pub fn encode(data: &[u8], ecc: EccLevel) -> Result<Self, DataTooLong> {
+        let version = (1..=MAX_VERSION)
+            .find(|&v| data_capacity_bits(v, ecc) >= segment_bits(v, data.len()))
+            .ok_or(DataTooLong)?;
+
+        let codewords = add_ecc_and_interleave(&data_codewords(version, ecc, data), version, ecc);
+
+        let mut builder = Builder::new(version);
+        builder.draw_function_patterns(ecc);
+        builder.draw_codewords(&codewords);
+
+        let mut best = (i32::MAX, 0);
+        for mask in 0..8 {
+            builder.apply_mask(mask);
+            builder.draw_format_bits(ecc, mask);
+            let penalty = builder.penalty();
+            if penalty < best.0 {
+                best = (penalty, mask);
+            }
+            // Masking twice undoes it
+            builder.apply_mask(mask);
+        }
+        builder.apply_mask(best.1);
+        builder.draw_format_bits(ecc, best.1);
+
+        Ok(Self {
+            size: builder.size as u32,
+            modules: builder.modules,
+        })
+    }
+
+    /// Width and height in modules, not counting the quiet zone
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Whether the module is dark; anything outside the code is light
+    pub fn module(&self, x: u32, y: u32) -> bool {
+        x < self.size && y < self.size && self.modules[(y * self.size + x) as usize]
+    }
+}
+
+/// The payload phones recognise as an offer to join a WPA network
+pub fn wifi_join(ssid: &str, password: &str) -> String {
+    let mut payload = String::from("WIFI:S:");
+    push_escaped(&mut payload, ssid);
+    if password.is_empty() {
+        payload.push_str(";T:nopass;;");
+    } else {
+        payload.push_str(";T:WPA;P:");
+        push_escaped(&mut payload, password);
+        payload.push_str(";;");
+    }
+    payload
+}
+
+fn push_escaped(payload: &mut String, value: &str) {
+    for c in value.chars() {
+        if matches!(c, '\\' | ';' | ',' | ':' | '"') {
+            payload.push('\\');
+        }
+        payload.push(c);
+    }
+}
+
+fn raw_data_modules(version: usize) -> usize {
+    let mut modules = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let alignments = version / 7 + 2;
+        modules -= (25 * alignments - 10) * alignments - 55;
+        if version >= 7 {
+            modules -= 36;
+        }
+    }
+    modules
+}
+
+fn data_codewords_len(version: usize, ecc: EccLevel) -> usize {
+    raw_data_modules(version) / 8
+        - ECC_CODEWORDS_PER_BLOCK[ecc.index()][version] as usize
+            * ERROR_CORRECTION_BLOCKS[ecc.index()][version] as usize
+}
+
+fn data_capacity_bits(version: usize, ecc: EccLevel) -> usize {
+    data_codewords_len(version, ecc) * 8
+}
+
+// Mode indicator, character count and the data itself
+fn segment_bits(version: usize, len: usize) -> usize {
+    let count_bits = if version < 10 { 8 } else { 16 };
+    if len >= 1 << count_bits {
+        return usize::MAX;
+    }
+    4 + count_bits + len * 8
+}
+
+struct BitBuffer(Vec<u8>, usize);
+
+impl BitBuffer {
+    fn push(&mut self, value: u32, bits: usize) {
+        for i in (0..bits).rev() {
+            if self.1 % 8 == 0 {
+                self.0.push(0);
+            }
+            if (value >> i) & 1 != 0 {
+                *self.0.last_mut().unwrap() |= 0x80 >> (self.1 % 8);
+            }
+            self.1 += 1;
+        }
+    }
+}
+
+// The byte mode segment, terminated and padded to fill the version
+fn data_codewords(version: usize, ecc: EccLevel, data: &[u8]) -> Vec<u8> {
+    let capacity = data_capacity_bits(version, ecc);
+    let mut bits = BitBuffer(Vec::with_capacity(capacity / 8), 0);
+    bits.push(0b0100, 4);
+    bits.push(data.len() as u32, if version < 10 { 8 } else { 16 });
+    for &byte in data {
+        bits.push(byte as u32, 8);
+    }
+    let terminator = (capacity - bits.1).min(4);
+    bits.push(0, terminator);
+    bits.push(0, (8 - bits.1 % 8) % 8);
+    for pad in [0xec, 0x11].into_iter().cycle() {
+        if bits.1 >= capacity {
+            break;
+        }
+        bits.push(pad, 8);
+    }
+    bits.0
+}
+
+// Splits the data into blocks, appends Reed-Solomon codewords to each and
+// interleaves them
+fn add_ecc_and_interleave(data: &[u8], version: usize, ecc: EccLevel) -> Vec<u8> {
+    let blocks = ERROR_CORRECTION_BLOCKS[ecc.index()][version] as usize;
+    let ecc_len = ECC_CODEWORDS_PER_BLOCK[ecc.index()][version] as usize;
+    let raw_codewords = raw_data_modules(version) / 8;
+    let short_blocks = blocks - raw_codewords % blocks;
+    let short_block_len = raw_codewords / blocks;
+
+    let divisor = reed_solomon_divisor(ecc_len);
+    let mut split = Vec::with_capacity(blocks);
+    let mut start = 0;
+    for i in 0..blocks {
+        let len = short_block_len - ecc_len + usize::from(i >= short_blocks);
+        let mut block = data[start..start + len].to_vec();
+        start += len;
+        let remainder = reed_solomon_remainder(&block, &divisor);
+        if i < short_blocks {
+            // Placeholder so every block lines up; skipped when interleaving
+            block.push(0);
+        }
+        block.extend_from_slice(&remainder);
+        split.push(block);
+    }
+
+    let mut result = Vec::with_capacity(raw_codewords);
+    for i in 0..=short_block_len {
+        for (j, block) in split.iter().enumerate() {
+            if i != short_block_len - ecc_len || j >= short_blocks {
+                result.push(block[i]);
+            }
+        }
+    }
+    result
+}
+
+// Generator polynomial, highest power first without the leading 1
+fn reed_solomon_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0u8; degree];
+    result[degree - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_multiply(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    result
+}
+
+fn reed_solomon_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0u8; divisor.len()];
+    for &byte in data {
+        let factor = byte ^ result.remove(0);
+        result.push(0);
+        for (r, &d) in result.iter_mut().zip(divisor) {
+            *r ^= gf_multiply(d, factor);
+        }
+    }
+    result
+}
+
+// Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z = 0u8;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x1d);
+        z ^= ((y >> i) & 1) * x;
+    }
+    z
+}
+
+// The module grid while it is being drawn
+struct Builder {
+    version: usize,
+    size: i32,
+    modules: Vec<bool>,
+    // Finder, timing, alignment and format modules, which masks leave alone
+    function: Vec<bool>,
+}
+
+impl Builder {
+    fn new(version: usize) -> Self {
+        let size = version as i32 * 4 + 17;
+        let count = (size * size) as usize;
+        Self {
+            version,
+            size,
+            modules: vec![false; count],
+            function: vec![false; count],
+        }
+    }
+
+    fn index(&self, x: i32, y: i32) -> usize {
+        (y * self.size + x) as usize
+    }
+
+    fn module(&self, x: i32, y: i32) -> bool {
+        self.modules[self.index(x, y)]
+    }
+
+    fn set_function(&mut self, x: i32, y: i32, dark: bool) {
+        let index = self.index(x, y);
+        self.modules[index] = dark;
+        self.function[index] = true;
+    }
+
+    fn draw_function_patterns(&mut self, ecc: EccLevel) {
+        for i in 0..self.size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+
+        self.draw_finder(3, 3);
+        self.draw_finder(self.size - 4, 3);
+        self.draw_finder(3, self.size - 4);
+
+        let positions = self.alignment_positions();
+        let last = positions.len().saturating_sub(1);
+        for (i, &x) in positions.iter().enumerate() {
+            for (j, &y) in positions.iter().enumerate() {
+                // Skip the corners taken by finders
+                let finder = (i == 0 && (j == 0 || j == last)) || (i == last && j == 0);
+                if !finder {
+                    self.draw_alignment(x, y);
+                }
+            }
+        }
+
+        // Reserve the format modules; the real mask is drawn later
+        self.draw_format_bits(ecc, 0);
+        self.draw_version();
+    }
+
+    // A 7x7 finder with its light separator, centred on (x, y)
+    fn draw_finder(&mut self, x: i32, y: i32) {
+        for dy in -4..=4 {
+            for dx in -4..=4 {
+                let (px, py) = (x + dx, y + dy);
+                if (0..self.size).contains(&px) && (0..self.size).contains(&py) {
+                    let distance = dx.abs().max(dy.abs());
+                    self.set_function(px, py, distance != 2 && distance != 4);
+                }
+            }
+        }
+    }
+
+    fn draw_alignment(&mut self, x: i32, y: i32) {
+        for dy in -2..=2 {
+            for dx in -2..=2 {
+                self.set_function(x + dx, y + dy, dx.abs().max(dy.abs()) != 1);
+            }
+        }
+    }
+
+    fn alignment_positions(&self) -> Vec<i32> {
+        if self.version == 1 {
+            return Vec::new();
+        }
+        let version = self.version as i32;
+        let count = version / 7 + 2;
+        let step = (version * 4 + count * 2 + 1) / (count * 2 - 2) * 2;
+        let mut positions: Vec<i32> = (0..count - 1).map(|i| self.size - 7 - i * step).collect();
+        positions.push(6);
+        positions.reverse();
+        positions
+    }
+
+    fn draw_format_bits(&mut self, ecc: EccLevel, mask: u32) {
+        let data = ecc.format_bits() << 3 | mask;
+        let mut remainder = data;
+        for _ in 0..10 {
+            remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+        }
+        let bits = (data << 10 | remainder) ^ 0x5412;
+        let bit = |i: i32| (bits >> i) & 1 != 0;
+
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+
+        let size = self.size;
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    fn draw_version(&mut self) {
+        if self.version < 7 {
+            return;
+        }
+        let data = self.version as u32;
+        let mut remainder = data;
+        for _ in 0..12 {
+            remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1f25);
+        }
+        let bits = data << 12 | remainder;
+        for i in 0..18 {
+            let dark = (bits >> i) & 1 != 0;
+            let (a, b) = (self.size - 11 + i % 3, i / 3);
+            self.set_function(a, b, dark);
+            self.set_function(b, a, dark);
+        }
+    }
+
+    // Places the codewords in the zigzag order, two columns at a time
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let total_bits = codewords.len() * 8;
+        let mut i = 0;
+        let mut right = self.size - 1;
+        while right >= 1 {
+            // Skip the vertical timing pattern
+            if right == 6 {
+                right = 5;
+            }
+            for vertical in 0..self.size {
+                for j in 0..2 {
+                    let x = right - j;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward {
+                        self.size - 1 - vertical
+                    } else {
+                        vertical
+                    };
+                    let index = self.index(x, y);
+                    if !self.function[index] && i < total_bits {
+                        self.modules[index] = (codewords[i >> 3] >> (7 - (i & 7))) & 1 != 0;
+                        i += 1;
+                    }
+                }
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                let index = self.index(x, y);
+                self.modules[index] ^= invert && !self.function[index];
+            }
+        }
+    }
+
+    // Lower is easier to scan: penalises long runs, 2x2 blocks, finder-like
+    // patterns and an imbalance of dark and light
+    fn penalty(&self) -> i32 {
+        let size = self.size;
+        let mut result = 0;
+
+        for transpose in [false, true] {
+            for a in 0..size {
+                let mut run_color = false;
+                let mut run_length = 0;
+                let mut history = RunHistory::new(size);
+                for b in 0..size {
+                    let dark = if transpose {
+                        self.module(a, b)
+                    } else {
+                        self.module(b, a)
+                    };
+                    if dark == run_color {
+                        run_length += 1;
+                        if run_length == 5 {
+                            result += PENALTY_RUN;
+                        } else if run_length > 5 {
+                            result += 1;
+                        }
+                    } else {
+                        history.push(run_length);
+                        if !run_color {
+                            result += history.finder_patterns() * PENALTY_FINDER;
+                        }
+                        run_color = dark;
+                        run_length = 1;
+                    }
+                }
+                result += history.finish(run_color, run_length) * PENALTY_FINDER;
+            }
+        }
+
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let dark = self.module(x, y);
+                if dark == self.module(x + 1, y)
+                    && dark == self.module(x, y + 1)
+                    && dark == self.module(x + 1, y + 1)
+                {
+                    result += PENALTY_BLOCK;
+                }
+            }
+        }
+
+        let dark = self.modules.iter().filter(|&&m| m).count() as i32;
+        let total = size * size;
+        // Smallest k such that the dark share is within (45-5k)% to (55+5k)%
+        let k = ((dark * 20 - total * 10).abs() + total - 1) / total - 1;
+        result + k * PENALTY_BALANCE
+    }
+}
+
+// Lengths of the last seven runs along a row or column, newest first
+struct RunHistory {
+    size: i32,
+    runs: [i32; 7],
+}
+
+impl RunHistory {
+    fn new(size: i32) -> Self {
+        Self { size, runs: [0; 7] }
+    }
+
+    fn push(&mut self, mut length: i32) {
+        // The quiet zone counts as light before the first run
+        if self.runs[0] == 0 {
+            length += self.size;
+        }
+        self.runs.copy_within(0..6, 1);
+        self.runs[0] = length;
+    }
+
+    // 1:1:3:1:1 dark runs with four light modules on either side; called after a light run
+    fn finder_patterns(&self) -> i32 {
+        let r = &self.runs;
+        let n = r[1];
+        let core = n > 0 && r[2] == n && r[3] == n * 3 && r[4] == n && r[5] == n;
+        i32::from(core && r[0] >= n * 4 && r[6] >= n)
+            + i32::from(core && r[6] >= n * 4 && r[0] >= n)
+    }
+
+    fn finish(mut self, run_color: bool, mut run_length: i32) -> i32 {
+        if run_color {
+            self.push(run_length);
+            run_length = 0;
+        }
+        // The quiet zone counts as light after the last run
+        self.push(run_length + self.size);
+        self.finder_patterns()
+    }
+}
@@ -0,0 +1,396 @@
+//
+// Remote control and Home Assistant integration over MQTT.
+//
+// Topics live under `photo-frame/<frame id>/`:
+//
+//   availability   "online", or "offline" as the last will
+//   state          JSON: battery, battery_mv, rssi, last_refresh, image_id,
+//                  uptime_s
+//   command        "refresh", "set_url <url>", "clear", "sleep" or
+//                  "update <firmware url>"
+//
+// Discovery configs are published retained under `homeassistant/` on each
+// connection so the frame shows up as a device with sensors and buttons.
+//
+use alloc::{format, string::String, vec, vec::Vec};
+use defmt::{info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_net::{dns::DnsQueryType, tcp::TcpSocket, Stack};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use serde::Serialize;
+
+use crate::color::Color;
+use crate::config;
+use crate::draw::SharedDisplay;
+use crate::identity;
+use crate::mqtt::{self, Client, ConnectOptions, Message, Will};
+use crate::ota;
+use crate::state::{self, Command};
+
+const TOPIC_PREFIX: &str = "photo-frame";
+const DISCOVERY_PREFIX: &str = "homeassistant";
+const SOCKET_BUFFER_SIZE: usize = 2048;
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+// Pings go out well inside the keep-alive so the broker never gives up on us
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const STATE_INTERVAL: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_MIN: Duration = Duration::from_secs(5);
+const RETRY_MAX: Duration = Duration::from_secs(300);
+
+/// Where the broker is, from the build-time configuration
+pub struct Settings {
+    pub host: &'static str,
+    pub port: u16,
+    pub username: Option<&'static str>,
+    pub password: Option<&'static str>,
+}
+
+impl Settings {
+    /// `broker` is "host" or "host:port"
+    pub fn parse(
+        broker: &'static str,
+        username: Option<&'static str>,
+        password: Option<&'static str>,
+    ) -> Option<Self> {
+        let (host, port) = match broker.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (broker, 1883),
+        };
+        Some(Self {
+            host,
+            port,
+            username,
+            password,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct StateBody<'a> {
+    battery: Option<u8>,
+    battery_mv: Option<u16>,
+    rssi: Option<i8>,
+    last_refresh: Option<&'a str>,
+    image_id: Option<&'a str>,
+    uptime_s: u64,
+}
+
+#[derive(Serialize)]
+struct Device<'a> {
+    identifiers: &'a [&'a str],
+    name: &'a str,
+    manufacturer: &'a str,
+    model: &'a str,
+    sw_version: &'a str,
+}
+
+#[derive(Serialize)]
+struct Discovery<'a> {
+    name: &'a str,
+    unique_id: &'a str,
+    availability_topic: &'a str,
+    device: &'a Device<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_topic: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_template: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_topic: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_press: Option<&'a str>,
+}
+
+// A Home Assistant entity for the frame
+struct Entity {
+    component: &'static str,
+    object_id: &'static str,
+    name: &'static str,
+    value_template: Option<&'static str>,
+    device_class: Option<&'static str>,
+    unit_of_measurement: Option<&'static str>,
+    entity_category: Option<&'static str>,
+    payload_press: Option<&'static str>,
+}
+
+const ENTITIES: &[Entity] = &[
+    Entity {
+        component: "sensor",
+        object_id: "battery",
+        name: "Battery",
+        value_template: Some("{{ value_json.battery }}"),
+        device_class: Some("battery"),
+        unit_of_measurement: Some("%"),
+        entity_category: None,
+        payload_press: None,
+    },
+    Entity {
+        component: "sensor",
+        object_id: "battery_voltage",
+        name: "Battery voltage",
+        value_template: Some("{{ value_json.battery_mv }}"),
+        device_class: Some("voltage"),
+        unit_of_measurement: Some("mV"),
+        entity_category: Some("diagnostic"),
+        payload_press: None,
+    },
+    Entity {
+        component: "sensor",
+        object_id: "rssi",
+        name: "Wi-Fi signal",
+        value_template: Some("{{ value_json.rssi }}"),
+        device_class: Some("signal_strength"),
+        unit_of_measurement: Some("dBm"),
+        entity_category: Some("diagnostic"),
+        payload_press: None,
+    },
+    Entity {
+        component: "sensor",
+        object_id: "last_refresh",
+        name: "Last refresh",
+        value_template: Some("{{ value_json.last_refresh }}"),
+        device_class: None,
+        unit_of_measurement: None,
+        entity_category: Some("diagnostic"),
+        payload_press: None,
+    },
+    Entity {
+        component: "sensor",
+        object_id: "image_id",
+        name: "Image",
+        value_template: Some("{{ value_json.image_id }}"),
+        device_class: None,
+        unit_of_measurement: None,
+        entity_category: None,
+        payload_press: None,
+    },
+    Entity {
+        component: "button",
+        object_id: "refresh",
+        name: "Refresh",
+        value_template: None,
+        device_class: None,
+        unit_of_measurement: None,
+        entity_category: None,
+        payload_press: Some("refresh"),
+    },
+    Entity {
+        component: "button",
+        object_id: "clear",
+        name: "Clear",
+        value_template: None,
+        device_class: None,
+        unit_of_measurement: None,
+        entity_category: None,
+        payload_press: Some("clear"),
+    },
+];
+
+// Topic names for one frame
+struct Topics {
+    id: String,
+    availability: String,
+    state: String,
+    command: String,
+}
+
+impl Topics {
+    fn new(id: String) -> Self {
+        let base = format!("{}/{}", TOPIC_PREFIX, id);
+        Self {
+            availability: format!("{}/availability", base),
+            state: format!("{}/state", base),
+            command: format!("{}/command", base),
+            id,
+        }
+    }
+}
+
+fn to_json(value: &impl Serialize, capacity: usize) -> Option<Vec<u8>> {
+    let mut json = vec![0u8; capacity];
+    let len = serde_json_core::to_slice(value, &mut json).ok()?;
+    json.truncate(len);
+    Some(json)
+}
+
+fn state_json() -> Option<Vec<u8>> {
+    let state = state::get();
+    let body = StateBody {
+        battery: state.battery.map(|b| b.percent),
+        battery_mv: state.battery.map(|b| b.millivolts),
+        rssi: state.rssi,
+        last_refresh: state.last_refresh.map(|(outcome, _)| outcome.as_str()),
+        image_id: state.image_id.as_deref(),
+        uptime_s: Instant::now().as_secs(),
+    };
+    to_json(&body, 256)
+}
+
+async fn publish_discovery(client: &mut Client<'_>, topics: &Topics) -> Result<(), mqtt::Error> {
+    let name = identity::frame_name();
+    let device = Device {
+        identifiers: &[topics.id.as_str()],
+        name: &name,
+        manufacturer: "DIY",
+        model: "ESP32-S3 photo frame",
+        sw_version: env!("CARGO_PKG_VERSION"),
+    };
+    for entity in ENTITIES {
+        let unique_id = format!("{}_{}", topics.id, entity.object_id);
+        let is_button = entity.payload_press.is_some();
+        let body = Discovery {
+            name: entity.name,
+            unique_id: &unique_id,
+            availability_topic: &topics.availability,
+            device: &device,
+            state_topic: (!is_button).then_some(topics.state.as_str()),
+            value_template: entity.value_template,
+            device_class: entity.device_class,
+            unit_of_measurement: entity.unit_of_measurement,
+            entity_category: entity.entity_category,
+            command_topic: is_button.then_some(topics.command.as_str()),
+            payload_press: entity.payload_press,
+        };
+        let topic = format!(
+            "{}/{}/{}/{}/config",
+            DISCOVERY_PREFIX, entity.component, topics.id, entity.object_id
+        );
+        match to_json(&body, 768) {
+            Some(json) => client.publish(&topic, &json, true).await?,
+            None => warn!("Discovery config for {} too large", entity.object_id),
+        }
+    }
+    Ok(())
+}
+
+async fn publish_state(client: &mut Client<'_>, topics: &Topics) -> Result<(), mqtt::Error> {
+    match state_json() {
+        Some(json) => client.publish(&topics.state, &json, true).await,
+        None => {
+            warn!("MQTT state too large");
+            Ok(())
+        }
+    }
+}
+
+async fn handle_command(message: &Message, display: &SharedDisplay) {
+    let Ok(payload) = core::str::from_utf8(&message.payload) else {
+        warn!("MQTT command is not UTF-8");
+        return;
+    };
+    let payload = payload.trim();
+    info!("MQTT command: {}", payload);
+    let (command, argument) = payload.split_once(' ').unwrap_or((payload, ""));
+    match command {
+        "refresh" => state::send_command(Command::Refresh),
+        "sleep" => state::send_command(Command::Sleep),
+        "set_url" if !argument.trim().is_empty() => {
+            let mut config = config::get();
+            config.server_url = String::from(argument.trim());
+            if let Err(e) = config::set(config) {
+                warn!("Rejected server URL: {:?}", e);
+            }
+        }
+        "update" if !argument.trim().is_empty() => ota::request(String::from(argument.trim())),
+        "clear" => {
+            let mut display = display.lock().await;
+            let result = match display.init().await {
+                Ok(()) => display.clear(Color::White).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!("Failed to clear display: {:?}", e);
+            }
+            display.sleep().await.ok();
+        }
+        _ => warn!("Unknown MQTT command: {}", payload),
+    }
+}
+
+async fn session(
+    stack: Stack<'_>,
+    display: &SharedDisplay,
+    settings: &Settings,
+    topics: &Topics,
+) -> Result<(), mqtt::Error> {
+    let address = stack
+        .dns_query(settings.host, DnsQueryType::A)
+        .await
+        .ok()
+        .and_then(|addresses| addresses.first().copied())
+        .ok_or(mqtt::Error::Network)?;
+
+    let mut rx_buffer = vec![0u8; SOCKET_BUFFER_SIZE];
+    let mut tx_buffer = vec![0u8; SOCKET_BUFFER_SIZE];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_keep_alive(Some(PING_INTERVAL));
+    with_timeout(CONNECT_TIMEOUT, socket.connect((address, settings.port)))
+        .await
+        .map_err(|_| mqtt::Error::Timeout)?
+        .map_err(|_| mqtt::Error::Network)?;
+
+    let options = ConnectOptions {
+        client_id: &topics.id,
+        username: settings.username,
+        password: settings.password,
+        keep_alive: KEEP_ALIVE,
+        will: Some(Will {
+            topic: &topics.availability,
+            message: b"offline",
+            retain: true,
+        }),
+    };
+    let mut client = Client::connect(socket, &options).await?;
+    info!("MQTT connected to {}", settings.host);
+
+    publish_discovery(&mut client, topics).await?;
+    client
+        .publish(&topics.availability, b"online", true)
+        .await?;
+    client.subscribe(&topics.command).await?;
+    publish_state(&mut client, topics).await?;
+
+    let mut last_state = Instant::now();
+    loop {
+        match select(client.next_message(), Timer::after(PING_INTERVAL)).await {
+            Either::First(message) => {
+                let message = message?;
+                if message.topic == topics.command {
+                    handle_command(&message, display).await;
+                    publish_state(&mut client, topics).await?;
+                    last_state = Instant::now();
+                }
+            }
+            Either::Second(()) => client.ping().await?,
+        }
+        if last_state.elapsed() >= STATE_INTERVAL {
+            publish_state(&mut client, topics).await?;
+            last_state = Instant::now();
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn mqtt(stack: Stack<'static>, display: &'static SharedDisplay, settings: Settings) {
+    let topics = Topics::new(identity::frame_id());
+    let mut retry = RETRY_MIN;
+    loop {
+        stack.wait_config_up().await;
+        let started = Instant::now();
+        if let Err(e) = session(stack, display, &settings, &topics).await {
+            warn!("MQTT session ended: {:?}", e);
+        }
+        // Back off while the broker keeps failing, but not after a long session
+        if started.elapsed() > RETRY_MAX {
+            retry = RETRY_MIN;
+        }
+        Timer::after(retry).await;
+        retry = (retry * 2).min(RETRY_MAX);
+    }
+}
@@ -0,0 +1,580 @@
+//
+// Small HTTP server for looking at and configuring the frame from the LAN:
+//
+//   GET  /          setup page
+//   GET  /status    JSON: ID, name, IP, RSSI, heap, battery, last refresh, uptime
+//   POST /config    JSON body with any of server_url, refresh_interval (s),
+//                   wifi_ssid, wifi_password, name and panel (the init
+//                   sequence, from the next boot); see below
+//   POST /refresh   fetch and show a new image now
+//   PUT  /image     show the EPD image in the body now, streamed to the panel
+//   POST /update    JSON body with the url of signed firmware to install
+//
+// One connection is served at a time. With awake windows configured the
+// server only listens inside them, once the clock is set.
+//
+// On the LAN, POST /config and POST /update need the FRAME_CONFIG_TOKEN given at
+// build time in an X-Config-Token header, and are refused outside the awake
+// windows, or before the clock is set if there are any. The setup access point
+// has a password of its own, so it takes changes without a token.
+//
+use alloc::{format, string::String, vec, vec::Vec};
+use defmt::{info, warn, Format};
+use embassy_net::{tcp::TcpSocket, Stack};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_io_async::Write;
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+use crate::config::{self, InvalidConfig};
+use crate::draw::{self, SharedDisplay, DISPLAY_BUFFER_SIZE};
+use crate::identity;
+use crate::ota;
+use crate::schedule::DailyWindow;
+use crate::state::{self, Command, RefreshOutcome};
+use crate::wifi;
+
+pub const PORT: u16 = 80;
+const SOCKET_BUFFER_SIZE: usize = 1024;
+// Longest request line and headers accepted
+const MAX_HEAD_LEN: usize = 1024;
+const MAX_BODY_LEN: usize = 1024;
+// Bytes read from the socket at a time while streaming an image
+const IMAGE_CHUNK_SIZE: usize = 4096;
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+// How often to look again at the awake windows while waiting
+const WINDOW_POLL: Duration = Duration::from_secs(60);
+
+const SETUP_PAGE: &str = include_str!("setup.html");
+const TOKEN_HEADER: &str = "x-config-token";
+
+/// Who may change the settings or install firmware, with POST /config and /update
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ConfigAccess {
+    /// Anyone who can connect
+    Open,
+    /// Requests carrying this token, inside the awake windows
+    Token(&'static str),
+    /// Nobody
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+enum Error {
+    Network,
+    Timeout,
+    HeadTooLarge,
+    BadRequest,
+}
+
+impl From<embassy_net::tcp::Error> for Error {
+    fn from(_: embassy_net::tcp::Error) -> Self {
+        Error::Network
+    }
+}
+
+enum ImageError {
+    Request(Error),
+    Display(draw::Error),
+}
+
+impl From<Error> for ImageError {
+    fn from(e: Error) -> Self {
+        ImageError::Request(e)
+    }
+}
+
+impl From<draw::Error> for ImageError {
+    fn from(e: draw::Error) -> Self {
+        ImageError::Display(e)
+    }
+}
+
+struct Request<'a> {
+    method: &'a str,
+    path: &'a str,
+    content_length: usize,
+    config_token: Option<&'a str>,
+}
+
+// Parses the request line and the headers we care about
+fn parse_head(head: &str) -> Option<Request<'_>> {
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let (method, target) = (request_line.next()?, request_line.next()?);
+    let path = target.split('?').next()?;
+
+    let mut content_length = 0;
+    let mut config_token = None;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok()?;
+            } else if name.eq_ignore_ascii_case(TOKEN_HEADER) {
+                config_token = Some(value.trim());
+            }
+        }
+    }
+    Some(Request {
+        method,
+        path,
+        content_length,
+        config_token,
+    })
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn ok(status: u16) -> Self {
+        Self::json(status, Vec::from(*b"{\"ok\":true}"))
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self::json(
+            status,
+            format!("{{\"error\":\"{}\"}}", message).into_bytes(),
+        )
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Content",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+#[derive(Serialize)]
+struct StatusBody<'a> {
+    id: &'a str,
+    name: &'a str,
+    ip: Option<&'a str>,
+    rssi: Option<i8>,
+    heap_size: usize,
+    heap_used: usize,
+    battery_mv: Option<u16>,
+    battery_percent: Option<u8>,
+    last_refresh: Option<&'a str>,
+    last_refresh_age_s: Option<u64>,
+    uptime_s: u64,
+    firmware: &'a str,
+    server_url: &'a str,
+    refresh_interval: u64,
+    wifi_ssid: &'a str,
+    panel: &'a str,
+}
+
+// Owned strings, so escapes such as \" in a password are undone
+#[derive(Deserialize)]
+struct ConfigBody {
+    server_url: Option<heapless::String<{ config::MAX_URL_LEN }>>,
+    refresh_interval: Option<u32>,
+    wifi_ssid: Option<heapless::String<{ config::MAX_SSID_LEN }>>,
+    wifi_password: Option<heapless::String<{ config::MAX_PASSWORD_LEN }>>,
+    name: Option<heapless::String<{ config::MAX_NAME_LEN }>>,
+    panel: Option<heapless::String<{ config::MAX_PANEL_LEN }>>,
+}
+
+#[derive(Deserialize)]
+struct UpdateBody<'a> {
+    url: &'a str,
+}
+
+fn status(stack: Stack<'_>) -> Response {
+    let ip = stack
+        .config_v4()
+        .map(|c| format!("{}", c.address.address()));
+    let heap = esp_alloc::HEAP.stats();
+    let state = state::get();
+    let config = config::get();
+    let id = identity::frame_id();
+    let body = StatusBody {
+        id: &id,
+        name: &config.name,
+        ip: ip.as_deref(),
+        rssi: state.rssi,
+        heap_size: heap.size,
+        heap_used: heap.current_usage,
+        battery_mv: state.battery.map(|b| b.millivolts),
+        battery_percent: state.battery.map(|b| b.percent),
+        last_refresh: state.last_refresh.map(|(outcome, _)| outcome.as_str()),
+        last_refresh_age_s: state.last_refresh.map(|(_, at)| at.elapsed().as_secs()),
+        uptime_s: Instant::now().as_secs(),
+        firmware: env!("CARGO_PKG_VERSION"),
+        server_url: &config.server_url,
+        refresh_interval: config.refresh_interval.as_secs(),
+        wifi_ssid: &config.wifi_ssid,
+        panel: &config.panel,
+    };
+    let mut json = vec![0u8; 768];
+    match serde_json_core::to_slice(&body, &mut json) {
+        Ok(len) => {
+            json.truncate(len);
+            Response::json(200, json)
+        }
+        Err(_) => Response::error(500, "status too large"),
+    }
+}
+
+// Compares without stopping at the first difference, so timing gives nothing away
+fn token_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Why a settings change isn't allowed, if it isn't
+fn check_access(
+    access: ConfigAccess,
+    token: Option<&str>,
+    windows: &[DailyWindow],
+) -> Option<Response> {
+    match access {
+        ConfigAccess::Open => None,
+        ConfigAccess::Closed => Some(Response::error(403, "settings are locked")),
+        ConfigAccess::Token(expected) => {
+            if !token.is_some_and(|token| token_matches(token, expected)) {
+                Some(Response::error(403, "wrong or missing token"))
+            } else if !windows.is_empty()
+                && !clock::local_time()
+                    .is_some_and(|now| windows.iter().any(|w| w.contains(now.seconds_of_day())))
+            {
+                Some(Response::error(403, "outside awake window"))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+fn update_config(body: &[u8]) -> Response {
+    // One string at a time is unescaped here
+    let mut unescaped = [0u8; config::MAX_URL_LEN];
+    let Ok((update, _)) = serde_json_core::from_slice_escaped::<ConfigBody>(body, &mut unescaped)
+    else {
+        return Response::error(400, "invalid JSON or value too long");
+    };
+
+    let old = config::get();
+    let mut new = old.clone();
+    if let Some(url) = update.server_url {
+        new.server_url = String::from(url.as_str());
+    }
+    if let Some(seconds) = update.refresh_interval {
+        new.refresh_interval = Duration::from_secs(seconds as u64);
+    }
+    if let Some(ssid) = update.wifi_ssid {
+        new.wifi_ssid = String::from(ssid.as_str());
+    }
+    if let Some(password) = update.wifi_password {
+        new.wifi_password = String::from(password.as_str());
+    }
+    if let Some(name) = update.name {
+        new.name = String::from(name.as_str());
+    }
+    if let Some(panel) = update.panel {
+        new.panel = String::from(panel.as_str());
+    }
+
+    match config::set(new.clone()) {
+        Ok(()) => {
+            info!("Configuration updated");
+            if new.wifi_ssid != old.wifi_ssid || new.wifi_password != old.wifi_password {
+                wifi::restart();
+            }
+            Response::ok(200)
+        }
+        Err(e) => Response::error(
+            400,
+            match e {
+                InvalidConfig::UrlTooLong => "server_url too long",
+                InvalidConfig::SsidTooLong => "wifi_ssid too long",
+                InvalidConfig::PasswordTooLong => "wifi_password too long",
+                InvalidConfig::NameTooLong => "name too long",
+                InvalidConfig::IntervalTooShort => "refresh_interval too short",
+                InvalidConfig::UnknownPanel => "unknown panel",
+            },
+        ),
+    }
+}
+
+fn request_update(body: &[u8]) -> Response {
+    match serde_json_core::from_slice::<UpdateBody>(body) {
+        Ok((update, _)) => {
+            ota::request(String::from(update.url));
+            Response::ok(202)
+        }
+        Err(_) => Response::error(400, "invalid JSON"),
+    }
+}
+
+// What went wrong with a pushed image, as an HTTP response
+fn image_error(e: draw::Error) -> Response {
+    match e {
+        draw::Error::InvalidMagic => Response::error(415, "not an EPD7 image"),
+        draw::Error::InvalidVersion => Response::error(415, "unsupported EPD version"),
+        draw::Error::InvalidDimensions => Response::error(422, "wrong image dimensions"),
+        draw::Error::BufferTooSmall => Response::error(400, "image truncated"),
+        draw::Error::TemperatureOutOfRange(_) => {
+            Response::error(503, "panel temperature out of range")
+        }
+        draw::Error::SpiError(_) => Response::error(500, "panel error"),
+    }
+}
+
+async fn put_image(
+    socket: &mut TcpSocket<'_>,
+    display: &SharedDisplay,
+    received: &[u8],
+    len: usize,
+) -> Result<Response, Error> {
+    let mut display = display.lock().await;
+    let result = match display.init().await {
+        Ok(()) => stream_image(socket, &mut display, received, len).await,
+        Err(e) => Err(e.into()),
+    };
+    display.sleep().await.ok();
+
+    match result {
+        Ok(()) => {
+            info!("Showing pushed image");
+            state::record_refresh(RefreshOutcome::Ok);
+            Ok(Response::ok(200))
+        }
+        Err(ImageError::Display(e)) => {
+            warn!("Pushed image failed: {:?}", e);
+            Ok(image_error(e))
+        }
+        Err(ImageError::Request(e)) => Err(e),
+    }
+}
+
+// Passes the body to the panel as it arrives, starting with what came in with the headers
+async fn stream_image(
+    socket: &mut TcpSocket<'_>,
+    display: &mut draw::EPD7in3f<'_>,
+    received: &[u8],
+    len: usize,
+) -> Result<(), ImageError> {
+    let mut stream = display.stream_epd();
+    let received = &received[..received.len().min(len)];
+    stream.write(received).await?;
+
+    let mut remaining = len - received.len();
+    let mut buffer = vec![0u8; IMAGE_CHUNK_SIZE];
+    while remaining > 0 {
+        let want = remaining.min(buffer.len());
+        let read = with_timeout(IO_TIMEOUT, socket.read(&mut buffer[..want]))
+            .await
+            .map_err(|_| Error::Timeout)?
+            .map_err(Error::from)?;
+        if read == 0 {
+            return Err(Error::BadRequest.into());
+        }
+        stream.write(&buffer[..read]).await?;
+        remaining -= read;
+    }
+    Ok(stream.finish().await?)
+}
+
+async fn read_body(
+    socket: &mut TcpSocket<'_>,
+    buffer: &mut Vec<u8>,
+    len: usize,
+) -> Result<(), Error> {
+    while buffer.len() < len {
+        let mut chunk = [0u8; 256];
+        let want = (len - buffer.len()).min(chunk.len());
+        let read = with_timeout(IO_TIMEOUT, socket.read(&mut chunk[..want]))
+            .await
+            .map_err(|_| Error::Timeout)??;
+        if read == 0 {
+            return Err(Error::BadRequest);
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+    Ok(())
+}
+
+// Reads up to the end of the headers, returning them and any body bytes that came with them
+async fn read_head(socket: &mut TcpSocket<'_>) -> Result<(String, Vec<u8>), Error> {
+    let mut buffer = vec![0u8; MAX_HEAD_LEN];
+    let mut filled = 0;
+    loop {
+        if let Some(end) = buffer[..filled].windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = core::str::from_utf8(&buffer[..end]).map_err(|_| Error::BadRequest)?;
+            return Ok((String::from(head), buffer[end + 4..filled].to_vec()));
+        }
+        if filled == buffer.len() {
+            return Err(Error::HeadTooLarge);
+        }
+        let read = with_timeout(IO_TIMEOUT, socket.read(&mut buffer[filled..]))
+            .await
+            .map_err(|_| Error::Timeout)??;
+        if read == 0 {
+            return Err(Error::BadRequest);
+        }
+        filled += read;
+    }
+}
+
+async fn handle(
+    socket: &mut TcpSocket<'_>,
+    stack: Stack<'_>,
+    display: &SharedDisplay,
+    access: ConfigAccess,
+    awake_windows: &[DailyWindow],
+) -> Result<(), Error> {
+    let response = match read_head(socket).await {
+        Ok((head, mut body)) => match parse_head(&head) {
+            None => Response::error(400, "bad request"),
+            Some(request) => {
+                info!("{} {}", request.method, request.path);
+                match (request.method, request.path) {
+                    ("GET", "/") => Response {
+                        status: 200,
+                        content_type: "text/html",
+                        body: Vec::from(SETUP_PAGE.as_bytes()),
+                    },
+                    ("GET", "/status") => status(stack),
+                    ("POST", "/config") if request.content_length > MAX_BODY_LEN => {
+                        Response::error(413, "body too large")
+                    }
+                    ("POST", "/config") => {
+                        match check_access(access, request.config_token, awake_windows) {
+                            Some(refused) => refused,
+                            None => {
+                                read_body(socket, &mut body, request.content_length).await?;
+                                update_config(&body)
+                            }
+                        }
+                    }
+                    ("PUT", "/image") if request.content_length == 0 => {
+                        Response::error(411, "content length required")
+                    }
+                    // Header plus pixels, with some slack for trailing bytes
+                    ("PUT", "/image") if request.content_length > DISPLAY_BUFFER_SIZE * 2 => {
+                        Response::error(413, "image too large")
+                    }
+                    ("PUT", "/image") => {
+                        put_image(socket, display, &body, request.content_length).await?
+                    }
+                    ("POST", "/update") if request.content_length > MAX_BODY_LEN => {
+                        Response::error(413, "body too large")
+                    }
+                    ("POST", "/update") => {
+                        match check_access(access, request.config_token, awake_windows) {
+                            Some(refused) => refused,
+                            None => {
+                                read_body(socket, &mut body, request.content_length).await?;
+                                request_update(&body)
+                            }
+                        }
+                    }
+                    ("POST", "/refresh") => {
+                        state::send_command(Command::Refresh);
+                        Response::ok(202)
+                    }
+                    (_, "/" | "/status" | "/config" | "/refresh" | "/image" | "/update") => {
+                        Response::error(405, "method not allowed")
+                    }
+                    _ => Response::error(404, "not found"),
+                }
+            }
+        },
+        Err(Error::HeadTooLarge) => Response::error(431, "headers too large"),
+        Err(e) => return Err(e),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(&response.body).await?;
+    socket.flush().await?;
+    Ok(())
+}
+
+fn is_awake(windows: &[DailyWindow]) -> bool {
+    if windows.is_empty() {
+        return true;
+    }
+    // Without the time we can't tell, so stay reachable
+    match clock::local_time() {
+        Some(now) => windows.iter().any(|w| w.contains(now.seconds_of_day())),
+        None => true,
+    }
+}
+
+// Run on both the LAN and the setup access point
+#[embassy_executor::task(pool_size = 2)]
+pub async fn server(
+    stack: Stack<'static>,
+    display: &'static SharedDisplay,
+    awake_windows: Vec<DailyWindow>,
+    config_access: ConfigAccess,
+) {
+    // On the heap, as the task arena is small
+    let mut rx_buffer = vec![0u8; SOCKET_BUFFER_SIZE];
+    let mut tx_buffer = vec![0u8; SOCKET_BUFFER_SIZE];
+
+    loop {
+        if !is_awake(&awake_windows) {
+            Timer::after(WINDOW_POLL).await;
+            continue;
+        }
+
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(IO_TIMEOUT));
+        // Give up now and then to look at the awake windows again
+        match with_timeout(WINDOW_POLL, socket.accept(PORT)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                warn!("Failed to accept connection: {:?}", e);
+                Timer::after(Duration::from_secs(1)).await;
+                continue;
+            }
+            Err(_) => continue,
+        }
+
+        if let Err(e) = handle(&mut socket, stack, display, config_access, &awake_windows).await {
+            warn!("Failed to handle request: {:?}", e);
+        }
+        socket.close();
+        // Let the response drain before the socket goes away
+        Timer::after(Duration::from_millis(100)).await;
+        socket.abort();
+    }
+}
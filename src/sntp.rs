@@ -0,0 +1,84 @@
+//
+// Minimal SNTP (RFC 4330) client over the embassy-net UDP stack
+//
+use defmt::{info, Format};
+use embassy_net::{
+    dns::DnsQueryType,
+    udp::{PacketMetadata, UdpSocket},
+    Stack,
+};
+use embassy_time::{with_timeout, Duration, Instant};
+
+const NTP_SERVER: &str = "pool.ntp.org";
+const NTP_PORT: u16 = 123;
+const NTP_PACKET_SIZE: usize = 48;
+// Seconds between the NTP epoch (1900) and the Unix epoch (1970)
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Format)]
+pub enum Error {
+    Dns,
+    Socket,
+    Timeout,
+    InvalidResponse,
+}
+
+/// Asks an NTP server for the time, returning microseconds since the Unix epoch
+pub async fn request_time(stack: Stack<'_>) -> Result<u64, Error> {
+    let addresses = stack
+        .dns_query(NTP_SERVER, DnsQueryType::A)
+        .await
+        .map_err(|_| Error::Dns)?;
+    let server = *addresses.first().ok_or(Error::Dns)?;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0u8; NTP_PACKET_SIZE * 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0u8; NTP_PACKET_SIZE * 2];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(0).map_err(|_| Error::Socket)?;
+
+    // LI = 0, version 4, mode 3 (client)
+    let mut packet = [0u8; NTP_PACKET_SIZE];
+    packet[0] = 0x23;
+
+    let sent_at = Instant::now();
+    socket
+        .send_to(&packet, (server, NTP_PORT))
+        .await
+        .map_err(|_| Error::Socket)?;
+    let (len, _) = with_timeout(TIMEOUT, socket.recv_from(&mut packet))
+        .await
+        .map_err(|_| Error::Timeout)?
+        .map_err(|_| Error::Socket)?;
+    let round_trip = sent_at.elapsed();
+
+    // Mode 4 is a server reply; stratum 0 is a "kiss of death"
+    if len < NTP_PACKET_SIZE || packet[0] & 0x07 != 4 || packet[1] == 0 {
+        return Err(Error::InvalidResponse);
+    }
+
+    // Transmit timestamp: 32 bits of seconds then 32 bits of fraction
+    let seconds = u32::from_be_bytes(packet[40..44].try_into().unwrap()) as u64;
+    let fraction = u32::from_be_bytes(packet[44..48].try_into().unwrap()) as u64;
+    if seconds < NTP_UNIX_OFFSET {
+        return Err(Error::InvalidResponse);
+    }
+
+    let unix_us = (seconds - NTP_UNIX_OFFSET) * 1_000_000
+        + ((fraction * 1_000_000) >> 32)
+        + round_trip.as_micros() / 2;
+    info!(
+        "SNTP: {} s since epoch (round trip {} ms)",
+        unix_us / 1_000_000,
+        round_trip.as_millis()
+    );
+    Ok(unix_us)
+}
@@ -0,0 +1,120 @@
+//
+// What the frame has been doing recently, kept for reporting over the
+// network rather than only to RTT, and requests for the refresh loop from
+// the network services.
+//
+use alloc::string::String;
+use core::cell::RefCell;
+use critical_section::Mutex;
+use defmt::Format;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::Instant;
+
+use crate::battery;
+
+/// How the last refresh went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum RefreshOutcome {
+    Ok,
+    WifiNotConfigured,
+    WifiUnavailable,
+    ServerUnreachable,
+    BadImage,
+    PanelError,
+    LowBattery,
+}
+
+impl RefreshOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RefreshOutcome::Ok => "ok",
+            RefreshOutcome::WifiNotConfigured => "wifi_not_configured",
+            RefreshOutcome::WifiUnavailable => "wifi_unavailable",
+            RefreshOutcome::ServerUnreachable => "server_unreachable",
+            RefreshOutcome::BadImage => "bad_image",
+            RefreshOutcome::PanelError => "panel_error",
+            RefreshOutcome::LowBattery => "low_battery",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct State {
+    pub last_refresh: Option<(RefreshOutcome, Instant)>,
+    /// Most recent refresh that didn't go to plan, kept after later successes
+    pub last_error: Option<RefreshOutcome>,
+    /// Signal strength of the access point, in dBm, when last seen
+    pub rssi: Option<i8>,
+    /// As sent by the server with the image on show
+    pub image_id: Option<String>,
+    pub battery: Option<battery::Reading>,
+}
+
+/// Something for the refresh loop to do between refreshes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Command {
+    /// Fetch and show a new image now
+    Refresh,
+    /// Turn Wi-Fi off until the next scheduled refresh
+    Sleep,
+    /// Install the firmware update waiting in `ota`
+    Update,
+    /// Show the next image from the cache
+    NextImage,
+    /// Start the setup access point
+    Provision,
+    /// Someone came into the room
+    Motion,
+}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    last_refresh: None,
+    last_error: None,
+    rssi: None,
+    image_id: None,
+    battery: None,
+}));
+
+static COMMAND: Signal<CriticalSectionRawMutex, Command> = Signal::new();
+
+pub fn get() -> State {
+    critical_section::with(|cs| STATE.borrow_ref(cs).clone())
+}
+
+fn update(f: impl FnOnce(&mut State)) {
+    critical_section::with(|cs| f(&mut STATE.borrow_ref_mut(cs)));
+}
+
+pub fn record_refresh(outcome: RefreshOutcome) {
+    update(|state| {
+        state.last_refresh = Some((outcome, Instant::now()));
+        if outcome != RefreshOutcome::Ok {
+            state.last_error = Some(outcome);
+        }
+    });
+}
+
+pub fn record_rssi(rssi: i8) {
+    update(|state| state.rssi = Some(rssi));
+}
+
+pub fn record_battery(reading: battery::Reading) {
+    update(|state| state.battery = Some(reading));
+}
+
+pub fn record_image(image_id: Option<String>) {
+    update(|state| state.image_id = image_id);
+}
+
+pub fn send_command(command: Command) {
+    COMMAND.signal(command);
+}
+
+/// Whether a command is waiting for the refresh loop
+pub fn has_command() -> bool {
+    COMMAND.signaled()
+}
+
+pub async fn wait_for_command() -> Command {
+    COMMAND.wait().await
+}
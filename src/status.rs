@@ -0,0 +1,221 @@
+//
+// Full screen status pages drawn on the frame itself when it can't show a
+// picture, so problems are visible on the wall rather than only over RTT.
+//
+use alloc::{format, string::String};
+use core::mem::{discriminant, Discriminant};
+
+use crate::color::Color;
+use crate::font;
+use crate::framebuffer::{self, Framebuffer};
+use crate::qr::{self, EccLevel, QrCode};
+use crate::state::RefreshOutcome;
+
+const MARGIN: u32 = 40;
+const TITLE_SCALE: u32 = 5;
+const MESSAGE_SCALE: u32 = 3;
+const FOOTER_SCALE: u32 = 2;
+const LINE_GAP: u32 = 12;
+// Pixels per QR module; large enough to scan from across a room
+const QR_SCALE: u32 = 5;
+// Consecutive failed refreshes before a status screen replaces the picture
+const FAILURES_BEFORE_SCREEN: u32 = 3;
+
+/// Something worth telling whoever is looking at the frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatusScreen {
+    WifiNotConfigured,
+    WifiUnavailable {
+        ssid: &'static str,
+    },
+    ServerUnreachable {
+        url: String,
+    },
+    BadImage {
+        reason: String,
+    },
+    LowBattery {
+        percent: u8,
+    },
+    /// The frame is running its own access point to be set up through
+    Provisioning {
+        ssid: String,
+        password: String,
+    },
+}
+
+/// Why a refresh didn't show a new picture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefreshFailure {
+    /// Something to tell whoever is looking, once it keeps happening
+    Screen(StatusScreen),
+    /// The panel itself failed, so a status screen wouldn't show either
+    Panel,
+}
+
+impl StatusScreen {
+    pub fn title(&self) -> &'static str {
+        match self {
+            StatusScreen::WifiNotConfigured => "Wi-Fi not configured",
+            StatusScreen::WifiUnavailable { .. } => "Cannot connect to Wi-Fi",
+            StatusScreen::ServerUnreachable { .. } => "Cannot reach server",
+            StatusScreen::BadImage { .. } => "Bad image",
+            StatusScreen::LowBattery { .. } => "Low battery",
+            StatusScreen::Provisioning { .. } => "Set up the frame",
+        }
+    }
+
+    /// The refresh outcome this screen reports
+    pub fn outcome(&self) -> RefreshOutcome {
+        match self {
+            StatusScreen::WifiNotConfigured | StatusScreen::Provisioning { .. } => {
+                RefreshOutcome::WifiNotConfigured
+            }
+            StatusScreen::WifiUnavailable { .. } => RefreshOutcome::WifiUnavailable,
+            StatusScreen::ServerUnreachable { .. } => RefreshOutcome::ServerUnreachable,
+            StatusScreen::BadImage { .. } => RefreshOutcome::BadImage,
+            StatusScreen::LowBattery { .. } => RefreshOutcome::LowBattery,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            StatusScreen::WifiNotConfigured => {
+                String::from("Hold the button for 3 seconds to set up Wi-Fi")
+            }
+            StatusScreen::WifiUnavailable { ssid } => format!("Network: {}", ssid),
+            StatusScreen::ServerUnreachable { url } => format!("at {}", url),
+            StatusScreen::BadImage { reason } => format!("The server sent {}", reason),
+            StatusScreen::LowBattery { percent } => {
+                format!("{}% - please charge the frame", percent)
+            }
+            StatusScreen::Provisioning { ssid, .. } => {
+                format!("Scan the code or join {} to continue", ssid)
+            }
+        }
+    }
+}
+
+/// Details shown at the foot of every status screen
+pub struct DeviceInfo<'a> {
+    pub ip: Option<String>,
+    pub firmware: &'a str,
+    /// Where to configure the frame; also shown as a QR code
+    pub setup_url: Option<&'a str>,
+}
+
+/// Draws `screen` over the whole panel
+pub fn render(framebuffer: &mut Framebuffer, screen: &StatusScreen, info: &DeviceInfo) {
+    framebuffer.fill(Color::White);
+    framebuffer.fill_rect(0, 0, framebuffer.width(), 8, title_color(screen));
+
+    let y = draw_wrapped(
+        framebuffer,
+        MARGIN,
+        screen.title(),
+        TITLE_SCALE,
+        Color::Black,
+    );
+    let y = y + LINE_GAP * 2;
+    draw_wrapped(
+        framebuffer,
+        y,
+        &screen.message(),
+        MESSAGE_SCALE,
+        Color::Black,
+    );
+
+    let mut footer = format!("Firmware {}", info.firmware);
+    if let Some(ip) = &info.ip {
+        footer = format!("IP {}   {}", ip, footer);
+    }
+    let line_height = font::GLYPH_HEIGHT * FOOTER_SCALE + LINE_GAP;
+    let mut y = framebuffer.height() - MARGIN - line_height;
+    if let Some(url) = info.setup_url {
+        y -= line_height;
+        let setup = format!("Setup: {}", url);
+        draw_wrapped(framebuffer, y, &setup, FOOTER_SCALE, Color::Blue);
+        y += line_height;
+    }
+    draw_wrapped(framebuffer, y, &footer, FOOTER_SCALE, Color::Black);
+
+    // Joining the frame's own network comes before its setup page
+    let payload = match screen {
+        StatusScreen::Provisioning { ssid, password } => Some(qr::wifi_join(ssid, password)),
+        _ => info.setup_url.map(String::from),
+    };
+    if let Some(code) = payload.and_then(|p| QrCode::encode(p.as_bytes(), EccLevel::Medium).ok()) {
+        let side = framebuffer::qr_side(&code, QR_SCALE);
+        let x = framebuffer.width().saturating_sub(side + MARGIN);
+        let y = framebuffer.height().saturating_sub(side + MARGIN);
+        framebuffer.draw_qr(x, y, &code, QR_SCALE);
+    }
+}
+
+fn title_color(screen: &StatusScreen) -> Color {
+    match screen {
+        StatusScreen::LowBattery { .. } => Color::Orange,
+        StatusScreen::BadImage { .. } => Color::Yellow,
+        StatusScreen::Provisioning { .. } => Color::Blue,
+        _ => Color::Red,
+    }
+}
+
+// Draws text from the left margin, breaking at spaces to fit the panel.
+// Returns the y coordinate below the last line.
+fn draw_wrapped(
+    framebuffer: &mut Framebuffer,
+    mut y: u32,
+    text: &str,
+    scale: u32,
+    color: Color,
+) -> u32 {
+    let advance = (font::GLYPH_WIDTH + font::GLYPH_SPACING) * scale;
+    let columns = ((framebuffer.width() - MARGIN * 2) / advance) as usize;
+    let mut line = String::new();
+    for word in text.split(' ') {
+        if !line.is_empty() && line.len() + 1 + word.len() > columns {
+            framebuffer.draw_text(MARGIN, y, &line, scale, color);
+            y += font::GLYPH_HEIGHT * scale + LINE_GAP;
+            line.clear();
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    framebuffer.draw_text(MARGIN, y, &line, scale, color);
+    y + font::GLYPH_HEIGHT * scale
+}
+
+/// Decides when a failure deserves a status screen: only after several in a
+/// row, and not again for the same kind of problem until a refresh succeeds.
+#[derive(Default)]
+pub struct FailureTracker {
+    consecutive: u32,
+    shown: Option<Discriminant<StatusScreen>>,
+}
+
+impl FailureTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_success(&mut self) {
+        self.consecutive = 0;
+        self.shown = None;
+    }
+
+    /// Records a failure, returning whether `screen` should now be shown
+    pub fn record_failure(&mut self, screen: &StatusScreen) -> bool {
+        self.consecutive += 1;
+        let kind = discriminant(screen);
+        // A flat battery won't sort itself out, so there's no point waiting
+        let urgent = matches!(screen, StatusScreen::LowBattery { .. });
+        if (self.consecutive < FAILURES_BEFORE_SCREEN && !urgent) || self.shown == Some(kind) {
+            return false;
+        }
+        self.shown = Some(kind);
+        true
+    }
+}
@@ -0,0 +1,56 @@
+//
+// Records kept in the `nvs` data partition, which the firmware has no other
+// use for. Its four sectors are split into two rings, see `records`.
+//
+use alloc::vec;
+use esp_bootloader_esp_idf::partitions::{
+    self, DataPartitionSubType, FlashRegion, PartitionType, PARTITION_TABLE_MAX_LEN,
+};
+use esp_storage::FlashStorage;
+
+use crate::records::{Error, Ring};
+
+/// Settings changed at runtime
+pub const CONFIG: Ring = Ring {
+    first_sector: 0,
+    sectors: 2,
+    slot_size: 512,
+};
+
+/// Maintenance counters
+pub const COUNTERS: Ring = Ring {
+    first_sector: 2,
+    sectors: 2,
+    slot_size: 32,
+};
+
+// Runs `f` on the partition, with offsets counted from its start
+fn with_partition<R>(
+    f: impl FnOnce(&mut FlashRegion<'_, FlashStorage>) -> Result<R, Error>,
+) -> Result<R, Error> {
+    let mut flash = FlashStorage::new();
+    let mut buffer = vec![0u8; PARTITION_TABLE_MAX_LEN];
+    let table =
+        partitions::read_partition_table(&mut flash, &mut buffer).map_err(|_| Error::Flash)?;
+    let nvs = table
+        .find_partition(PartitionType::Data(DataPartitionSubType::Nvs))
+        .ok()
+        .flatten()
+        .ok_or(Error::Flash)?;
+    f(&mut nvs.as_embedded_storage(&mut flash))
+}
+
+/// Reads the newest record in `ring` into `buffer`, returning its length
+pub fn load(ring: Ring, buffer: &mut [u8]) -> Result<Option<usize>, Error> {
+    with_partition(|region| ring.read(region, buffer))
+}
+
+/// Stores `record` as the newest in `ring`
+pub fn save(ring: Ring, record: &[u8]) -> Result<(), Error> {
+    with_partition(|region| ring.write(region, record))
+}
+
+/// Forgets every record in `ring`
+pub fn clear(ring: Ring) -> Result<(), Error> {
+    with_partition(|region| ring.clear(region))
+}
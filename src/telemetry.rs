@@ -0,0 +1,84 @@
+//
+// What the frame tells the image server about itself with each fetch, so one
+// server can tailor content per frame and keep an eye on the whole fleet.
+// Sent as query parameters:
+//
+//   id, firmware, battery (%), battery_mv, rssi (dBm), temperature (°C),
+//   last_error, free_heap (bytes)
+//
+// Anything unknown is left out.
+//
+use alloc::{format, string::String, vec, vec::Vec};
+
+use crate::battery;
+use crate::state::RefreshOutcome;
+use crate::temperature::Temperature;
+
+// Every parameter added to the query
+const KEYS: [&str; 8] = [
+    "id",
+    "firmware",
+    "battery",
+    "battery_mv",
+    "rssi",
+    "temperature",
+    "last_error",
+    "free_heap",
+];
+
+pub struct Telemetry<'a> {
+    pub frame_id: &'a str,
+    pub firmware: &'a str,
+    pub battery: Option<battery::Reading>,
+    pub rssi: Option<i8>,
+    pub temperature: Option<Temperature>,
+    /// How the last failed refresh went wrong
+    pub last_error: Option<RefreshOutcome>,
+    pub free_heap: usize,
+}
+
+impl Telemetry<'_> {
+    /// `url` with the telemetry added to its query
+    pub fn apply(&self, url: &str) -> String {
+        let mut query = vec![
+            format!("id={}", self.frame_id),
+            format!("firmware={}", self.firmware),
+        ];
+        if let Some(reading) = self.battery {
+            query.push(format!("battery={}", reading.percent));
+            query.push(format!("battery_mv={}", reading.millivolts));
+        }
+        if let Some(rssi) = self.rssi {
+            query.push(format!("rssi={}", rssi));
+        }
+        if let Some(temperature) = self.temperature {
+            query.push(format!("temperature={}", temperature));
+        }
+        if let Some(outcome) = self.last_error {
+            query.push(format!("last_error={}", outcome.as_str()));
+        }
+        query.push(format!("free_heap={}", self.free_heap));
+
+        let separator = if url.contains('?') { '&' } else { '?' };
+        format!("{}{}{}", url, separator, query.join("&"))
+    }
+
+    /// `url` without any telemetry, e.g. as echoed back in a redirect
+    pub fn strip(url: &str) -> String {
+        let Some((base, query)) = url.split_once('?') else {
+            return String::from(url);
+        };
+        let kept: Vec<&str> = query
+            .split('&')
+            .filter(|pair| {
+                let key = pair.split('=').next().unwrap_or_default();
+                !pair.is_empty() && !KEYS.contains(&key)
+            })
+            .collect();
+        if kept.is_empty() {
+            String::from(base)
+        } else {
+            format!("{}?{}", base, kept.join("&"))
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//
+// Panel temperature readings and the refresh policy derived from them.
+// ACeP panels are only rated for 0-50°C and look washed out when cold, so
+// a reading is taken before each refresh.
+//
+use core::fmt;
+use defmt::Format;
+
+/// Lowest temperature the panel is rated to refresh at
+pub const RATED_MIN_C: i16 = 0;
+/// Highest temperature the panel is rated to refresh at
+pub const RATED_MAX_C: i16 = 50;
+/// Below this the colours come out noticeably washed out
+pub const COLD_C: i16 = 10;
+
+/// A temperature reading, stored in half degrees Celsius as reported by the TSC command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Temperature(i16);
+
+impl Temperature {
+    pub const fn from_celsius(celsius: i16) -> Self {
+        Self(celsius * 2)
+    }
+
+    /// Decodes the two TSC bytes: a 9-bit two's complement value, MSB first,
+    /// with the LSB worth 0.5°C
+    pub fn from_raw(raw: [u8; 2]) -> Self {
+        Self(i16::from_be_bytes(raw) >> 7)
+    }
+
+    /// Whole degrees Celsius, rounded towards negative infinity
+    pub fn celsius(self) -> i16 {
+        self.0 >> 1
+    }
+
+    // Sign, whole degrees and tenths, for printing
+    fn parts(self) -> (&'static str, u16, u16) {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        (sign, abs / 2, (abs % 2) * 5)
+    }
+
+    /// Value for the TSSET register, which takes a signed whole degree
+    pub fn to_register(self) -> u8 {
+        self.celsius().clamp(i8::MIN as i16, i8::MAX as i16) as i8 as u8
+    }
+}
+
+impl fmt::Display for Temperature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (sign, whole, tenths) = self.parts();
+        write!(f, "{}{}.{}", sign, whole, tenths)
+    }
+}
+
+impl Format for Temperature {
+    fn format(&self, f: defmt::Formatter) {
+        let (sign, whole, tenths) = self.parts();
+        defmt::write!(f, "{}{}.{}C", sign, whole, tenths)
+    }
+}
+
+/// Where the panel controller takes its waveform temperature from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum TemperatureSource {
+    /// The controller's internal sensor (the power-on default)
+    Internal,
+    /// A fixed value written through TSSET, e.g. from an external sensor
+    Fixed(Temperature),
+}
+
+/// What to do with a refresh at a given temperature
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum RefreshAdvice {
+    /// Within the range the panel is tuned for
+    Normal,
+    /// Within the rated range, but the image will look washed out
+    Cold,
+    /// Outside the rated range; refreshing risks damaging the panel
+    OutOfRange,
+}
+
+impl RefreshAdvice {
+    pub fn for_temperature(temperature: Temperature) -> Self {
+        let celsius = temperature.celsius();
+        if !(RATED_MIN_C..=RATED_MAX_C).contains(&celsius) {
+            RefreshAdvice::OutOfRange
+        } else if celsius < COLD_C {
+            RefreshAdvice::Cold
+        } else {
+            RefreshAdvice::Normal
+        }
+    }
+}
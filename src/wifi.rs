@@ -1,38 +1,179 @@
-use defmt::info;
-use embassy_net::Runner;
-use embassy_time::{Duration, Timer};
+use alloc::{string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use defmt::{info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_net::{Runner, Stack};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 use esp_wifi::wifi::{
-    ClientConfiguration, Configuration, WifiController, WifiDevice, WifiEvent, WifiState,
+    AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration, WifiController,
+    WifiDevice, WifiEvent, WifiState,
 };
 
-const SSID: &str = env!("ESP_WIFI_SSID");
-const PASSWORD: &str = env!("ESP_WIFI_PASSWORD");
+use crate::config;
+use crate::indicator::{self, Status};
+use crate::state;
+
+// Defaults until changed through the HTTP server
+pub const SSID: &str = env!("ESP_WIFI_SSID");
+pub const PASSWORD: &str = env!("ESP_WIFI_PASSWORD");
+// The setup access point goes away if nobody saves new settings by then
+const PROVISIONING_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+// Networks reported by a scan, strongest first
+const SCAN_MAX: usize = 10;
+// Longest to wait for the connection task to get round to a scan
+const SCAN_TIMEOUT: Duration = Duration::from_secs(20);
+
+enum Command {
+    Enable,
+    Disable,
+    // Reconnect with the current configuration
+    Restart,
+    // Run the setup access point instead of joining a network
+    Provision { ssid: String, password: String },
+    // Look for networks and report them through SCANNED
+    Scan,
+}
+
+/// A network found by a scan
+pub struct Network {
+    pub ssid: String,
+    pub rssi: i8,
+    pub secured: bool,
+}
+
+// Turns the radio off and on again, e.g. around quiet hours
+static COMMAND: Signal<CriticalSectionRawMutex, Command> = Signal::new();
+static PROVISIONING: AtomicBool = AtomicBool::new(false);
+static SCANNED: Signal<CriticalSectionRawMutex, Option<Vec<Network>>> = Signal::new();
+
+/// Asks the connection task to stop or restart Wi-Fi
+pub fn set_enabled(enabled: bool) {
+    COMMAND.signal(if enabled {
+        Command::Enable
+    } else {
+        Command::Disable
+    });
+}
+
+/// Reconnects using the network in the current configuration
+pub fn restart() {
+    COMMAND.signal(Command::Restart);
+}
+
+/// Runs a WPA2 access point for setting the frame up, until new settings are saved
+pub fn start_provisioning(ssid: String, password: String) {
+    COMMAND.signal(Command::Provision { ssid, password });
+}
+
+/// Networks in range, or None if the connection task couldn't look, as while
+/// the setup access point is up
+pub async fn scan() -> Option<Vec<Network>> {
+    SCANNED.reset();
+    COMMAND.signal(Command::Scan);
+    with_timeout(SCAN_TIMEOUT, SCANNED.wait())
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Whether the setup access point is up
+pub fn is_provisioning() -> bool {
+    PROVISIONING.load(Ordering::Relaxed)
+}
+
+/// Whether there is a network to join
+pub fn is_configured() -> bool {
+    !config::get().wifi_ssid.is_empty()
+}
+
+/// Waits until the link is up and DHCP has handed out an address
+pub async fn wait_for_connection(stack: Stack<'_>) {
+    info!("Waiting to start WiFi...");
+    loop {
+        if stack.is_link_up() {
+            break;
+        }
+        Timer::after(Duration::from_millis(500)).await;
+    }
+
+    info!("Waiting to get IP address...");
+    loop {
+        if let Some(config) = stack.config_v4() {
+            info!("Got IP: {}", config.address);
+            break;
+        }
+        Timer::after(Duration::from_millis(500)).await;
+    }
+}
 
 #[embassy_executor::task]
 pub async fn connection(mut controller: WifiController<'static>) {
     info!("start connection task");
     // info!("Device capabilities: {}", controller.capabilities());
     loop {
+        if !is_configured() {
+            info!("No WiFi network configured");
+            loop {
+                match COMMAND.wait().await {
+                    Command::Restart => break,
+                    Command::Provision { ssid, password } => {
+                        provision(&mut controller, &ssid, &password).await;
+                        break;
+                    }
+                    Command::Scan => scan_for(&mut controller).await,
+                    _ => {}
+                }
+            }
+            continue;
+        }
         if esp_wifi::wifi::wifi_state() == WifiState::StaConnected {
-            // wait until we're no longer connected
-            controller.wait_for_event(WifiEvent::StaDisconnected).await;
-            Timer::after(Duration::from_millis(5000)).await
+            // wait until we're no longer connected, or asked to switch off
+            match select(
+                controller.wait_for_event(WifiEvent::StaDisconnected),
+                COMMAND.wait(),
+            )
+            .await
+            {
+                Either::First(_) => Timer::after(Duration::from_millis(5000)).await,
+                // Scanning doesn't drop the connection, so there's nothing to rejoin
+                Either::Second(Command::Scan) => {
+                    scan_for(&mut controller).await;
+                    continue;
+                }
+                Either::Second(command) => handle_command(&mut controller, command).await,
+            }
+        } else if let Some(command) = COMMAND.try_take() {
+            // Sent while we were still trying to connect
+            handle_command(&mut controller, command).await;
+        }
+        let config = config::get();
+        if config.wifi_ssid.is_empty() {
+            continue;
         }
         if !matches!(controller.is_started(), Ok(true)) {
             let client_config = Configuration::Client(ClientConfiguration {
-                ssid: SSID.into(),
-                password: PASSWORD.into(),
+                ssid: config.wifi_ssid.as_str().into(),
+                password: config.wifi_password.as_str().into(),
                 ..Default::default()
             });
-            controller.set_configuration(&client_config).unwrap();
+            if let Err(e) = controller.set_configuration(&client_config) {
+                info!("Invalid wifi configuration: {:?}", e);
+                while !matches!(COMMAND.wait().await, Command::Restart) {}
+                continue;
+            }
             info!("Starting wifi");
             controller.start_async().await.unwrap();
             info!("Wifi started!");
+        }
 
-            info!("Scan");
-            let result = controller.scan_n_async(10).await.unwrap();
+        info!("Scan");
+        if let Ok(result) = controller.scan_n_async(10).await {
             for ap in result {
                 info!("{:?}", ap);
+                if ap.ssid.as_str() == config.wifi_ssid {
+                    state::record_rssi(ap.signal_strength);
+                }
             }
         }
         info!("About to connect...");
@@ -48,7 +189,115 @@ pub async fn connection(mut controller: WifiController<'static>) {
     }
 }
 
-#[embassy_executor::task]
+async fn handle_command(controller: &mut WifiController<'static>, command: Command) {
+    match command {
+        Command::Enable => {}
+        Command::Disable => {
+            info!("Stopping wifi");
+            controller.stop_async().await.ok();
+            loop {
+                match COMMAND.wait().await {
+                    Command::Enable | Command::Restart => break,
+                    Command::Provision { ssid, password } => {
+                        provision(controller, &ssid, &password).await;
+                        break;
+                    }
+                    Command::Scan => scan_for(controller).await,
+                    Command::Disable => {}
+                }
+            }
+        }
+        Command::Restart => {
+            info!("Restarting wifi with new configuration");
+            controller.stop_async().await.ok();
+        }
+        Command::Provision { ssid, password } => {
+            provision(controller, &ssid, &password).await;
+        }
+        Command::Scan => scan_for(controller).await,
+    }
+}
+
+// Scans on behalf of `scan`, starting the radio just for it if it's off
+async fn scan_for(controller: &mut WifiController<'static>) {
+    let started = matches!(controller.is_started(), Ok(true));
+    if !started {
+        let client_config = Configuration::Client(ClientConfiguration::default());
+        if controller.set_configuration(&client_config).is_err()
+            || controller.start_async().await.is_err()
+        {
+            warn!("Failed to start wifi to scan");
+            SCANNED.signal(None);
+            return;
+        }
+    }
+    let networks = match controller.scan_n_async(SCAN_MAX).await {
+        Ok(found) => {
+            let mut networks: Vec<Network> = found
+                .iter()
+                .map(|ap| Network {
+                    ssid: String::from(ap.ssid.as_str()),
+                    rssi: ap.signal_strength,
+                    secured: ap.auth_method.is_some_and(|auth| auth != AuthMethod::None),
+                })
+                .collect();
+            networks.sort_by(|a, b| b.rssi.cmp(&a.rssi));
+            Some(networks)
+        }
+        Err(e) => {
+            warn!("Scan failed: {:?}", e);
+            None
+        }
+    };
+    if !started {
+        controller.stop_async().await.ok();
+    }
+    SCANNED.signal(networks);
+}
+
+// Leaves the controller stopped, for the connection loop to join the network again
+async fn provision(controller: &mut WifiController<'static>, ssid: &str, password: &str) {
+    controller.stop_async().await.ok();
+    let ap_config = Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: ssid.into(),
+        password: password.into(),
+        auth_method: AuthMethod::WPA2Personal,
+        ..Default::default()
+    });
+    if let Err(e) = controller.set_configuration(&ap_config) {
+        warn!("Invalid access point configuration: {:?}", e);
+        return;
+    }
+    if let Err(e) = controller.start_async().await {
+        warn!("Failed to start access point: {:?}", e);
+        return;
+    }
+    info!("Setup access point {} up", ssid);
+    PROVISIONING.store(true, Ordering::Relaxed);
+    indicator::show(Status::Provisioning);
+
+    let deadline = Instant::now() + PROVISIONING_TIMEOUT;
+    let ended = loop {
+        match select(COMMAND.wait(), Timer::at(deadline)).await {
+            // The radio is busy being an access point
+            Either::First(Command::Scan) => SCANNED.signal(None),
+            ended => break ended,
+        }
+    };
+    PROVISIONING.store(false, Ordering::Relaxed);
+    indicator::show(Status::Off);
+    info!("Stopping setup access point");
+    controller.stop_async().await.ok();
+    match ended {
+        Either::First(Command::Restart | Command::Enable) => {}
+        // Anything else is for the connection loop
+        Either::First(command) => COMMAND.signal(command),
+        Either::Second(()) => warn!("Nobody set the frame up, giving up"),
+    }
+}
+
+// One for the station interface and one for the setup access point
+#[embassy_executor::task(pool_size = 2)]
 pub async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
     runner.run().await
 }